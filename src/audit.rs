@@ -0,0 +1,109 @@
+use std::{
+    fmt::Write as _,
+    fs::OpenOptions,
+    io::{BufWriter, Write as _},
+    path::Path,
+    sync::mpsc::{sync_channel, SyncSender, TrySendError},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Records which can be queued before new ones are dropped.
+const QUEUE_SIZE: usize = 4096;
+
+/// Append-only log of remote input, written as one JSON object per line.
+///
+/// Records are handed to a writer thread so a slow disk never delays input
+/// handling; records are dropped with an error log when the queue is full.
+#[derive(Clone)]
+pub struct AuditLog {
+    sender: SyncSender<String>,
+    keystrokes: bool,
+}
+
+impl AuditLog {
+    /// Opens `path` for appending. Key content is only recorded with `keystrokes`.
+    pub fn open(path: &Path, keystrokes: bool) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (sender, receiver) = sync_channel::<String>(QUEUE_SIZE);
+
+        std::thread::Builder::new()
+            .name("audit-log".to_owned())
+            .spawn(move || {
+                let mut writer = BufWriter::new(file);
+                while let Ok(record) = receiver.recv() {
+                    let mut result = writeln!(writer, "{record}");
+                    // Flush once the queue is drained so records hit the disk
+                    // promptly without a syscall per record under load.
+                    while result.is_ok() {
+                        match receiver.try_recv() {
+                            Ok(record) => result = writeln!(writer, "{record}"),
+                            Err(_) => break,
+                        }
+                    }
+                    if let Err(e) = result.and_then(|_| writer.flush()) {
+                        tracing::error!(?e, "Failed to write audit log");
+                    }
+                }
+            })?;
+
+        Ok(Self { sender, keystrokes })
+    }
+
+    pub fn session(&self, session: u64) -> SessionAudit {
+        SessionAudit {
+            log: self.clone(),
+            session,
+        }
+    }
+}
+
+/// Audit log bound to a single client session.
+pub struct SessionAudit {
+    log: AuditLog,
+    session: u64,
+}
+
+impl SessionAudit {
+    /// Key press or release. `key` is the key content and is left out of the
+    /// record unless keystroke auditing is enabled.
+    pub fn key(&self, pressed: bool, key: impl FnOnce(&mut String)) {
+        let kind = if pressed { "key_press" } else { "key_release" };
+        self.record(kind, |record| {
+            if self.log.keystrokes {
+                key(record);
+            }
+        });
+    }
+
+    pub fn mouse_button(&self, button: &str, pressed: bool, x: f64, y: f64) {
+        let kind = if pressed {
+            "mouse_press"
+        } else {
+            "mouse_release"
+        };
+        self.record(kind, |record| {
+            let _ = write!(record, r#","button":"{button}","x":{x},"y":{y}"#);
+        });
+    }
+
+    fn record(&self, kind: &str, fields: impl FnOnce(&mut String)) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let mut record = format!(
+            r#"{{"timestamp":{timestamp:.3},"session":{},"kind":"{kind}""#,
+            self.session
+        );
+        fields(&mut record);
+        record.push('}');
+
+        match self.log.sender.try_send(record) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                tracing::error!("Audit log queue is full - record dropped")
+            }
+            Err(TrySendError::Disconnected(_)) => tracing::error!("Audit log writer is gone"),
+        }
+    }
+}
@@ -1,35 +1,494 @@
-use std::sync::{Arc, RwLock};
+//! Clipboard text and images shared between the client and the Mac's general
+//! pasteboard.
+
+use std::{
+    sync::{
+        atomic::{AtomicIsize, Ordering},
+        Arc, PoisonError, RwLock,
+    },
+    time::Duration,
+};
 
 use ironrdp::{
-    cliprdr::backend::{CliprdrBackend, CliprdrBackendFactory},
+    cliprdr::{
+        backend::{ClipboardMessage, CliprdrBackend, CliprdrBackendFactory},
+        pdu::{
+            ClipboardFormat, ClipboardFormatId, ClipboardGeneralCapabilityFlags,
+            FileContentsRequest, FileContentsResponse, FormatDataRequest, FormatDataResponse,
+            LockDataId,
+        },
+    },
     server::{CliprdrServerFactory, ServerEvent, ServerEventSender},
 };
-use ironrdp_cliprdr_native::StubCliprdrBackend;
+use objc2::rc::Retained;
+use objc2_app_kit::{
+    NSBitmapImageFileType, NSBitmapImageRep, NSPasteboard, NSPasteboardTypePNG,
+    NSPasteboardTypeString, NSPasteboardTypeTIFF,
+};
+use objc2_foundation::{NSData, NSDictionary, NSString};
+use strum::EnumString;
 use tokio::sync::mpsc::UnboundedSender;
 
-pub struct StubCliprdrServerFactory {
-    inner: Arc<RwLock<Option<UnboundedSender<ServerEvent>>>>,
+/// How often the pasteboard is checked for copies on the Mac.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// `BITMAPFILEHEADER` which turns a `CF_DIB` into a BMP file.
+const BMP_FILE_HEADER_LEN: usize = 14;
+/// `BI_BITFIELDS` compression, whose masks follow a `BITMAPINFOHEADER`.
+const BI_BITFIELDS: u32 = 3;
+const BITMAPINFOHEADER_LEN: u32 = 40;
+
+type EventSender = Arc<RwLock<Option<UnboundedSender<ServerEvent>>>>;
+
+/// Directions in which the clipboard is shared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString)]
+#[strum(serialize_all = "kebab-case", ascii_case_insensitive)]
+pub enum ClipboardMode {
+    Off,
+    /// The client's copies can be pasted on the Mac, not the other way round.
+    ClientToServer,
+    /// The Mac's copies can be pasted on the client, not the other way round.
+    ServerToClient,
+    Both,
 }
 
-impl StubCliprdrServerFactory {
-    pub fn new() -> Self {
-        Self {
-            inner: Default::default(),
-        }
+impl ClipboardMode {
+    fn client_to_server(self) -> bool {
+        matches!(self, Self::ClientToServer | Self::Both)
+    }
+
+    fn server_to_client(self) -> bool {
+        matches!(self, Self::ServerToClient | Self::Both)
+    }
+}
+
+pub struct PasteboardCliprdrFactory {
+    sender: EventSender,
+    mode: ClipboardMode,
+}
+
+impl PasteboardCliprdrFactory {
+    /// Factory for the clipboard channel, or `None` when the clipboard is
+    /// not shared at all.
+    pub fn new(mode: ClipboardMode) -> Option<Self> {
+        (mode != ClipboardMode::Off).then(|| Self {
+            sender: Default::default(),
+            mode,
+        })
     }
 }
 
-impl CliprdrBackendFactory for StubCliprdrServerFactory {
+impl CliprdrBackendFactory for PasteboardCliprdrFactory {
     fn build_cliprdr_backend(&self) -> Box<dyn CliprdrBackend> {
-        Box::new(StubCliprdrBackend::new())
+        Box::new(PasteboardBackend {
+            sender: self.sender.clone(),
+            mode: self.mode,
+            change_count: Arc::new(AtomicIsize::new(pasteboard_change_count())),
+            requested: None,
+        })
     }
 }
 
-impl ServerEventSender for StubCliprdrServerFactory {
+impl ServerEventSender for PasteboardCliprdrFactory {
     fn set_sender(&mut self, sender: UnboundedSender<ServerEvent>) {
-        let mut inner = self.inner.write().expect("Failed to retreive write lock");
+        let mut inner = self.sender.write().unwrap_or_else(PoisonError::into_inner);
         *inner = Some(sender);
     }
 }
 
-impl CliprdrServerFactory for StubCliprdrServerFactory {}
+impl CliprdrServerFactory for PasteboardCliprdrFactory {}
+
+#[derive(Debug)]
+struct PasteboardBackend {
+    sender: EventSender,
+    mode: ClipboardMode,
+    /// Pasteboard change count last offered to the client or caused by it,
+    /// shared with the task watching for copies on the Mac.
+    change_count: Arc<AtomicIsize>,
+    /// Format requested from the client, whose data arrives next.
+    requested: Option<ClipboardFormatId>,
+}
+
+ironrdp::core::impl_as_any!(PasteboardBackend);
+
+fn send(sender: &EventSender, message: ClipboardMessage) {
+    let sender = sender.read().unwrap_or_else(PoisonError::into_inner);
+    let Some(sender) = sender.as_ref() else {
+        tracing::warn!("Clipboard message before the session started");
+        return;
+    };
+    if sender.send(ServerEvent::Clipboard(message)).is_err() {
+        tracing::debug!("Session ended, dropping clipboard message");
+    }
+}
+
+/// Formats what was copied on the Mac is offered in.
+fn pasteboard_formats() -> Vec<ClipboardFormat> {
+    let mut formats = Vec::new();
+    if pasteboard_text().is_some() {
+        formats.push(ClipboardFormat::new(ClipboardFormatId::CF_UNICODETEXT));
+    }
+    if pasteboard_image().is_some() {
+        formats.push(ClipboardFormat::new(ClipboardFormatId::CF_DIB));
+    }
+    formats
+}
+
+fn pasteboard_change_count() -> isize {
+    unsafe { NSPasteboard::generalPasteboard().changeCount() }
+}
+
+fn pasteboard_text() -> Option<String> {
+    let pasteboard = unsafe { NSPasteboard::generalPasteboard() };
+    let text = unsafe { pasteboard.stringForType(NSPasteboardTypeString) }?;
+    Some(text.to_string())
+}
+
+/// Image copied on the Mac, which apps put on the pasteboard as PNG or TIFF.
+fn pasteboard_image() -> Option<Retained<NSData>> {
+    let pasteboard = unsafe { NSPasteboard::generalPasteboard() };
+    unsafe { pasteboard.dataForType(NSPasteboardTypePNG) }
+        .or_else(|| unsafe { pasteboard.dataForType(NSPasteboardTypeTIFF) })
+}
+
+/// Replaces the pasteboard contents with `text`, returning the new change
+/// count.
+fn set_pasteboard_text(text: &str) -> isize {
+    let pasteboard = unsafe { NSPasteboard::generalPasteboard() };
+    unsafe { pasteboard.clearContents() };
+    let string = NSString::from_str(text);
+    if !unsafe { pasteboard.setString_forType(&string, NSPasteboardTypeString) } {
+        tracing::warn!("Failed to put client clipboard text on the pasteboard");
+    }
+    unsafe { pasteboard.changeCount() }
+}
+
+/// Replaces the pasteboard contents with the BMP file `bmp` as TIFF,
+/// returning the new change count.
+fn set_pasteboard_image(bmp: &[u8]) -> Option<isize> {
+    let image = unsafe { NSBitmapImageRep::imageRepWithData(&NSData::with_bytes(bmp)) }?;
+    let tiff = unsafe { image.TIFFRepresentation() }?;
+    let pasteboard = unsafe { NSPasteboard::generalPasteboard() };
+    unsafe { pasteboard.clearContents() };
+    if !unsafe { pasteboard.setData_forType(Some(&tiff), NSPasteboardTypeTIFF) } {
+        tracing::warn!("Failed to put client clipboard image on the pasteboard");
+    }
+    Some(unsafe { pasteboard.changeCount() })
+}
+
+/// `CF_DIB` data of the pasteboard image. ImageIO writes the BMP, so its
+/// header and row order are ones every client reads.
+fn pasteboard_dib() -> Option<Vec<u8>> {
+    let image = unsafe { NSBitmapImageRep::imageRepWithData(&pasteboard_image()?) }?;
+    let bmp = unsafe {
+        image.representationUsingType_properties(NSBitmapImageFileType::BMP, &NSDictionary::new())
+    }?;
+    bmp_to_dib(&bmp.to_vec()).map(<[u8]>::to_vec)
+}
+
+/// BMP file of a `CF_DIB`, which is the file without its
+/// `BITMAPFILEHEADER`. The header only has to say where the pixels start;
+/// their rows stay bottom-up or top-down as the height's sign says.
+fn dib_to_bmp(dib: &[u8]) -> Option<Vec<u8>> {
+    let u16_at = |offset: usize| {
+        Some(u16::from_le_bytes(
+            dib.get(offset..offset + 2)?.try_into().ok()?,
+        ))
+    };
+    let u32_at = |offset: usize| {
+        Some(u32::from_le_bytes(
+            dib.get(offset..offset + 4)?.try_into().ok()?,
+        ))
+    };
+    let header_len = u32_at(0)?;
+    if header_len < BITMAPINFOHEADER_LEN {
+        return None;
+    }
+    let bit_count = u16_at(14)?;
+    let compression = u32_at(16)?;
+    let colors_used = u32_at(32)?;
+    let colors = match colors_used {
+        0 if bit_count <= 8 => 1 << bit_count,
+        colors => colors,
+    };
+    let masks = if compression == BI_BITFIELDS && header_len == BITMAPINFOHEADER_LEN {
+        3 * 4
+    } else {
+        0
+    };
+    // The header is from the client, so its lengths may not add up
+    let pixels_offset = colors
+        .checked_mul(4)?
+        .checked_add(header_len)?
+        .checked_add(BMP_FILE_HEADER_LEN as u32 + masks)?;
+    let file_len = u32::try_from(BMP_FILE_HEADER_LEN + dib.len()).ok()?;
+    if pixels_offset > file_len {
+        return None;
+    }
+
+    let mut bmp = Vec::with_capacity(file_len as usize);
+    bmp.extend_from_slice(b"BM");
+    bmp.extend_from_slice(&file_len.to_le_bytes());
+    bmp.extend_from_slice(&[0; 4]);
+    bmp.extend_from_slice(&pixels_offset.to_le_bytes());
+    bmp.extend_from_slice(dib);
+    Some(bmp)
+}
+
+fn bmp_to_dib(bmp: &[u8]) -> Option<&[u8]> {
+    if !bmp.starts_with(b"BM") {
+        return None;
+    }
+    bmp.get(BMP_FILE_HEADER_LEN..)
+}
+
+/// `CF_UNICODETEXT` data: NUL terminated UTF-16LE with CRLF line endings.
+fn encode_unicode_text(text: &str) -> Vec<u8> {
+    windows_line_endings(text)
+        .encode_utf16()
+        .chain([0])
+        .flat_map(u16::to_le_bytes)
+        .collect()
+}
+
+fn decode_unicode_text(data: &[u8]) -> String {
+    let units = data
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .take_while(|unit| *unit != 0)
+        .collect::<Vec<_>>();
+    String::from_utf16_lossy(&units).replace("\r\n", "\n")
+}
+
+/// `CF_TEXT` data, NUL terminated. Clients are sent UTF-8, which matches
+/// ASCII text in any code page.
+fn encode_text(text: &str) -> Vec<u8> {
+    let mut data = windows_line_endings(text).into_bytes();
+    data.push(0);
+    data
+}
+
+fn decode_text(data: &[u8]) -> String {
+    let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).replace("\r\n", "\n")
+}
+
+fn windows_line_endings(text: &str) -> String {
+    text.replace("\r\n", "\n").replace('\n', "\r\n")
+}
+
+impl CliprdrBackend for PasteboardBackend {
+    fn temporary_directory(&self) -> &str {
+        ".cliprdr"
+    }
+
+    fn client_capabilities(&self) -> ClipboardGeneralCapabilityFlags {
+        ClipboardGeneralCapabilityFlags::empty()
+    }
+
+    fn on_ready(&mut self) {
+        if !self.mode.server_to_client() {
+            return;
+        }
+        let sender = self.sender.clone();
+        let change_count = Arc::downgrade(&self.change_count);
+        // Stops once the backend is dropped with its session.
+        tokio::task::spawn_local(async move {
+            let mut interval = tokio::time::interval(POLL_INTERVAL);
+            loop {
+                interval.tick().await;
+                let Some(change_count) = change_count.upgrade() else {
+                    break;
+                };
+                let current = pasteboard_change_count();
+                if change_count.swap(current, Ordering::AcqRel) == current {
+                    continue;
+                }
+                let formats = pasteboard_formats();
+                if !formats.is_empty() {
+                    tracing::debug!(?formats, "Copied on the Mac, offering it to the client");
+                    send(&sender, ClipboardMessage::SendInitiateCopy(formats));
+                }
+            }
+        });
+    }
+
+    fn on_request_format_list(&mut self) {
+        if !self.mode.server_to_client() {
+            return;
+        }
+        let formats = pasteboard_formats();
+        if !formats.is_empty() {
+            send(&self.sender, ClipboardMessage::SendInitiateCopy(formats));
+        }
+    }
+
+    fn on_process_negotiated_capabilities(&mut self, _: ClipboardGeneralCapabilityFlags) {}
+
+    fn on_remote_copy(&mut self, available_formats: &[ClipboardFormat]) {
+        if !self.mode.client_to_server() {
+            tracing::debug!("Ignoring client copy, clipboard is not shared to the Mac");
+            return;
+        }
+        let format = [
+            ClipboardFormatId::CF_UNICODETEXT,
+            ClipboardFormatId::CF_TEXT,
+            ClipboardFormatId::CF_DIB,
+        ]
+        .into_iter()
+        .find(|format| available_formats.iter().any(|f| f.id() == *format));
+        let Some(format) = format else {
+            tracing::debug!("Client copied neither text nor an image");
+            return;
+        };
+        self.requested = Some(format);
+        send(&self.sender, ClipboardMessage::SendInitiatePaste(format));
+    }
+
+    fn on_format_data_request(&mut self, request: FormatDataRequest) {
+        let data = if !self.mode.server_to_client() {
+            tracing::warn!("Refusing clipboard request, clipboard is not shared to the client");
+            None
+        } else if request.format == ClipboardFormatId::CF_UNICODETEXT {
+            pasteboard_text().map(|text| encode_unicode_text(&text))
+        } else if request.format == ClipboardFormatId::CF_TEXT {
+            pasteboard_text().map(|text| encode_text(&text))
+        } else if request.format == ClipboardFormatId::CF_DIB {
+            pasteboard_dib()
+        } else {
+            None
+        };
+        let response = match data {
+            Some(data) => FormatDataResponse::new_data(data),
+            None => {
+                tracing::debug!(format = ?request.format, "No clipboard data in this format");
+                FormatDataResponse::new_error()
+            }
+        };
+        send(&self.sender, ClipboardMessage::SendFormatData(response));
+    }
+
+    fn on_format_data_response(&mut self, response: FormatDataResponse<'_>) {
+        let Some(format) = self.requested.take() else {
+            tracing::warn!("Unrequested clipboard data from the client");
+            return;
+        };
+        if response.is_error() {
+            tracing::warn!(?format, "Client failed to send its clipboard data");
+            return;
+        }
+        let change_count = if format == ClipboardFormatId::CF_DIB {
+            let Some(change_count) = dib_to_bmp(response.data())
+                .as_deref()
+                .and_then(set_pasteboard_image)
+            else {
+                tracing::warn!("Failed to decode the client's clipboard image");
+                return;
+            };
+            change_count
+        } else if format == ClipboardFormatId::CF_UNICODETEXT {
+            set_pasteboard_text(&decode_unicode_text(response.data()))
+        } else {
+            set_pasteboard_text(&decode_text(response.data()))
+        };
+        // Not offered back to the client
+        self.change_count.store(change_count, Ordering::Release);
+    }
+
+    fn on_file_contents_request(&mut self, _: FileContentsRequest) {}
+
+    fn on_file_contents_response(&mut self, _: FileContentsResponse<'_>) {}
+
+    fn on_lock(&mut self, _: LockDataId) {}
+
+    fn on_unlock(&mut self, _: LockDataId) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unicode_text_round_trips_with_crlf() {
+        let data = encode_unicode_text("한글\nline");
+        assert_eq!(&data[data.len() - 2..], [0, 0]);
+        assert_eq!(
+            String::from_utf16_lossy(
+                &data
+                    .chunks_exact(2)
+                    .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                    .collect::<Vec<_>>()
+            ),
+            "한글\r\nline\0"
+        );
+        assert_eq!(decode_unicode_text(&data), "한글\nline");
+    }
+
+    /// `BITMAPINFOHEADER` of a DIB two pixels wide, bottom-up for a positive
+    /// `height`.
+    fn dib(height: i32, bit_count: u16, compression: u32) -> Vec<u8> {
+        let mut dib = Vec::new();
+        dib.extend_from_slice(&BITMAPINFOHEADER_LEN.to_le_bytes());
+        dib.extend_from_slice(&2i32.to_le_bytes());
+        dib.extend_from_slice(&height.to_le_bytes());
+        dib.extend_from_slice(&1u16.to_le_bytes());
+        dib.extend_from_slice(&bit_count.to_le_bytes());
+        dib.extend_from_slice(&compression.to_le_bytes());
+        dib.resize(BITMAPINFOHEADER_LEN as usize, 0);
+        dib
+    }
+
+    #[test]
+    fn bmp_header_points_past_the_dib_header() {
+        let mut pixels = dib(1, 24, 0);
+        pixels.extend_from_slice(&[0xFF; 8]);
+        let bmp = dib_to_bmp(&pixels).unwrap();
+        assert_eq!(&bmp[..2], b"BM");
+        assert_eq!(u32::from_le_bytes(bmp[2..6].try_into().unwrap()), 62);
+        assert_eq!(u32::from_le_bytes(bmp[10..14].try_into().unwrap()), 54);
+        assert_eq!(bmp_to_dib(&bmp), Some(&pixels[..]));
+
+        // Top-down rows are kept as they are
+        let top_down = dib(-1, 24, 0);
+        assert_eq!(&dib_to_bmp(&top_down).unwrap()[14..], &top_down[..]);
+    }
+
+    #[test]
+    fn bmp_header_skips_color_table_and_masks() {
+        let mut paletted = dib(1, 8, 0);
+        paletted.resize(40 + 256 * 4 + 4, 0);
+        let bmp = dib_to_bmp(&paletted).unwrap();
+        assert_eq!(
+            u32::from_le_bytes(bmp[10..14].try_into().unwrap()),
+            14 + 40 + 1024
+        );
+
+        let mut bitfields = dib(1, 32, BI_BITFIELDS);
+        bitfields.resize(40 + 12 + 8, 0);
+        let bmp = dib_to_bmp(&bitfields).unwrap();
+        assert_eq!(
+            u32::from_le_bytes(bmp[10..14].try_into().unwrap()),
+            14 + 40 + 12
+        );
+
+        assert_eq!(dib_to_bmp(&paletted[..100]), None);
+        assert_eq!(dib_to_bmp(&[0; 8]), None);
+    }
+
+    #[test]
+    fn bmp_header_rejects_overflowing_lengths() {
+        let mut colors = dib(1, 8, 0);
+        colors[32..36].copy_from_slice(&0x4000_0001u32.to_le_bytes());
+        assert_eq!(dib_to_bmp(&colors), None);
+
+        let mut header = dib(1, 24, 0);
+        header[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(dib_to_bmp(&header), None);
+    }
+
+    #[test]
+    fn text_stops_at_nul() {
+        assert_eq!(decode_text(b"a\r\nb\0garbage"), "a\nb");
+        assert_eq!(decode_text(b"no terminator"), "no terminator");
+        assert_eq!(encode_text("a\nb"), b"a\r\nb\0");
+    }
+}
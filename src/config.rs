@@ -0,0 +1,173 @@
+//! Settings file loaded with `--config`, so a launchd plist can point at a
+//! file instead of carrying a long command line.
+
+use std::{
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::Context as _;
+use arisu::{credential::TotpSecret, Security};
+use serde::{Deserialize, Deserializer};
+
+/// Values of the matching command line flags. Flags given on the command
+/// line take precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    #[serde(deserialize_with = "one_or_many")]
+    pub host: Option<Vec<IpAddr>>,
+    pub port: Option<u16>,
+    pub dual_stack: Option<bool>,
+    pub certificate: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    #[serde(deserialize_with = "parsed")]
+    pub security: Option<Security>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(deserialize_with = "parsed")]
+    pub totp_secret: Option<TotpSecret>,
+    #[serde(deserialize_with = "max_fps")]
+    pub max_fps: Option<u32>,
+    #[serde(deserialize_with = "scale")]
+    pub scale: Option<f64>,
+    pub power_save: Option<bool>,
+    pub show_cursor: Option<bool>,
+    #[serde(alias = "max-clients")]
+    pub max_sessions: Option<usize>,
+    pub max_bitrate: Option<u64>,
+    pub require_consent: Option<bool>,
+    pub no_gui: Option<bool>,
+    pub health_addr: Option<SocketAddr>,
+    pub metrics_port: Option<u16>,
+    pub log_file: Option<PathBuf>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config {path:?}"))?;
+        Self::parse(&content).with_context(|| format!("invalid config {path:?}"))
+    }
+
+    fn parse(content: &str) -> anyhow::Result<Self> {
+        let config: Self = toml::from_str(content)?;
+        // Like the flags, which conflict with each other
+        if config.password.is_some() && config.totp_secret.is_some() {
+            anyhow::bail!("password and totp-secret can't both be set");
+        }
+        Ok(config)
+    }
+}
+
+/// Values written the way the command line takes them, e.g. `security = "tls"`.
+fn parsed<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| value.parse().map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// A frame rate cap of at least 1, as `--max-fps` takes.
+fn max_fps<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<u32>::deserialize(deserializer)? {
+        Some(0) => Err(serde::de::Error::custom(
+            "invalid max-fps 0, expected at least 1",
+        )),
+        max_fps => Ok(max_fps),
+    }
+}
+
+/// A scale factor in (0, 1], as `--scale` takes.
+fn scale<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<f64>::deserialize(deserializer)? {
+        Some(scale) if !(scale > 0.0 && scale <= 1.0) => Err(serde::de::Error::custom(format!(
+            "invalid scale {scale}, expected a factor in (0, 1]"
+        ))),
+        scale => Ok(scale),
+    }
+}
+
+/// A single value or a list, e.g. `host = "::1"` or `host = ["10.0.0.2", "::1"]`.
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Option<Vec<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => Some(vec![value]),
+        OneOrMany::Many(values) => Some(values),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_are_kebab_case_keys() {
+        let config: Config = toml::from_str(
+            r#"
+            host = "192.168.0.2"
+            port = 3390
+            security = "tls"
+            certificate = "/etc/arisu/cert.pem"
+            max-fps = 20
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.host, Some(vec![IpAddr::from([192, 168, 0, 2])]));
+        assert_eq!(config.port, Some(3390));
+        assert_eq!(config.security, Some(Security::Tls));
+        assert_eq!(config.max_fps, Some(20));
+        assert_eq!(config.key, None);
+
+        let config: Config = toml::from_str(r#"host = ["10.0.0.2", "::1"]"#).unwrap();
+        assert_eq!(config.host.map(|hosts| hosts.len()), Some(2));
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(toml::from_str::<Config>("max_fps = 20").is_err());
+        assert!(toml::from_str::<Config>(r#"security = "ssl""#).is_err());
+    }
+
+    #[test]
+    fn password_and_totp_secret_conflict() {
+        assert!(Config::parse(
+            r#"
+            password = "secret"
+            totp-secret = "JBSWY3DPEHPK3PXP"
+            "#
+        )
+        .is_err());
+        assert!(Config::parse(r#"totp-secret = "JBSWY3DPEHPK3PXP""#).is_ok());
+    }
+
+    #[test]
+    fn values_are_checked_like_flags() {
+        assert!(toml::from_str::<Config>("max-fps = 0").is_err());
+        assert!(toml::from_str::<Config>("scale = 0.0").is_err());
+        assert!(toml::from_str::<Config>("scale = 1.5").is_err());
+        let config: Config = toml::from_str("scale = 0.5").unwrap();
+        assert_eq!(config.scale, Some(0.5));
+    }
+}
@@ -1,22 +1,45 @@
 use std::{
+    collections::VecDeque,
     sync::{
         atomic::{AtomicU64, Ordering},
-        Arc,
+        Arc, Mutex, MutexGuard, PoisonError,
     },
-    time::Instant,
+    time::{Duration, Instant},
 };
 
-#[derive(Clone)]
+/// Intervals averaged unless configured otherwise.
+pub const DEFAULT_INTERVAL_WINDOW: usize = 30;
+
+#[derive(Clone, Debug)]
 pub struct IntervalCounter {
+    epoch: Instant,
     last_time: Instant,
-    interval: Arc<AtomicU64>, // unit: micro seconds
+    /// Most recent intervals in micro seconds, owned by the updating side so
+    /// the shared average stays lock-free.
+    window: VecDeque<u64>,
+    window_len: usize,
+    window_sum: u64,
+    interval: Arc<AtomicU64>,  // unit: micro seconds, average over `window`
+    last_seen: Arc<AtomicU64>, // unit: micro seconds since epoch
 }
 
 impl IntervalCounter {
     pub fn new() -> Self {
+        Self::with_window(DEFAULT_INTERVAL_WINDOW)
+    }
+
+    /// Counter whose interval is the average of the last `len` updates.
+    pub fn with_window(len: usize) -> Self {
+        let now = Instant::now();
+        let window_len = len.max(1);
         Self {
-            last_time: Instant::now(),
+            epoch: now,
+            last_time: now,
+            window: VecDeque::with_capacity(window_len),
+            window_len,
+            window_sum: 0,
             interval: Arc::new(AtomicU64::new(1000000)),
+            last_seen: Arc::new(AtomicU64::new(0)),
         }
     }
 
@@ -24,19 +47,167 @@ impl IntervalCounter {
         let now = Instant::now();
         let duration = now.duration_since(self.last_time);
         self.last_time = now;
-        self.interval
-            .store(duration.as_micros() as u64, Ordering::Release);
+        self.record(duration.as_micros() as u64);
+        self.store_last_seen(now);
+    }
+
+    fn record(&mut self, micros: u64) {
+        if self.window.len() == self.window_len {
+            self.window_sum -= self.window.pop_front().unwrap_or_default();
+        }
+        self.window.push_back(micros);
+        self.window_sum += micros;
+        self.interval.store(
+            self.window_sum / self.window.len() as u64,
+            Ordering::Release,
+        );
+    }
+
+    /// Marks the source as alive without counting a new interval.
+    pub fn touch(&self) {
+        self.store_last_seen(Instant::now());
+    }
+
+    fn store_last_seen(&self, now: Instant) {
+        self.last_seen.store(
+            now.duration_since(self.epoch).as_micros() as u64,
+            Ordering::Release,
+        );
     }
 
     pub fn interval(&self) -> Interval {
-        Interval(Arc::clone(&self.interval))
+        Interval {
+            epoch: self.epoch,
+            interval: Arc::clone(&self.interval),
+            last_seen: Arc::clone(&self.last_seen),
+        }
     }
 }
 
-pub struct Interval(Arc<AtomicU64>);
+#[derive(Clone, Debug)]
+pub struct Interval {
+    epoch: Instant,
+    interval: Arc<AtomicU64>,
+    last_seen: Arc<AtomicU64>,
+}
 
 impl Interval {
-    pub fn get(&self) -> std::time::Duration {
-        std::time::Duration::from_micros(self.0.load(Ordering::Relaxed))
+    /// Average interval over the counter's window.
+    pub fn get(&self) -> Duration {
+        Duration::from_micros(self.interval.load(Ordering::Relaxed))
+    }
+
+    /// Time elapsed since the last update or touch.
+    pub fn since_last_seen(&self) -> Duration {
+        let last_seen = self.epoch + Duration::from_micros(self.last_seen.load(Ordering::Acquire));
+        Instant::now().saturating_duration_since(last_seen)
+    }
+}
+
+/// Bytes sent over a sliding window, shared by every session.
+#[derive(Clone)]
+pub struct BitrateCounter {
+    /// Limit in bytes per second.
+    limit: Option<u64>,
+    sent: Arc<Mutex<VecDeque<(Instant, u64)>>>,
+}
+
+impl BitrateCounter {
+    const WINDOW: Duration = Duration::from_secs(1);
+
+    pub fn new(max_kbps: Option<u64>) -> Self {
+        Self {
+            limit: max_kbps.map(|kbps| kbps * 1000 / 8),
+            sent: Default::default(),
+        }
+    }
+
+    fn lock_window(&self, now: Instant) -> MutexGuard<'_, VecDeque<(Instant, u64)>> {
+        let mut sent = self.sent.lock().unwrap_or_else(PoisonError::into_inner);
+        while sent
+            .front()
+            .is_some_and(|(time, _)| now.duration_since(*time) >= Self::WINDOW)
+        {
+            sent.pop_front();
+        }
+        sent
+    }
+
+    pub fn record(&self, bytes: usize) {
+        let now = Instant::now();
+        self.lock_window(now).push_back((now, bytes as u64));
+    }
+
+    /// How long to hold back the next frame to stay under the limit.
+    pub fn throttle_delay(&self) -> Duration {
+        let Some(limit) = self.limit else {
+            return Duration::ZERO;
+        };
+        let now = Instant::now();
+        let sent = self.lock_window(now);
+        let mut total: u64 = sent.iter().map(|(_, bytes)| bytes).sum();
+        for (time, bytes) in sent.iter() {
+            if total < limit {
+                break;
+            }
+            total -= bytes;
+            if total < limit {
+                return (*time + Self::WINDOW).saturating_duration_since(now);
+            }
+        }
+        Duration::ZERO
+    }
+
+    /// Bits per second sent over the last window.
+    pub fn bitrate(&self) -> u64 {
+        let sent = self.lock_window(Instant::now());
+        sent.iter().map(|(_, bytes)| bytes).sum::<u64>() * 8 / Self::WINDOW.as_secs()
+    }
+}
+
+/// Number of times something failed, shared by every session.
+#[derive(Clone, Default, Debug)]
+pub struct FailureCounter(Arc<AtomicU64>);
+
+impl FailureCounter {
+    pub fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Number of frames, e.g. those skipped for clients falling behind, shared
+/// by every session.
+#[derive(Clone, Default, Debug)]
+pub struct FrameCounter(Arc<AtomicU64>);
+
+impl FrameCounter {
+    pub fn add(&self, frames: u64) {
+        self.0.fetch_add(frames, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interval_is_averaged_over_the_window() {
+        let mut counter = IntervalCounter::with_window(3);
+        let interval = counter.interval();
+        counter.record(10);
+        assert_eq!(interval.get(), Duration::from_micros(10));
+        counter.record(20);
+        counter.record(30);
+        assert_eq!(interval.get(), Duration::from_micros(20));
+        counter.record(100);
+        assert_eq!(interval.get(), Duration::from_micros(50));
     }
 }
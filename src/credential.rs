@@ -1,19 +1,435 @@
-use ironrdp::{
-    connector::sspi::{AuthIdentity, Secret, UserNameFormat, Username},
-    server::{CredentialChecker, Credentials},
+//! Password clients log in with, which can be static, change on a schedule
+//! (TOTP) or be good for a single login, and the rate limit on failed logins.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-pub struct DummyCredential;
+use hmac::{Hmac, Mac};
+use ironrdp::server::Credentials;
+use sha1::Sha1;
+
+/// Window in which failed logins of a source count towards a lockout.
+const FAILURE_WINDOW: Duration = Duration::from_secs(60);
+/// Lockouts of a source are forgotten after this long without failed logins.
+const LOCKOUT_RESET: Duration = Duration::from_secs(60 * 60);
+/// Each further lockout of a source doubles, up to this many times.
+const MAX_LOCKOUT_DOUBLINGS: u32 = 6;
+/// RFC 6238 defaults, which authenticator apps assume.
+const TOTP_STEP: u64 = 30;
+const TOTP_DIGITS: u32 = 6;
+/// Steps tried in turn for a source whose code was rejected, so a code which
+/// expired while it was typed or comes from a clock one step off is accepted
+/// on a retry.
+const TOTP_STEP_OFFSETS: [i64; 3] = [0, -1, 1];
+
+pub enum Password {
+    Static(String),
+    /// The code current when a client connects is its password for that
+    /// connection. ironrdp checks a single password, so the adjacent codes
+    /// are only tried when a source retries after a rejected one.
+    Totp(TotpSecret),
+    /// Password which is gone after the first successful login.
+    OneTime(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OneTimeState {
+    Available,
+    /// Handed to a connection which hasn't logged in yet.
+    Claimed,
+    Used,
+}
+
+/// How a connection given credentials by [`CredentialStore::issue`] ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginOutcome {
+    LoggedIn,
+    /// The client's credentials were rejected.
+    Rejected,
+    /// The connection ended before its credentials were checked, e.g. it was
+    /// dropped or failed TLS.
+    Abandoned,
+}
+
+/// Lockout of sources guessing passwords.
+#[derive(Debug, Clone, Copy)]
+pub struct LoginLimit {
+    /// Failed logins within a minute after which a source is locked out.
+    pub max_failures: usize,
+    /// How long the first lockout lasts.
+    pub lockout: Duration,
+}
+
+impl Default for LoginLimit {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            lockout: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct SourceFailures {
+    /// Failed logins within `FAILURE_WINDOW`, oldest first.
+    recent: VecDeque<Instant>,
+    locked_until: Option<Instant>,
+    lockouts: u32,
+    last_failure: Option<Instant>,
+}
+
+pub struct CredentialStore {
+    username: String,
+    password: Password,
+    one_time: Cell<OneTimeState>,
+    limit: LoginLimit,
+    failures: RefCell<HashMap<IpAddr, SourceFailures>>,
+    /// TOTP step a source's codes were rejected at, and how many times.
+    totp_rejections: RefCell<HashMap<IpAddr, (u64, usize)>>,
+}
+
+impl CredentialStore {
+    pub fn new(username: String, password: Password) -> Self {
+        Self {
+            username,
+            password,
+            one_time: Cell::new(OneTimeState::Available),
+            limit: LoginLimit::default(),
+            failures: Default::default(),
+            totp_rejections: Default::default(),
+        }
+    }
+
+    pub fn with_login_limit(mut self, limit: LoginLimit) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    fn credentials(&self, password: String) -> Credentials {
+        Credentials {
+            username: self.username.clone(),
+            password,
+            domain: None,
+        }
+    }
+
+    /// Credentials a connection from `source` has to log in with. `None`
+    /// refuses the connection, while the source is locked out for failed
+    /// logins or the one-time password is used or claimed by another
+    /// connection.
+    ///
+    /// Every issued credential has to be followed by a [`Self::report`].
+    pub fn issue(&self, source: IpAddr) -> Option<Credentials> {
+        let now = Instant::now();
+        let mut failures = self.failures.borrow_mut();
+        failures.retain(|_, failures| {
+            failures
+                .last_failure
+                .is_some_and(|failed| now.duration_since(failed) < LOCKOUT_RESET)
+        });
+        if let Some(locked_until) = failures
+            .get(&source)
+            .and_then(|failures| failures.locked_until)
+            .filter(|locked_until| *locked_until > now)
+        {
+            tracing::warn!(
+                %source,
+                remaining = ?locked_until.duration_since(now),
+                "Source locked out for failed logins"
+            );
+            return None;
+        }
+
+        match &self.password {
+            Password::Static(password) => Some(self.credentials(password.clone())),
+            Password::Totp(secret) => {
+                Some(self.credentials(self.totp_for(&secret.0, source, unix_time())))
+            }
+            Password::OneTime(password) => {
+                if self.one_time.get() != OneTimeState::Available {
+                    tracing::warn!(state = ?self.one_time.get(), "One-time password unavailable");
+                    return None;
+                }
+                self.one_time.set(OneTimeState::Claimed);
+                Some(self.credentials(password.clone()))
+            }
+        }
+    }
+
+    /// Credentials which are neither counted nor consumed, for connections
+    /// which are refused right after they are established.
+    pub fn peek(&self) -> Credentials {
+        match &self.password {
+            Password::Static(password) | Password::OneTime(password) => {
+                self.credentials(password.clone())
+            }
+            Password::Totp(secret) => self.credentials(totp(&secret.0, unix_time())),
+        }
+    }
+
+    /// TOTP code issued to `source` at `time`, the code of an adjacent step
+    /// when the source's code was rejected during the current one.
+    fn totp_for(&self, secret: &[u8], source: IpAddr, time: u64) -> String {
+        let step = time / TOTP_STEP;
+        let rejections = self
+            .totp_rejections
+            .borrow()
+            .get(&source)
+            .filter(|(rejected_step, _)| *rejected_step == step)
+            .map_or(0, |(_, rejections)| *rejections);
+        let offset = TOTP_STEP_OFFSETS[rejections % TOTP_STEP_OFFSETS.len()];
+        totp_at_step(secret, step.saturating_add_signed(offset))
+    }
+
+    fn reject_totp(&self, source: IpAddr, time: u64) {
+        let step = time / TOTP_STEP;
+        let mut rejections = self.totp_rejections.borrow_mut();
+        let (rejected_step, count) = rejections.entry(source).or_insert((step, 0));
+        if *rejected_step != step {
+            *rejected_step = step;
+            *count = 0;
+        }
+        *count += 1;
+    }
+
+    /// Records how a connection from `source` given credentials by
+    /// [`Self::issue`] ended. Only rejected credentials count towards a
+    /// lockout.
+    pub fn report(&self, source: IpAddr, outcome: LoginOutcome) {
+        if outcome == LoginOutcome::LoggedIn {
+            self.failures.borrow_mut().remove(&source);
+            self.totp_rejections.borrow_mut().remove(&source);
+            if self.one_time.get() == OneTimeState::Claimed {
+                tracing::info!("One-time password used");
+                self.one_time.set(OneTimeState::Used);
+            }
+            return;
+        }
+
+        if self.one_time.get() == OneTimeState::Claimed {
+            self.one_time.set(OneTimeState::Available);
+        }
+        if outcome == LoginOutcome::Abandoned {
+            tracing::info!(%source, "Connection ended before logging in");
+            return;
+        }
+        tracing::warn!(%source, "Credentials rejected");
+        if matches!(self.password, Password::Totp(_)) {
+            self.reject_totp(source, unix_time());
+        }
+        let now = Instant::now();
+        let mut failures = self.failures.borrow_mut();
+        let failures = failures.entry(source).or_default();
+        failures.last_failure = Some(now);
+        failures.recent.push_back(now);
+        while failures
+            .recent
+            .front()
+            .is_some_and(|failed| now.duration_since(*failed) >= FAILURE_WINDOW)
+        {
+            failures.recent.pop_front();
+        }
+        if failures.recent.len() >= self.limit.max_failures {
+            let lockout =
+                self.limit.lockout * 2u32.pow(failures.lockouts.min(MAX_LOCKOUT_DOUBLINGS));
+            tracing::warn!(%source, ?lockout, "Too many failed logins, locking out source");
+            failures.recent.clear();
+            failures.locked_until = Some(now + lockout);
+            failures.lockouts += 1;
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// RFC 6238 TOTP with HMAC-SHA1 at `time` seconds since the epoch.
+fn totp(secret: &[u8], time: u64) -> String {
+    totp_at_step(secret, time / TOTP_STEP)
+}
+
+fn totp_at_step(secret: &[u8], step: u64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0F) as usize;
+    let code = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap()) & 0x7FFF_FFFF;
+    format!(
+        "{:0width$}",
+        code % 10u32.pow(TOTP_DIGITS),
+        width = TOTP_DIGITS as usize
+    )
+}
+
+/// TOTP secret, written in RFC 4648 base32 as shown by authenticator setup.
+/// Padding, spaces and case are ignored.
+#[derive(Clone)]
+pub struct TotpSecret(Vec<u8>);
+
+impl std::str::FromStr for TotpSecret {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        decode_base32(s).map(Self)
+    }
+}
+
+/// Keeps the secret out of logs.
+impl std::fmt::Debug for TotpSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TotpSecret(..)")
+    }
+}
+
+fn decode_base32(s: &str) -> Result<Vec<u8>, String> {
+    let mut secret = Vec::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in s.chars().filter(|c| !matches!(c, ' ' | '=')) {
+        let value = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u32 - 'A' as u32,
+            c @ '2'..='7' => c as u32 - '2' as u32 + 26,
+            _ => return Err(format!("invalid base32 character {c:?}")),
+        };
+        buffer = buffer << 5 | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            secret.push((buffer >> bits) as u8);
+        }
+    }
+    if secret.is_empty() {
+        return Err("empty TOTP secret".to_owned());
+    }
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn totp_matches_rfc6238_vectors() {
+        let secret = b"12345678901234567890";
+        assert_eq!(totp(secret, 59), "287082");
+        assert_eq!(totp(secret, 1111111109), "081804");
+        assert_eq!(totp(secret, 1111111111), "050471");
+        assert_eq!(totp(secret, 1234567890), "005924");
+        assert_eq!(totp(secret, 2000000000), "279037");
+    }
+
+    #[test]
+    fn decode_base32_decodes_totp_secrets() {
+        assert_eq!(
+            decode_base32("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").unwrap(),
+            b"12345678901234567890"
+        );
+        assert_eq!(decode_base32("mzxw 6ytb oi======").unwrap(), b"foobar");
+        assert!(decode_base32("MZXW1").is_err());
+        assert!(decode_base32("").is_err());
+    }
+
+    const SOURCE: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER_SOURCE: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 2));
+
+    #[test]
+    fn one_time_password_is_consumed_by_login() {
+        let store = CredentialStore::new("user".to_owned(), Password::OneTime("pw".to_owned()));
+
+        assert_eq!(store.issue(SOURCE).unwrap().password, "pw");
+        assert!(store.issue(SOURCE).is_none());
+        store.report(SOURCE, LoginOutcome::Rejected);
+        assert!(store.issue(SOURCE).is_some());
+        store.report(SOURCE, LoginOutcome::LoggedIn);
+        assert!(store.issue(SOURCE).is_none());
+    }
+
+    #[test]
+    fn only_rejected_logins_count_towards_a_lockout() {
+        let store = CredentialStore::new("user".to_owned(), Password::OneTime("pw".to_owned()))
+            .with_login_limit(LoginLimit {
+                max_failures: 1,
+                lockout: Duration::from_secs(60),
+            });
 
-impl CredentialChecker for DummyCredential {
-    fn auth_data(&self, _username: &str) -> Option<AuthIdentity> {
-        Some(AuthIdentity {
-            username: Username::new("user", None).ok()?,
-            password: Secret::new("user".to_string()),
-        })
+        assert!(store.issue(SOURCE).is_some());
+        store.report(SOURCE, LoginOutcome::Abandoned);
+        // The claim is released without locking the source out
+        assert!(store.issue(SOURCE).is_some());
+        store.report(SOURCE, LoginOutcome::Rejected);
+        assert!(store.issue(SOURCE).is_none());
     }
 
-    fn check(&self, credential: &Credentials) -> bool {
-        credential.username == "user" && credential.password == "user"
+    #[test]
+    fn rejected_totp_codes_are_retried_with_adjacent_steps() {
+        let secret = b"12345678901234567890";
+        let store = CredentialStore::new(
+            "user".to_owned(),
+            Password::Totp(TotpSecret(secret.to_vec())),
+        );
+        let time = 1111111111;
+        let step = time / TOTP_STEP;
+        let issued = |source, time| store.totp_for(secret, source, time);
+
+        assert_eq!(issued(SOURCE, time), totp_at_step(secret, step));
+        store.reject_totp(SOURCE, time);
+        assert_eq!(issued(SOURCE, time), totp_at_step(secret, step - 1));
+        assert_eq!(issued(OTHER_SOURCE, time), totp_at_step(secret, step));
+        store.reject_totp(SOURCE, time);
+        assert_eq!(issued(SOURCE, time), totp_at_step(secret, step + 1));
+        // A new step starts over with its own code
+        let next = time + TOTP_STEP;
+        assert_eq!(issued(SOURCE, next), totp_at_step(secret, step + 1));
+    }
+
+    #[test]
+    fn failed_logins_lock_out_the_source() {
+        let limit = LoginLimit::default();
+        let store = CredentialStore::new("user".to_owned(), Password::Static("pw".to_owned()))
+            .with_login_limit(limit);
+
+        for _ in 0..limit.max_failures {
+            assert!(store.issue(SOURCE).is_some());
+            store.report(SOURCE, LoginOutcome::Rejected);
+        }
+        assert!(store.issue(SOURCE).is_none());
+        assert!(store.issue(OTHER_SOURCE).is_some());
+    }
+
+    #[test]
+    fn repeated_lockouts_get_longer() {
+        let lockout = Duration::from_secs(10);
+        let store = CredentialStore::new("user".to_owned(), Password::Static("pw".to_owned()))
+            .with_login_limit(LoginLimit {
+                max_failures: 1,
+                lockout,
+            });
+        let locked_for = || {
+            let failures = store.failures.borrow();
+            failures[&SOURCE].locked_until.unwrap() - Instant::now()
+        };
+
+        store.report(SOURCE, LoginOutcome::Rejected);
+        assert!(locked_for() <= lockout);
+        // Let the first lockout expire
+        store
+            .failures
+            .borrow_mut()
+            .get_mut(&SOURCE)
+            .unwrap()
+            .locked_until = None;
+        assert!(store.issue(SOURCE).is_some());
+        store.report(SOURCE, LoginOutcome::Rejected);
+        assert!(locked_for() > lockout);
+
+        store.report(SOURCE, LoginOutcome::LoggedIn);
+        assert!(store.issue(SOURCE).is_some());
     }
 }
@@ -0,0 +1,56 @@
+use std::net::{IpAddr, SocketAddr};
+
+use crate::Security;
+
+/// Errors returned by the capture and server setup functions, and by
+/// [`crate::ArisuServer`].
+///
+/// ScreenCaptureKit errors don't implement `std::error::Error`, so they are
+/// carried as their debug representation.
+#[derive(Debug, thiserror::Error)]
+pub enum ArisuError {
+    #[error("screen recording permission is not granted")]
+    PermissionDenied,
+    #[error("no display available to capture")]
+    NoDisplay,
+    #[error("no display {selection:?}, available displays: {available}")]
+    DisplayNotFound {
+        selection: crate::screen::DisplaySelection,
+        available: String,
+    },
+    #[error("failed to get shareable content - {0}")]
+    ShareableContent(String),
+    #[error("failed to configure capture stream - {0}")]
+    StreamConfiguration(String),
+    #[error("failed to start capture - {0}")]
+    CaptureStart(String),
+    #[error("failed to create virtual display - {0}")]
+    VirtualDisplay(String),
+    #[error("a password is required with {0:?} security")]
+    PasswordRequired(Security),
+    #[error("a certificate and key are required with {0:?} security")]
+    CertificateRequired(Security),
+    #[error("no host to listen on")]
+    NoHost,
+    #[error("could not listen on any of {0:?}")]
+    NoListener(Vec<IpAddr>),
+    #[error("server task failed")]
+    Task(#[source] tokio::task::JoinError),
+    #[error("failed to set up TLS")]
+    Tls(#[source] anyhow::Error),
+    #[error("failed to bind {addr}")]
+    Bind {
+        addr: SocketAddr,
+        #[source]
+        source: std::io::Error,
+    },
+    #[cfg(feature = "shm")]
+    #[error("failed to create shared memory segment {name}")]
+    SharedMemory {
+        name: String,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+pub type Result<T, E = ArisuError> = std::result::Result<T, E>;
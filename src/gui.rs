@@ -1,24 +1,47 @@
 #![deny(unsafe_op_in_unsafe_fn)]
 use std::cell::{Cell, RefCell};
+use std::net::SocketAddr;
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
 
-use crate::counter::Interval;
+use arisu::counter::{BitrateCounter, FailureCounter, Interval};
+use arisu::screen::{CaptureControl, FreezeSwitch};
+use arisu::server::{ConsentRequest, SessionEvent};
 use objc2::rc::Retained;
 use objc2::runtime::ProtocolObject;
 use objc2::{define_class, msg_send, sel, DefinedClass, MainThreadMarker, MainThreadOnly};
 use objc2_app_kit::{
-    NSApplication, NSApplicationActivationPolicy, NSApplicationDelegate, NSImage, NSMenu,
-    NSStatusBar, NSStatusBarButton, NSVariableStatusItemLength,
+    NSAlert, NSAlertFirstButtonReturn, NSApplication, NSApplicationActivationPolicy,
+    NSApplicationDelegate, NSBackingStoreType, NSColor, NSControlStateValueOff,
+    NSControlStateValueOn, NSImage, NSMenu, NSMenuItem, NSScreen, NSScreenSaverWindowLevel,
+    NSStatusBar, NSStatusBarButton, NSVariableStatusItemLength, NSWindow,
+    NSWindowCollectionBehavior, NSWindowStyleMask,
 };
 use objc2_foundation::{
     NSNotification, NSObject, NSObjectProtocol, NSString, NSTimeInterval, NSTimer,
+    NSUserNotification, NSUserNotificationCenter,
 };
 
 struct Ivars {
     capture_interval: Interval,
     display_send_interval: Interval,
+    bitrate: BitrateCounter,
+    freeze: FreezeSwitch,
+    freeze_item: RefCell<Option<Retained<NSMenuItem>>>,
+    capture: CaptureControl,
+    pause_item: RefCell<Option<Retained<NSMenuItem>>>,
+    synth_failures: FailureCounter,
     status_bar: Cell<Option<Retained<NSStatusBar>>>,
     status_bar_button: RefCell<Option<Retained<NSStatusBarButton>>>,
     update_timer: Cell<Option<Retained<NSTimer>>>,
+    consent_requests: Option<Receiver<ConsentRequest>>,
+    session_events: Receiver<SessionEvent>,
+    notify_sessions: Cell<bool>,
+    notify_item: RefCell<Option<Retained<NSMenuItem>>>,
+    power_save: bool,
+    blank_local_display: bool,
+    connected_sessions: Cell<usize>,
+    privacy_screens: RefCell<Vec<Retained<NSWindow>>>,
 }
 
 define_class!(
@@ -44,29 +67,76 @@ define_class!(
 
         #[unsafe(method(applicationWillTerminate:))]
         fn will_terminate(&self, _notification: &NSNotification) {
-            println!("Will terminate!");
+            tracing::info!("Quitting, stopping capture");
+            self.ivars().capture.shutdown(SHUTDOWN_TIMEOUT);
         }
 
         #[unsafe(method(onUpdateTimer))]
         fn update_timer(&self) {
             self.on_update_timer();
         }
+
+        #[unsafe(method(toggleFreeze:))]
+        fn toggle_freeze(&self, _sender: Option<&NSObject>) {
+            let freeze = &self.ivars().freeze;
+            freeze.set_frozen(!freeze.is_frozen());
+            self.update_freeze_item();
+        }
+
+        #[unsafe(method(togglePause:))]
+        fn toggle_pause(&self, _sender: Option<&NSObject>) {
+            let capture = &self.ivars().capture;
+            capture.set_paused(!capture.is_paused());
+            self.update_pause_item();
+        }
+
+        #[unsafe(method(toggleNotifications:))]
+        fn toggle_notifications(&self, _sender: Option<&NSObject>) {
+            let notify_sessions = &self.ivars().notify_sessions;
+            notify_sessions.set(!notify_sessions.get());
+            self.update_notify_item();
+        }
     }
 );
 
+/// How long quitting waits for capture to stop and the display to be put back.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
 impl AppDelegate {
     fn new(
         capture_interval: Interval,
         display_send_interval: Interval,
+        bitrate: BitrateCounter,
+        freeze: FreezeSwitch,
+        capture: CaptureControl,
+        synth_failures: FailureCounter,
+        consent_requests: Option<Receiver<ConsentRequest>>,
+        session_events: Receiver<SessionEvent>,
+        power_save: bool,
+        blank_local_display: bool,
         mtm: MainThreadMarker,
     ) -> Retained<Self> {
         let this = Self::alloc(mtm);
         let this = this.set_ivars(Ivars {
             capture_interval,
             display_send_interval,
+            bitrate,
+            freeze,
+            freeze_item: RefCell::new(None),
+            capture,
+            pause_item: RefCell::new(None),
+            synth_failures,
             status_bar: Cell::new(None),
             status_bar_button: RefCell::new(None),
             update_timer: Cell::new(None),
+            consent_requests,
+            session_events,
+            notify_sessions: Cell::new(true),
+            notify_item: RefCell::new(None),
+            power_save,
+            blank_local_display,
+            connected_sessions: Cell::new(0),
+            privacy_screens: RefCell::new(Vec::new()),
         });
         unsafe { msg_send![super(this), init] }
     }
@@ -78,7 +148,11 @@ impl AppDelegate {
         if let Some(button) = unsafe { status_bar_item.button(mtm) } {
             let image = unsafe {
                 NSImage::imageWithSystemSymbolName_accessibilityDescription(
-                    &NSString::from_str("apple.logo"),
+                    &NSString::from_str(if self.ivars().power_save {
+                        "leaf"
+                    } else {
+                        "apple.logo"
+                    }),
                     None,
                 )
             };
@@ -86,6 +160,54 @@ impl AppDelegate {
             self.ivars().status_bar_button.replace(Some(button));
         }
 
+        let menu = NSMenu::new(mtm);
+        let freeze_item = unsafe {
+            NSMenuItem::initWithTitle_action_keyEquivalent(
+                NSMenuItem::alloc(mtm),
+                &NSString::from_str("Freeze Screen"),
+                Some(sel!(toggleFreeze:)),
+                &NSString::from_str(""),
+            )
+        };
+        unsafe { freeze_item.setTarget(Some(self.as_ref())) };
+        menu.addItem(&freeze_item);
+        let pause_item = unsafe {
+            NSMenuItem::initWithTitle_action_keyEquivalent(
+                NSMenuItem::alloc(mtm),
+                &NSString::from_str("Pause Capture"),
+                Some(sel!(togglePause:)),
+                &NSString::from_str(""),
+            )
+        };
+        unsafe { pause_item.setTarget(Some(self.as_ref())) };
+        menu.addItem(&pause_item);
+        let notify_item = unsafe {
+            NSMenuItem::initWithTitle_action_keyEquivalent(
+                NSMenuItem::alloc(mtm),
+                &NSString::from_str("Notify on Connect"),
+                Some(sel!(toggleNotifications:)),
+                &NSString::from_str(""),
+            )
+        };
+        unsafe { notify_item.setTarget(Some(self.as_ref())) };
+        menu.addItem(&notify_item);
+        menu.addItem(&NSMenuItem::separatorItem(mtm));
+        // Without a target the action goes to the application
+        let quit_item = unsafe {
+            NSMenuItem::initWithTitle_action_keyEquivalent(
+                NSMenuItem::alloc(mtm),
+                &NSString::from_str("Quit"),
+                Some(sel!(terminate:)),
+                &NSString::from_str("q"),
+            )
+        };
+        menu.addItem(&quit_item);
+        unsafe { status_bar_item.setMenu(Some(&menu)) };
+        self.ivars().freeze_item.replace(Some(freeze_item));
+        self.ivars().pause_item.replace(Some(pause_item));
+        self.ivars().notify_item.replace(Some(notify_item));
+        self.update_notify_item();
+
         self.ivars().status_bar.replace(Some(status_bar));
 
         let timer = unsafe {
@@ -101,6 +223,9 @@ impl AppDelegate {
     }
 
     fn on_update_timer(&self) {
+        self.handle_consent_requests();
+        self.handle_session_events();
+
         let bar_button = self.ivars().status_bar_button.borrow();
         let Some(bar_button) = bar_button.as_ref() else {
             if let Some(timer) = self.ivars().update_timer.take() {
@@ -113,24 +238,202 @@ impl AppDelegate {
         let capture_fps = 1.0 / capture_interval.as_secs_f64();
         let send_interval = self.ivars().display_send_interval.get();
         let send_fps = 1.0 / send_interval.as_secs_f64();
+        let kbps = self.ivars().bitrate.bitrate() / 1000;
+        let mut title = format!("{:.2}/{:.2}FPS {}kbps", capture_fps, send_fps, kbps);
+        let synth_failures = self.ivars().synth_failures.get();
+        if synth_failures != 0 {
+            title.push_str(&format!(" {synth_failures} dropped input"));
+        }
+
+        unsafe { bar_button.setTitle(&NSString::from_str(&title)) };
+    }
+
+    fn update_freeze_item(&self) {
+        let freeze_item = self.ivars().freeze_item.borrow();
+        let Some(freeze_item) = freeze_item.as_ref() else {
+            return;
+        };
+        let title = if self.ivars().freeze.is_frozen() {
+            "Reveal Live Screen"
+        } else {
+            "Freeze Screen"
+        };
+        unsafe { freeze_item.setTitle(&NSString::from_str(title)) };
+    }
 
-        unsafe {
-            bar_button.setTitle(&NSString::from_str(&format!(
-                "{:.2}/{:.2}FPS",
-                capture_fps, send_fps
-            )))
+    fn update_pause_item(&self) {
+        let pause_item = self.ivars().pause_item.borrow();
+        let Some(pause_item) = pause_item.as_ref() else {
+            return;
+        };
+        let title = if self.ivars().capture.is_paused() {
+            "Resume Capture"
+        } else {
+            "Pause Capture"
+        };
+        unsafe { pause_item.setTitle(&NSString::from_str(title)) };
+    }
+
+    fn update_notify_item(&self) {
+        let notify_item = self.ivars().notify_item.borrow();
+        let Some(notify_item) = notify_item.as_ref() else {
+            return;
         };
+        let state = if self.ivars().notify_sessions.get() {
+            NSControlStateValueOn
+        } else {
+            NSControlStateValueOff
+        };
+        unsafe { notify_item.setState(state) };
     }
+
+    fn handle_session_events(&self) {
+        let connected_sessions = &self.ivars().connected_sessions;
+        while let Ok(event) = self.ivars().session_events.try_recv() {
+            connected_sessions.set(match event {
+                SessionEvent::Connected(_) => connected_sessions.get() + 1,
+                SessionEvent::Disconnected(_) => connected_sessions.get().saturating_sub(1),
+            });
+            if self.ivars().notify_sessions.get() {
+                notify_session(event);
+            }
+        }
+        if self.ivars().blank_local_display {
+            self.update_privacy_screens();
+        }
+    }
+
+    /// Covers every local display while a client is connected. The windows
+    /// belong to this process, which is left out of the capture, so clients
+    /// still see the real screen.
+    fn update_privacy_screens(&self) {
+        let mut privacy_screens = self.ivars().privacy_screens.borrow_mut();
+        let connected = self.ivars().connected_sessions.get() > 0;
+        if connected == !privacy_screens.is_empty() {
+            return;
+        }
+
+        if connected {
+            let mtm = MainThreadMarker::from(self);
+            tracing::info!("Blanking the local display");
+            privacy_screens.extend(
+                NSScreen::screens(mtm)
+                    .iter()
+                    .map(|screen| privacy_screen(&screen, mtm)),
+            );
+        } else {
+            tracing::info!("Restoring the local display");
+            for window in privacy_screens.drain(..) {
+                window.close();
+            }
+        }
+    }
+
+    fn handle_consent_requests(&self) {
+        let Some(consent_requests) = self.ivars().consent_requests.as_ref() else {
+            return;
+        };
+        let mtm = MainThreadMarker::from(self);
+
+        while let Ok(request) = consent_requests.try_recv() {
+            let approved = ask_consent(request.peer, mtm);
+            tracing::info!(peer = %request.peer, approved, "Local user answered consent prompt");
+            if request.reply.send(approved).is_err() {
+                tracing::warn!(peer = %request.peer, "Consent answered after the connection gave up");
+            }
+        }
+    }
+}
+
+/// Shows a banner so someone at the Mac notices remote sessions.
+// UNUserNotificationCenter only works from a signed app bundle
+#[allow(deprecated)]
+fn notify_session(event: SessionEvent) {
+    let (title, peer) = match event {
+        SessionEvent::Connected(peer) => ("Remote desktop client connected", peer),
+        SessionEvent::Disconnected(peer) => ("Remote desktop client disconnected", peer),
+    };
+    let notification = unsafe { NSUserNotification::new() };
+    unsafe {
+        notification.setTitle(Some(&NSString::from_str(title)));
+        notification.setInformativeText(Some(&NSString::from_str(&peer.to_string())));
+        NSUserNotificationCenter::defaultUserNotificationCenter()
+            .deliverNotification(&notification);
+    }
+}
+
+/// Borderless black window over `screen`, above everything but the cursor.
+/// Mouse events pass through, so input from clients reaches the windows below.
+fn privacy_screen(screen: &NSScreen, mtm: MainThreadMarker) -> Retained<NSWindow> {
+    let window = unsafe {
+        NSWindow::initWithContentRect_styleMask_backing_defer(
+            NSWindow::alloc(mtm),
+            screen.frame(),
+            NSWindowStyleMask::Borderless,
+            NSBackingStoreType::Buffered,
+            false,
+        )
+    };
+    unsafe {
+        window.setReleasedWhenClosed(false);
+        window.setBackgroundColor(Some(&NSColor::blackColor()));
+        window.setLevel(NSScreenSaverWindowLevel);
+        window.setIgnoresMouseEvents(true);
+        window.setCollectionBehavior(
+            NSWindowCollectionBehavior::CanJoinAllSpaces | NSWindowCollectionBehavior::Stationary,
+        );
+    }
+    window.orderFrontRegardless();
+    window
+}
+
+fn ask_consent(peer: SocketAddr, mtm: MainThreadMarker) -> bool {
+    NSApplication::sharedApplication(mtm).activateIgnoringOtherApps(true);
+
+    let alert = unsafe { NSAlert::new(mtm) };
+    unsafe {
+        alert.setMessageText(&NSString::from_str("Incoming remote desktop connection"));
+        alert.setInformativeText(&NSString::from_str(&format!(
+            "{peer} wants to view and control this Mac."
+        )));
+        alert.addButtonWithTitle(&NSString::from_str("Allow"));
+        alert.addButtonWithTitle(&NSString::from_str("Deny"));
+    }
+
+    (unsafe { alert.runModal() }) == NSAlertFirstButtonReturn
 }
 
-pub fn run(capture_interval: Interval, display_send_interval: Interval) {
+pub fn run(
+    capture_interval: Interval,
+    display_send_interval: Interval,
+    bitrate: BitrateCounter,
+    freeze: FreezeSwitch,
+    capture: CaptureControl,
+    synth_failures: FailureCounter,
+    consent_requests: Option<Receiver<ConsentRequest>>,
+    session_events: Receiver<SessionEvent>,
+    power_save: bool,
+    blank_local_display: bool,
+) {
     let mtm: MainThreadMarker = MainThreadMarker::new().unwrap();
 
     let app = NSApplication::sharedApplication(mtm);
     app.setActivationPolicy(NSApplicationActivationPolicy::Accessory);
 
     // configure the application delegate
-    let delegate = AppDelegate::new(capture_interval, display_send_interval, mtm);
+    let delegate = AppDelegate::new(
+        capture_interval,
+        display_send_interval,
+        bitrate,
+        freeze,
+        capture,
+        synth_failures,
+        consent_requests,
+        session_events,
+        power_save,
+        blank_local_display,
+        mtm,
+    );
     let object = ProtocolObject::from_ref(&*delegate);
     app.setDelegate(Some(object));
 
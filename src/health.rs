@@ -0,0 +1,84 @@
+use std::time::Duration;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::screen::ScreenCapture;
+
+pub(crate) const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves `GET /healthz` for load balancers and supervisors.
+///
+/// Responds `200 OK` while the capture pipeline is alive and
+/// `503 Service Unavailable` once it has failed. With `--debug-checksums`,
+/// `GET /checksum` returns the checksum of the last frame sent in hex.
+pub async fn serve(listener: TcpListener, screen: ScreenCapture) -> anyhow::Result<()> {
+    tracing::info!("Serving health check on {}", listener.local_addr()?);
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                tracing::error!(?e, "Failed to accept health check connection");
+                continue;
+            }
+        };
+
+        let healthy = screen.is_healthy();
+        let checksum = screen.last_frame_checksum();
+        tokio::task::spawn_local(async move {
+            let respond = respond(stream, healthy, checksum);
+            if let Err(e) = tokio::time::timeout(REQUEST_TIMEOUT, respond).await {
+                tracing::debug!(?e, "Health check request timed out");
+            }
+        });
+    }
+}
+
+/// Reads up to the end of the request line, which is all that matters.
+pub(crate) async fn read_request_line(stream: &mut TcpStream) -> Option<Vec<u8>> {
+    let mut buf = [0; 1024];
+    let mut len = 0;
+    while !buf[..len].contains(&b'\n') && len < buf.len() {
+        match stream.read(&mut buf[len..]).await {
+            Ok(0) | Err(_) => return None,
+            Ok(read) => len += read,
+        }
+    }
+    Some(buf[..len].to_vec())
+}
+
+pub(crate) async fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) {
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    if let Err(e) = stream.write_all(response.as_bytes()).await {
+        tracing::debug!(?e, "Failed to write HTTP response");
+    }
+}
+
+async fn respond(mut stream: TcpStream, healthy: bool, checksum: Option<u64>) {
+    let Some(request_line) = read_request_line(&mut stream).await else {
+        return;
+    };
+
+    let mut request_line = request_line.split(|&b| b == b' ');
+    let (status, body) = match (request_line.next(), request_line.next(), checksum) {
+        (Some(b"GET"), Some(b"/healthz"), _) if healthy => ("200 OK", None),
+        (Some(b"GET"), Some(b"/healthz"), _) => ("503 Service Unavailable", None),
+        (Some(b"GET"), Some(b"/checksum"), Some(checksum)) => {
+            ("200 OK", Some(format!("{checksum:016x}")))
+        }
+        _ => ("404 Not Found", None),
+    };
+    let body = body.unwrap_or_else(|| status.to_owned());
+    write_response(&mut stream, status, "text/plain", &format!("{body}\n")).await;
+}
@@ -1,18 +1,931 @@
+use std::time::{Duration, Instant};
+
 use anyhow::Context;
 use ironrdp::server::{KeyboardEvent, MouseEvent, RdpServerInputHandler};
-use objc2_core_foundation::{CFRetained, CGPoint};
+use objc2_app_kit::{NSEvent, NSRunningApplication, NSWorkspace};
+use objc2_core_foundation::CGPoint;
 use objc2_core_graphics::{
     CGEvent, CGEventFlags, CGEventTapLocation, CGMouseButton, CGScrollEventUnit,
 };
+use strum::EnumString;
 use tokio::sync::watch;
 
-use crate::screen::ScreenSize;
+use crate::{
+    audit::SessionAudit,
+    counter::{FailureCounter, IntervalCounter},
+    multi_client::ControlLease,
+    screen::{DisplayGeometry, RefreshRequest, ScreenSize},
+};
+
+/// macOS virtual keycode of the Fn key.
+const FN_KEYCODE: u16 = 0x3F;
+/// macOS virtual keycode of the space bar.
+const SPACE_KEYCODE: u16 = 0x31;
+/// macOS virtual keycode of the Escape key.
+const ESCAPE_KEYCODE: u16 = 0x35;
+/// macOS virtual keycode of the Q key.
+const Q_KEYCODE: u16 = 0x0C;
+/// macOS virtual keycode of the right Shift key, which the self-test taps
+/// since a lone Shift does nothing.
+const RIGHT_SHIFT_KEYCODE: u16 = 0x3C;
+/// Scroll delta of one mouse wheel notch.
+const WHEEL_DELTA: i32 = 120;
+/// Back and forward mouse buttons, by CoreGraphics button number.
+const X1_BUTTON: CGMouseButton = CGMouseButton(3);
+const X2_BUTTON: CGMouseButton = CGMouseButton(4);
+/// Presses of the same button at most this many points apart, and within
+/// the user's double-click interval, count as one multi-click.
+const DOUBLE_CLICK_DISTANCE: f64 = 4.0;
+
+#[link(name = "Carbon", kind = "framework")]
+extern "C" {
+    fn IsSecureEventInputEnabled() -> u8;
+}
+
+/// PC scan code as sent by the client.
+///
+/// Parsed from a decimal or hexadecimal number. Extended keys are written with
+/// the `E0` prefix, e.g. `0xE01D` for right Ctrl.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanCode {
+    pub code: u8,
+    pub extended: bool,
+}
+
+impl std::str::FromStr for ScanCode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            u16::from_str_radix(hex, 16)
+        } else {
+            s.parse()
+        }
+        .map_err(|e| format!("invalid scan code {s:?} - {e}"))?;
+
+        match value.to_be_bytes() {
+            [0, code] => Ok(Self {
+                code,
+                extended: false,
+            }),
+            [0xE0, code] => Ok(Self {
+                code,
+                extended: true,
+            }),
+            _ => Err(format!("invalid scan code {s:?}")),
+        }
+    }
+}
+
+/// Client key combination written as modifiers and a scan code joined by
+/// `+`, e.g. `ctrl+shift+0x39`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyCombo {
+    pub control: bool,
+    pub shift: bool,
+    pub option: bool,
+    pub command: bool,
+    pub key: ScanCode,
+}
+
+impl std::str::FromStr for KeyCombo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split('+').map(str::trim);
+        let key = parts
+            .next_back()
+            .ok_or_else(|| format!("invalid key combo {s:?}"))?
+            .parse()?;
+        let mut combo = Self {
+            control: false,
+            shift: false,
+            option: false,
+            command: false,
+            key,
+        };
+        for modifier in parts {
+            match modifier.to_ascii_lowercase().as_str() {
+                "ctrl" | "control" => combo.control = true,
+                "shift" => combo.shift = true,
+                "alt" | "option" => combo.option = true,
+                "cmd" | "command" | "win" => combo.command = true,
+                _ => return Err(format!("unknown modifier {modifier:?} in {s:?}")),
+            }
+        }
+        Ok(combo)
+    }
+}
+
+/// Modifier role of a key on the Mac.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Modifier {
+    Command,
+    Option,
+    Control,
+    Shift,
+}
+
+impl std::str::FromStr for Modifier {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "cmd" | "command" | "win" => Self::Command,
+            "opt" | "option" | "alt" => Self::Option,
+            "ctrl" | "control" => Self::Control,
+            "shift" => Self::Shift,
+            _ => return Err(format!("unknown modifier {s:?}")),
+        })
+    }
+}
+
+impl Modifier {
+    fn keycode(self, right: bool) -> u16 {
+        match (self, right) {
+            (Self::Command, false) => 0x37,
+            (Self::Command, true) => 0x36,
+            (Self::Option, false) => 0x3A,
+            (Self::Option, true) => 0x3D,
+            (Self::Control, false) => 0x3B,
+            (Self::Control, true) => 0x3E,
+            (Self::Shift, false) => 0x38,
+            (Self::Shift, true) => 0x3C,
+        }
+    }
+}
+
+/// Client modifier key, written with an `L` or `R` prefix, e.g. `LWIN`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModifierKey {
+    pub modifier: Modifier,
+    pub right: bool,
+}
+
+impl std::str::FromStr for ModifierKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (side, modifier) = s
+            .split_at_checked(1)
+            .ok_or_else(|| format!("invalid modifier key {s:?}"))?;
+        let right = match side {
+            "L" | "l" => false,
+            "R" | "r" => true,
+            _ => return Err(format!("modifier key {s:?} must start with L or R")),
+        };
+        Ok(Self {
+            modifier: modifier.parse()?,
+            right,
+        })
+    }
+}
+
+impl ModifierKey {
+    /// Key the client's Windows keyboard has at `code`, where Windows is
+    /// Command.
+    fn from_scancode(code: u8, extended: bool) -> Option<Self> {
+        let (modifier, right) = match (code, extended) {
+            (91, true) => (Modifier::Command, false),
+            (92, true) => (Modifier::Command, true),
+            (29, false) => (Modifier::Control, false),
+            (29, true) => (Modifier::Control, true),
+            (42, false) => (Modifier::Shift, false),
+            (54, false) => (Modifier::Shift, true),
+            (56, false) => (Modifier::Option, false),
+            // AltGr
+            (56, true) => (Modifier::Option, true),
+            _ => return None,
+        };
+        Some(Self { modifier, right })
+    }
+}
+
+/// Client modifier key acting as another modifier, e.g. `LWIN=CTRL`. The
+/// key keeps its side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyRemap {
+    pub from: ModifierKey,
+    pub to: Modifier,
+}
+
+impl std::str::FromStr for KeyRemap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (from, to) = s
+            .split_once('=')
+            .ok_or_else(|| format!("invalid remap {s:?}, expected KEY=MODIFIER"))?;
+        Ok(Self {
+            from: from.trim().parse()?,
+            to: to.trim().parse()?,
+        })
+    }
+}
+
+/// macOS shortcut posted to switch the input source.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, EnumString)]
+#[strum(serialize_all = "kebab-case", ascii_case_insensitive)]
+pub enum InputSourceSwitch {
+    /// Ctrl+Space, "Select the previous input source".
+    #[default]
+    CtrlSpace,
+    /// Ctrl+Option+Space, "Select next source in Input menu".
+    CtrlOptionSpace,
+}
+
+impl InputSourceSwitch {
+    fn shortcut(self) -> Shortcut {
+        Shortcut {
+            keycode: SPACE_KEYCODE,
+            flags: match self {
+                Self::CtrlSpace => CGEventFlags::MaskControl,
+                Self::CtrlOptionSpace => CGEventFlags::MaskControl | CGEventFlags::MaskAlternate,
+            },
+        }
+    }
+}
+
+/// macOS action for the client's Ctrl+Alt+Del.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, EnumString)]
+#[strum(serialize_all = "kebab-case", ascii_case_insensitive)]
+pub enum SecureAttentionAction {
+    /// Pass the keys through as they are.
+    #[strum(serialize = "none", serialize = "ignore")]
+    None,
+    /// Cmd+Option+Esc, the Force Quit Applications window.
+    #[default]
+    #[strum(serialize = "force-quit", serialize = "forcequit")]
+    ForceQuit,
+    /// Ctrl+Cmd+Q, lock the screen.
+    Lock,
+}
+
+impl SecureAttentionAction {
+    fn shortcut(self) -> Option<Shortcut> {
+        match self {
+            Self::None => None,
+            Self::ForceQuit => Some(Shortcut {
+                keycode: ESCAPE_KEYCODE,
+                flags: CGEventFlags::MaskCommand | CGEventFlags::MaskAlternate,
+            }),
+            Self::Lock => Some(Shortcut {
+                keycode: Q_KEYCODE,
+                flags: CGEventFlags::MaskControl | CGEventFlags::MaskCommand,
+            }),
+        }
+    }
+}
+
+/// Unit client scroll deltas are posted in. Clients send wheel notches as
+/// multiples of 120.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, EnumString)]
+#[strum(serialize_all = "kebab-case", ascii_case_insensitive)]
+pub enum ScrollUnit {
+    /// Deltas as pixels.
+    #[default]
+    Pixel,
+    /// Deltas as notches, each scrolling a line.
+    Line,
+    /// Whole notches as lines and anything finer, e.g. from a trackpad, as
+    /// pixels.
+    Auto,
+}
+
+/// How client pointer positions move the Mac's cursor.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, EnumString)]
+#[strum(serialize_all = "kebab-case", ascii_case_insensitive)]
+pub enum PointerMode {
+    /// Warp the cursor to the position.
+    #[default]
+    Absolute,
+    /// Post mouse moves carrying the change from the last position, for apps
+    /// which capture the pointer and only read deltas.
+    Relative,
+}
+
+/// Physical layout of the client's keyboard.
+///
+/// Scancodes and macOS keycodes both name key positions, so the characters
+/// typed come from the Mac's input source, which should match the client's
+/// layout, e.g. "German" for QWERTZ or "Dvorak" on a US keyboard. The
+/// layouts differ in the extra key ISO keyboards have, which macOS reports
+/// swapped with the key left of 1.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, EnumString)]
+#[strum(ascii_case_insensitive)]
+pub enum KeyboardLayout {
+    /// ANSI keyboard, also for Dvorak.
+    #[default]
+    #[strum(serialize = "us")]
+    Us,
+    #[strum(serialize = "uk", serialize = "gb")]
+    Uk,
+    /// QWERTZ
+    #[strum(serialize = "german", serialize = "de")]
+    German,
+    /// AZERTY
+    #[strum(serialize = "french", serialize = "fr")]
+    French,
+}
+
+/// Scancodes of the keys typing characters, row by row, then space.
+const CHARACTER_KEYS: [u8; 49] = [
+    41, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, //
+    16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 43, //
+    30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, //
+    86, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, //
+    57,
+];
+
+impl KeyboardLayout {
+    /// Characters typed by `CHARACTER_KEYS` without and with shift. NUL
+    /// marks a key typing nothing. Dead keys type their accent alone.
+    fn characters(self) -> [&'static str; 2] {
+        match self {
+            Self::Us => [
+                "`1234567890-=qwertyuiop[]\\asdfghjkl;'\\zxcvbnm,./ ",
+                "~!@#$%^&*()_+QWERTYUIOP{}|ASDFGHJKL:\"|ZXCVBNM<>? ",
+            ],
+            Self::Uk => [
+                "`1234567890-=qwertyuiop[]#asdfghjkl;'\\zxcvbnm,./ ",
+                "¬!\"£$%^&*()_+QWERTYUIOP{}~ASDFGHJKL:@|ZXCVBNM<>? ",
+            ],
+            Self::German => [
+                "^1234567890ß´qwertzuiopü+#asdfghjklöä<yxcvbnm,.- ",
+                "°!\"§$%&/()=?`QWERTZUIOPÜ*'ASDFGHJKLÖÄ>YXCVBNM;:_ ",
+            ],
+            Self::French => [
+                "²&é\"'(-è_çà)=azertyuiop^$*qsdfghjklmù<wxcvbn,;:! ",
+                "\01234567890°+AZERTYUIOP¨£µQSDFGHJKLM%>WXCVBN?./§ ",
+            ],
+        }
+    }
+
+    /// Character typed by a key with the given modifiers, `None` for keys
+    /// which don't type one.
+    fn character(self, code: u8, extended: bool, shift: bool, caps_lock: bool) -> Option<char> {
+        if extended {
+            return None;
+        }
+        let position = CHARACTER_KEYS.iter().position(|&key| key == code)?;
+        let [plain, shifted] = self.characters().map(|keys| keys.chars().nth(position));
+        let (character, other) = if shift {
+            (shifted?, plain?)
+        } else {
+            (plain?, shifted?)
+        };
+        // Caps lock only switches letters which have a case on this key
+        let character = if caps_lock && character.is_alphabetic() && other.is_alphabetic() {
+            other
+        } else {
+            character
+        };
+        (character != '\0').then_some(character)
+    }
+
+    /// macOS keycode of a key other than a modifier, `None` for keys the Mac
+    /// doesn't have.
+    fn keycode(self, code: u8, extended: bool) -> Option<u16> {
+        let iso = self != Self::Us;
+        Some(match (code, extended) {
+            // Key left of 1 and the ISO key right of left shift
+            (41, false) if iso => 0x0A,
+            (86, false) if iso => 0x32,
+            (86, false) => 0x0A,
+            // Delete
+            (14, false) => 0x33,
+            // Return
+            (28, false) => 0x24,
+            // qwertyuiop
+            (16, false) => 0x0C,
+            (17, false) => 0x0D,
+            (18, false) => 0x0E,
+            (19, false) => 0x0F,
+            (20, false) => 0x11,
+            (21, false) => 0x10,
+            (22, false) => 0x20,
+            (23, false) => 0x22,
+            (24, false) => 0x1F,
+            (25, false) => 0x23,
+            // asdfghjkl;
+            (30, false) => 0x00,
+            (31, false) => 0x01,
+            (32, false) => 0x02,
+            (33, false) => 0x03,
+            (34, false) => 0x05,
+            (35, false) => 0x04,
+            (36, false) => 0x26,
+            (37, false) => 0x28,
+            (38, false) => 0x25,
+            (39, false) => 0x29,
+            // zxcvbnm
+            (44, false) => 0x06,
+            (45, false) => 0x07,
+            (46, false) => 0x08,
+            (47, false) => 0x09,
+            (48, false) => 0x0B,
+            (49, false) => 0x2D,
+            (50, false) => 0x2E,
+            // F1..F12
+            (59, false) => 0x7A,
+            (60, false) => 0x78,
+            (61, false) => 0x63,
+            (62, false) => 0x76,
+            (63, false) => 0x60,
+            (64, false) => 0x61,
+            (65, false) => 0x62,
+            (66, false) => 0x64,
+            (67, false) => 0x65,
+            (68, false) => 0x6D,
+            (87, false) => 0x67,
+            (88, false) => 0x6F,
+            // Tab
+            (15, false) => 0x30,
+            // Arrow(left, up, down, right)
+            (75, true) => 0x7B,
+            (72, true) => 0x7E,
+            (80, true) => 0x7D,
+            (77, true) => 0x7C,
+            // Del
+            (83, true) => 0x75,
+            // Home, End, PgUp, PgDn
+            (71, true) => 0x73,
+            (79, true) => 0x77,
+            (73, true) => 0x74,
+            (81, true) => 0x79,
+            // ESC
+            (1, false) => 0x35,
+            // PrintScr, ScrollLock, Break
+            (55, true) => return None,
+            (70, false) => return None,
+            (69, false) => return None,
+            // 1..0
+            (2, false) => 0x12,
+            (3, false) => 0x13,
+            (4, false) => 0x14,
+            (5, false) => 0x15,
+            (6, false) => 0x17,
+            (7, false) => 0x16,
+            (8, false) => 0x1A,
+            (9, false) => 0x1C,
+            (10, false) => 0x19,
+            (11, false) => 0x1D,
+            // - = [ ] \ ' ` , . /
+            (12, false) => 0x1B,
+            (13, false) => 0x18,
+            (26, false) => 0x21,
+            (27, false) => 0x1E,
+            (43, false) => 0x2A,
+            (40, false) => 0x27,
+            (41, false) => 0x32,
+            (51, false) => 0x2B,
+            (52, false) => 0x2F,
+            (53, false) => 0x2C,
+            _ => {
+                tracing::trace!(?code, ?extended);
+                code as _
+            }
+        })
+    }
+}
+
+/// macOS key and modifiers posted in place of a client key.
+#[derive(Debug, Clone, Copy)]
+struct Shortcut {
+    keycode: u16,
+    flags: CGEventFlags,
+}
+
+/// Process synthesized events are posted to instead of the login session.
+///
+/// Per-process posting is best effort: the events skip the window server's
+/// routing, so global shortcuts and the Dock never see them, many apps
+/// ignore events while they aren't active, secure input fields drop them,
+/// and mouse positions are still global screen coordinates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InputTarget {
+    Pid(i32),
+    /// Running application by bundle identifier or name.
+    App(String),
+}
+
+impl InputTarget {
+    /// Pid of the running target process.
+    fn resolve(&self) -> Option<i32> {
+        match self {
+            Self::Pid(pid) => is_running(*pid).then_some(*pid),
+            Self::App(name) => {
+                let apps = unsafe { NSWorkspace::sharedWorkspace().runningApplications() };
+                apps.iter()
+                    .find(|app| unsafe {
+                        !app.isTerminated()
+                            && (app
+                                .bundleIdentifier()
+                                .is_some_and(|id| id.to_string() == *name)
+                                || app
+                                    .localizedName()
+                                    .is_some_and(|app_name| app_name.to_string() == *name))
+                    })
+                    .map(|app| unsafe { app.processIdentifier() })
+            }
+        }
+    }
+}
+
+fn is_running(pid: i32) -> bool {
+    unsafe { NSRunningApplication::runningApplicationWithProcessIdentifier(pid) }
+        .is_some_and(|app| !unsafe { app.isTerminated() })
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct InputOptions {
+    /// Client key which acts as the macOS Fn modifier.
+    pub fn_key: Option<ScanCode>,
+    /// Client key combo which switches the macOS input source.
+    ///
+    /// Only the switch itself goes through here. Text composed by a client
+    /// side IME is still committed through the unicode key events.
+    pub input_source_key: Option<KeyCombo>,
+    /// Shortcut posted when `input_source_key` is pressed.
+    pub input_source_switch: InputSourceSwitch,
+    /// Action for Ctrl+Alt+Del.
+    pub secure_attention: SecureAttentionAction,
+    /// Client key combo which forces a full screen refresh. The combo is
+    /// not passed on to the Mac.
+    pub refresh_key: Option<KeyCombo>,
+    /// Signalled when `refresh_key` is pressed.
+    pub refresh: RefreshRequest,
+    /// Process events are posted to, the login session when unset or not
+    /// running.
+    pub target: Option<InputTarget>,
+    /// Counts events CoreGraphics failed to create.
+    pub synth_failures: FailureCounter,
+    /// Touched on every client input, for adaptive quality.
+    pub activity: Option<IntervalCounter>,
+    /// Multiplier for scroll deltas, 1.0 when unset.
+    pub scroll_speed: Option<f64>,
+    pub scroll_unit: ScrollUnit,
+    pub pointer_mode: PointerMode,
+    pub keyboard_layout: KeyboardLayout,
+    /// Client modifier keys acting as other modifiers.
+    pub remap: Vec<KeyRemap>,
+    /// Post keys typing a character in `keyboard_layout` as that character,
+    /// regardless of the Mac's input source. Keys pressed with Control,
+    /// Option, Command or Fn still go by position, for shortcuts.
+    pub prefer_unicode: bool,
+    /// Drop the presses clients repeat while a key is held.
+    pub no_autorepeat: bool,
+}
+
+/// Event to synthesize on the Mac, converted from client input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SynthEvent {
+    Key {
+        keycode: u16,
+        pressed: bool,
+        flags: CGEventFlags,
+    },
+    Unicode {
+        code: u16,
+        pressed: bool,
+    },
+    MouseButton {
+        button: CGMouseButton,
+        pressed: bool,
+        point: CGPoint,
+        /// Click count, 2 for the second press of a double-click and its
+        /// release.
+        clicks: i64,
+    },
+    MouseDrag {
+        button: CGMouseButton,
+        point: CGPoint,
+    },
+    /// Moves the cursor without a button held.
+    MouseWarp(CGPoint),
+    /// Moves the cursor to `point` by `delta`, in relative pointer mode.
+    MouseDelta {
+        button: Option<CGMouseButton>,
+        point: CGPoint,
+        delta: (i64, i64),
+    },
+    /// `value` counts lines with `lines` and pixels otherwise.
+    VerticalScroll {
+        value: i32,
+        lines: bool,
+    },
+    /// Positive values scroll left.
+    HorizontalScroll {
+        value: i32,
+        lines: bool,
+    },
+}
+
+/// Receives the events `InputHandler` synthesizes.
+pub trait EventSink {
+    fn post(&mut self, event: SynthEvent);
+}
+
+/// Posts events into the login session, or to the target process, through
+/// CoreGraphics.
+pub struct CoreGraphicsSink {
+    failures: FailureCounter,
+    target: Option<InputTarget>,
+    /// Pid `target` last resolved to.
+    target_pid: Option<i32>,
+}
+
+impl CoreGraphicsSink {
+    fn target_pid(&mut self) -> Option<i32> {
+        let target = self.target.as_ref()?;
+        if self.target_pid.is_some_and(is_running) {
+            return self.target_pid;
+        }
+        let pid = target.resolve();
+        if pid != self.target_pid {
+            match pid {
+                Some(pid) => tracing::info!(?target, pid, "Posting input to target process"),
+                None => tracing::warn!(?target, "Input target is not running, posting to session"),
+            }
+        }
+        self.target_pid = pid;
+        pid
+    }
+}
+
+impl EventSink for CoreGraphicsSink {
+    fn post(&mut self, event: SynthEvent) {
+        use objc2_core_graphics::{CGEventField, CGEventType, CGWarpMouseCursorPosition};
 
-pub struct InputHandler {
+        let cg_event = match event {
+            SynthEvent::Key {
+                keycode,
+                pressed,
+                flags,
+            } => unsafe { CGEvent::new_keyboard_event(None, keycode, pressed) }.inspect(|event| {
+                if flags.0 != 0 {
+                    unsafe { CGEvent::set_flags(Some(event.as_ref()), flags) };
+                }
+            }),
+            SynthEvent::Unicode { code, pressed } => {
+                unsafe { CGEvent::new_keyboard_event(None, 0, pressed) }.inspect(|event| unsafe {
+                    CGEvent::keyboard_set_unicode_string(Some(event.as_ref()), 1, &code)
+                })
+            }
+            SynthEvent::MouseButton {
+                button,
+                pressed,
+                point,
+                clicks,
+            } => {
+                let event_type = match (button, pressed) {
+                    (CGMouseButton::Left, true) => CGEventType::LeftMouseDown,
+                    (CGMouseButton::Left, false) => CGEventType::LeftMouseUp,
+                    (CGMouseButton::Right, true) => CGEventType::RightMouseDown,
+                    (CGMouseButton::Right, false) => CGEventType::RightMouseUp,
+                    (_, true) => CGEventType::OtherMouseDown,
+                    (_, false) => CGEventType::OtherMouseUp,
+                };
+                unsafe { CGEvent::new_mouse_event(None, event_type, point, button) }.inspect(
+                    |event| unsafe {
+                        let event = Some(event.as_ref());
+                        CGEvent::set_integer_value_field(
+                            event,
+                            CGEventField::MouseEventButtonNumber,
+                            button.0 as i64,
+                        );
+                        CGEvent::set_integer_value_field(
+                            event,
+                            CGEventField::MouseEventClickState,
+                            clicks,
+                        );
+                    },
+                )
+            }
+            SynthEvent::MouseDrag { button, point } => {
+                let event_type = match button {
+                    CGMouseButton::Left => CGEventType::LeftMouseDragged,
+                    CGMouseButton::Right => CGEventType::RightMouseDragged,
+                    _ => CGEventType::OtherMouseDragged,
+                };
+                unsafe { CGEvent::new_mouse_event(None, event_type, point, button) }
+            }
+            SynthEvent::MouseDelta {
+                button,
+                point,
+                delta: (delta_x, delta_y),
+            } => {
+                let event_type = match button {
+                    None => CGEventType::MouseMoved,
+                    Some(CGMouseButton::Left) => CGEventType::LeftMouseDragged,
+                    Some(CGMouseButton::Right) => CGEventType::RightMouseDragged,
+                    Some(_) => CGEventType::OtherMouseDragged,
+                };
+                let button = button.unwrap_or(CGMouseButton::Left);
+                unsafe { CGEvent::new_mouse_event(None, event_type, point, button) }.inspect(
+                    |event| unsafe {
+                        let event = Some(event.as_ref());
+                        CGEvent::set_integer_value_field(
+                            event,
+                            CGEventField::MouseEventDeltaX,
+                            delta_x,
+                        );
+                        CGEvent::set_integer_value_field(
+                            event,
+                            CGEventField::MouseEventDeltaY,
+                            delta_y,
+                        );
+                    },
+                )
+            }
+            SynthEvent::MouseWarp(point) => {
+                let err = unsafe { CGWarpMouseCursorPosition(point) };
+                if err.0 != 0 {
+                    tracing::error!("[CGWarpMouseCursorPosition] error - {}", err.0);
+                }
+                return;
+            }
+            SynthEvent::VerticalScroll { value, lines } => unsafe {
+                CGEvent::new_scroll_wheel_event2(None, scroll_event_unit(lines), 1, value, 0, 0)
+            },
+            SynthEvent::HorizontalScroll { value, lines } => unsafe {
+                CGEvent::new_scroll_wheel_event2(None, scroll_event_unit(lines), 2, 0, value, 0)
+            },
+        };
+        let Some(cg_event) = synthesized(cg_event, &self.failures, format_args!("{event:?}"))
+        else {
+            return;
+        };
+        match self.target_pid() {
+            Some(pid) => unsafe { CGEvent::post_to_pid(pid, Some(&cg_event)) },
+            None => unsafe { CGEvent::post(CGEventTapLocation::SessionEventTap, Some(&cg_event)) },
+        }
+    }
+}
+
+/// Taps the right Shift key through the path [`InputHandler`] posts with and
+/// checks that the session saw it, warning about the usual reasons typing
+/// silently does nothing. Returns whether input synthesis looks usable.
+pub fn self_test(failures: &FailureCounter) -> bool {
+    use objc2_core_graphics::{CGEventSource, CGEventSourceStateID, CGPreflightPostEventAccess};
+
+    let mut usable = true;
+    if !unsafe { CGPreflightPostEventAccess() } {
+        tracing::error!(
+            "Input self-test: no permission to post events. Grant ARISU Accessibility access \
+             in System Settings > Privacy & Security > Accessibility and restart it"
+        );
+        usable = false;
+    }
+    if unsafe { IsSecureEventInputEnabled() } != 0 {
+        tracing::warn!(
+            "Input self-test: secure input is on (e.g. a password field has focus), \
+             remote typing is ignored until it is turned off"
+        );
+        usable = false;
+    }
+
+    let mut sink = CoreGraphicsSink {
+        failures: failures.clone(),
+        target: None,
+        target_pid: None,
+    };
+    let key = |pressed| SynthEvent::Key {
+        keycode: RIGHT_SHIFT_KEYCODE,
+        pressed,
+        flags: CGEventFlags(0),
+    };
+    sink.post(key(true));
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    let delivered = unsafe {
+        CGEventSource::key_state(
+            CGEventSourceStateID::CombinedSessionState,
+            RIGHT_SHIFT_KEYCODE,
+        )
+    };
+    sink.post(key(false));
+    if !delivered {
+        tracing::error!(
+            "Input self-test: a synthetic key press did not reach the session. \
+             Check the Accessibility permission"
+        );
+        usable = false;
+    }
+
+    if usable {
+        tracing::info!("Input self-test passed");
+    }
+    usable
+}
+
+/// Drops an event CoreGraphics failed to create, with a warning and a count
+/// in `failures`, so one failure doesn't take down input handling.
+fn synthesized<T>(
+    event: Option<T>,
+    failures: &FailureCounter,
+    context: impl std::fmt::Display,
+) -> Option<T> {
+    if event.is_none() {
+        tracing::warn!(%context, "Failed to create event, dropping it");
+        failures.increment();
+    }
+    event
+}
+
+fn scroll_event_unit(lines: bool) -> CGScrollEventUnit {
+    if lines {
+        CGScrollEventUnit::Line
+    } else {
+        CGScrollEventUnit::Pixel
+    }
+}
+
+/// Converts a client scroll delta in `unit`, returning the delta and whether
+/// it counts lines.
+fn scroll_delta(value: i32, unit: ScrollUnit) -> (i32, bool) {
+    let lines = match unit {
+        ScrollUnit::Pixel => false,
+        ScrollUnit::Line => true,
+        ScrollUnit::Auto => value % WHEEL_DELTA == 0,
+    };
+    if !lines {
+        return (value, false);
+    }
+    // A fraction of a notch, from a smooth wheel, still scrolls a line
+    let value = match value / WHEEL_DELTA {
+        0 => value.signum(),
+        notches => notches,
+    };
+    (value, true)
+}
+
+/// Multiplies a scroll delta by `speed`, saturating instead of overflowing.
+fn scale_scroll(value: i32, speed: Option<f64>) -> i32 {
+    match speed {
+        Some(speed) => (value as f64 * speed).round() as i32,
+        None => value,
+    }
+}
+
+/// Scales a client coordinate onto a server axis of `server_dim` pixels.
+///
+/// A zero client dimension, which a client could report before the first
+/// layout, leaves the position unscaled instead of dividing by zero.
+fn scale_coord(client_pos: u16, client_dim: u16, server_dim: f64) -> f64 {
+    if client_dim == 0 {
+        return client_pos as f64;
+    }
+    client_pos as f64 * server_dim / client_dim as f64
+}
+
+/// Maps a point on a canvas which lays `displays` out side by side at their
+/// native pixel size into the global display space.
+fn canvas_to_global(displays: &[DisplayGeometry], x: f64, y: f64) -> Option<CGPoint> {
+    let mut canvas_x = 0.0;
+    for display in displays {
+        let (pixel_width, pixel_height) = display.pixel_size();
+        if x >= canvas_x && x < canvas_x + pixel_width && y >= 0.0 && y < pixel_height {
+            return Some(CGPoint {
+                x: display.origin.0 + (x - canvas_x) / display.scale_factor,
+                y: display.origin.1 + y / display.scale_factor,
+            });
+        }
+        canvas_x += pixel_width;
+    }
+    None
+}
+
+pub struct InputHandler<S: EventSink = CoreGraphicsSink> {
+    sink: S,
     last_mouse_point: CGPoint,
     down_mouse_button: Option<CGMouseButton>,
     modifier_state: Modifiers,
     client_screen_size: watch::Receiver<ScreenSize>,
+    options: InputOptions,
+    audit: Option<SessionAudit>,
+    /// Client key currently replaced by a macOS shortcut.
+    held_shortcut: Option<(ScanCode, Shortcut)>,
+    /// Client key of the refresh combo while it is held.
+    held_refresh: Option<ScanCode>,
+    /// Client keys pressed and not released yet.
+    held_keys: Vec<ScanCode>,
+    /// Client keys posted as characters, with `prefer_unicode`, while held.
+    held_characters: Vec<(ScanCode, u16)>,
+    /// Input is dropped while another session has control.
+    control: Option<ControlLease>,
+    /// Whether the session had control at its last input, to release what it
+    /// held once another session takes over.
+    had_control: bool,
+    /// Last button press, to count multi-clicks.
+    last_click: Option<Click>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Click {
+    button: CGMouseButton,
+    time: Instant,
+    point: CGPoint,
+    clicks: i64,
 }
 
 #[derive(Default, Debug)]
@@ -21,19 +934,151 @@ struct Modifiers {
     command: bool,
     option: bool,
     control: bool,
+    function: bool,
+    /// Toggled by each Caps Lock press.
+    caps_lock: bool,
 }
 
 impl InputHandler {
-    pub fn new(client_screen_size: watch::Receiver<ScreenSize>) -> Self {
+    pub fn new(
+        client_screen_size: watch::Receiver<ScreenSize>,
+        options: InputOptions,
+        audit: Option<SessionAudit>,
+    ) -> Self {
+        let sink = CoreGraphicsSink {
+            failures: options.synth_failures.clone(),
+            target: options.target.clone(),
+            target_pid: None,
+        };
+        Self::with_sink(client_screen_size, options, audit, sink)
+    }
+}
+
+impl<S: EventSink> InputHandler<S> {
+    pub fn with_sink(
+        client_screen_size: watch::Receiver<ScreenSize>,
+        options: InputOptions,
+        audit: Option<SessionAudit>,
+        sink: S,
+    ) -> Self {
         Self {
+            sink,
             last_mouse_point: CGPoint { x: 0.0, y: 0.0 },
             down_mouse_button: None,
             modifier_state: Default::default(),
             client_screen_size,
+            options,
+            audit,
+            held_shortcut: None,
+            held_refresh: None,
+            held_keys: Vec::new(),
+            held_characters: Vec::new(),
+            control: None,
+            had_control: false,
+            last_click: None,
         }
     }
 
-    fn apply_modifier_to_event(&self, event: CFRetained<CGEvent>) -> CFRetained<CGEvent> {
+    pub fn with_control(mut self, control: Option<ControlLease>) -> Self {
+        self.control = control;
+        self
+    }
+
+    /// Whether the session only watches while another one has control.
+    /// Keys and buttons still held are released when control was just lost.
+    fn is_viewer(&mut self) -> bool {
+        let viewer = self
+            .control
+            .as_ref()
+            .is_some_and(|control| !control.has_control());
+        if std::mem::replace(&mut self.had_control, !viewer) && viewer {
+            tracing::info!("Input control lost, releasing held input");
+            self.reset();
+        }
+        viewer
+    }
+
+    /// Releases every key and button the client left held and forgets the
+    /// modifier state, so the Mac isn't stuck with e.g. Command down.
+    pub fn reset(&mut self) {
+        let modifiers = std::mem::take(&mut self.modifier_state);
+        let held = [
+            (modifiers.shift, 0x38),
+            (modifiers.control, 0x3B),
+            (modifiers.option, 0x3A),
+            (modifiers.command, 0x37),
+            (modifiers.function, FN_KEYCODE),
+        ];
+        let shortcut = self
+            .held_shortcut
+            .take()
+            .map(|(_, shortcut)| shortcut.keycode);
+        self.held_refresh = None;
+        self.held_keys.clear();
+        for keycode in held
+            .into_iter()
+            .filter_map(|(held, keycode)| held.then_some(keycode))
+            .chain(shortcut)
+        {
+            self.sink.post(SynthEvent::Key {
+                keycode,
+                pressed: false,
+                flags: CGEventFlags(0),
+            });
+        }
+        for (_, code) in std::mem::take(&mut self.held_characters) {
+            self.sink.post(SynthEvent::Unicode {
+                code,
+                pressed: false,
+            });
+        }
+        if let Some(button) = self.down_mouse_button.take() {
+            let clicks = self.last_click.take().map_or(1, |click| click.clicks);
+            self.sink.post(SynthEvent::MouseButton {
+                button,
+                pressed: false,
+                point: self.last_mouse_point,
+                clicks,
+            });
+        }
+    }
+
+    fn audit_keyboard(&self, event: &KeyboardEvent) {
+        use std::fmt::Write;
+
+        let Some(audit) = &self.audit else {
+            return;
+        };
+        match *event {
+            KeyboardEvent::Pressed { code, extended }
+            | KeyboardEvent::Released { code, extended } => {
+                let pressed = matches!(event, KeyboardEvent::Pressed { .. });
+                audit.key(pressed, |record| {
+                    let _ = write!(record, r#","scancode":{code},"extended":{extended}"#);
+                });
+            }
+            KeyboardEvent::UnicodePressed(code) | KeyboardEvent::UnicodeReleased(code) => {
+                let pressed = matches!(event, KeyboardEvent::UnicodePressed(_));
+                audit.key(pressed, |record| {
+                    let _ = write!(record, r#","unicode":{code}"#);
+                });
+            }
+            _ => {}
+        }
+    }
+
+    fn audit_mouse_button(&self, button: &str, pressed: bool) {
+        if let Some(audit) = &self.audit {
+            audit.mouse_button(
+                button,
+                pressed,
+                self.last_mouse_point.x,
+                self.last_mouse_point.y,
+            );
+        }
+    }
+
+    fn modifier_flags(&self) -> CGEventFlags {
         let mut flags = CGEventFlags(0);
         if self.modifier_state.command {
             flags |= CGEventFlags::MaskCommand;
@@ -47,274 +1092,1227 @@ impl InputHandler {
         if self.modifier_state.shift {
             flags |= CGEventFlags::MaskShift;
         }
-        if flags.0 != 0 {
-            unsafe { CGEvent::set_flags(Some(event.as_ref()), flags) };
+        if self.modifier_state.function {
+            flags |= CGEventFlags::MaskSecondaryFn;
+        }
+        if self.modifier_state.caps_lock {
+            flags |= CGEventFlags::MaskAlphaShift;
+        }
+        flags
+    }
+
+    /// Whether pressing `key` with the current modifiers makes `combo`.
+    fn is_combo(&self, combo: Option<KeyCombo>, key: ScanCode) -> bool {
+        let modifiers = &self.modifier_state;
+        combo.is_some_and(|combo| {
+            combo.key == key
+                && (
+                    modifiers.control,
+                    modifiers.shift,
+                    modifiers.option,
+                    modifiers.command,
+                ) == (combo.control, combo.shift, combo.option, combo.command)
+        })
+    }
+
+    /// macOS shortcut which replaces `key` pressed with the current modifiers.
+    fn shortcut_for_key(&self, key: ScanCode) -> Option<Shortcut> {
+        let modifiers = &self.modifier_state;
+        if self.is_combo(self.options.input_source_key, key) {
+            tracing::info!("Switching input source");
+            return Some(self.options.input_source_switch.shortcut());
+        }
+        // Ctrl+Alt+Del, with either the navigation or the keypad Delete
+        if key.code == 83 && modifiers.control && modifiers.option {
+            let shortcut = self.options.secure_attention.shortcut();
+            tracing::info!(action = ?self.options.secure_attention, "Secure attention sequence");
+            return shortcut;
+        }
+        None
+    }
+
+    /// Tracks held keys and tells whether `event` is a press the client
+    /// repeated for a held key which is dropped with `no_autorepeat`.
+    fn is_dropped_repeat(&mut self, event: &KeyboardEvent) -> bool {
+        match *event {
+            KeyboardEvent::Pressed { code, extended } => {
+                let key = ScanCode { code, extended };
+                if !self.held_keys.contains(&key) {
+                    self.held_keys.push(key);
+                    return false;
+                }
+                tracing::trace!(?key, "Key repeated by client");
+                self.options.no_autorepeat
+            }
+            KeyboardEvent::Released { code, extended } => {
+                let key = ScanCode { code, extended };
+                self.held_keys.retain(|held| *held != key);
+                false
+            }
+            _ => false,
         }
-        event
     }
 
-    fn convert_keyboard_event(
+    /// Handles the refresh combo, returning whether the event was consumed.
+    /// The release is consumed as well so the Mac never sees half of it.
+    fn handle_refresh_key(&mut self, event: &KeyboardEvent) -> bool {
+        match *event {
+            KeyboardEvent::Pressed { code, extended } => {
+                let key = ScanCode { code, extended };
+                if !self.is_combo(self.options.refresh_key, key) {
+                    return false;
+                }
+                tracing::info!("Full screen refresh requested by client");
+                self.options.refresh.request();
+                self.held_refresh = Some(key);
+                true
+            }
+            KeyboardEvent::Released { code, extended } => {
+                let key = ScanCode { code, extended };
+                if self.held_refresh != Some(key) {
+                    return false;
+                }
+                self.held_refresh = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Replaces keys bound to a macOS shortcut. The release is matched to the
+    /// press so the shortcut is released even if modifiers changed meanwhile.
+    fn convert_shortcut_key(
         &mut self,
-        event: KeyboardEvent,
-    ) -> anyhow::Result<CFRetained<CGEvent>> {
+        code: u8,
+        extended: bool,
+        pressed: bool,
+    ) -> Option<SynthEvent> {
+        let key = ScanCode { code, extended };
+        let shortcut = if pressed {
+            let shortcut = self.shortcut_for_key(key)?;
+            self.held_shortcut = Some((key, shortcut));
+            shortcut
+        } else {
+            match self.held_shortcut {
+                Some((held, shortcut)) if held == key => {
+                    self.held_shortcut = None;
+                    shortcut
+                }
+                _ => return None,
+            }
+        };
+
+        Some(SynthEvent::Key {
+            keycode: shortcut.keycode,
+            pressed,
+            flags: shortcut.flags,
+        })
+    }
+
+    /// Replaces keys typing a character with the character, with
+    /// `prefer_unicode`. The release is matched to the press like for
+    /// shortcuts.
+    fn convert_character_key(
+        &mut self,
+        code: u8,
+        extended: bool,
+        pressed: bool,
+    ) -> Option<SynthEvent> {
+        let key = ScanCode { code, extended };
+        let code = if pressed {
+            let modifiers = &self.modifier_state;
+            if !self.options.prefer_unicode
+                || modifiers.control
+                || modifiers.option
+                || modifiers.command
+                || modifiers.function
+            {
+                return None;
+            }
+            let character = self.options.keyboard_layout.character(
+                code,
+                extended,
+                modifiers.shift,
+                modifiers.caps_lock,
+            )?;
+            let code = u16::try_from(character as u32).ok()?;
+            // A repeat of a held key replaces it
+            self.held_characters.retain(|(held, _)| *held != key);
+            self.held_characters.push((key, code));
+            code
+        } else {
+            let position = self
+                .held_characters
+                .iter()
+                .position(|(held, _)| *held == key)?;
+            self.held_characters.remove(position).1
+        };
+
+        Some(SynthEvent::Unicode { code, pressed })
+    }
+
+    fn convert_keyboard_event(&mut self, event: KeyboardEvent) -> anyhow::Result<SynthEvent> {
         fn convert_non_unicode_key(
             code: u8,
             extended: bool,
             pressed: bool,
             modifier: &mut Modifiers,
+            fn_key: Option<ScanCode>,
+            remap: &[KeyRemap],
+            layout: KeyboardLayout,
         ) -> Option<u16> {
-            tracing::info!(?code, ?extended, ?pressed, ?modifier);
+            tracing::trace!(?code, ?extended, ?pressed, ?modifier);
+            if fn_key == Some(ScanCode { code, extended }) {
+                modifier.function = pressed;
+                return Some(FN_KEYCODE);
+            }
+            if let Some(key) = ModifierKey::from_scancode(code, extended) {
+                let role = remap
+                    .iter()
+                    .find(|remap| remap.from == key)
+                    .map_or(key.modifier, |remap| remap.to);
+                *match role {
+                    Modifier::Command => &mut modifier.command,
+                    Modifier::Option => &mut modifier.option,
+                    Modifier::Control => &mut modifier.control,
+                    Modifier::Shift => &mut modifier.shift,
+                } = pressed;
+                return Some(role.keycode(key.right));
+            }
             Some(match (code, extended) {
-                // Delete
-                (14, false) => 0x33,
-                // Command
-                (91, true) => {
-                    modifier.command = pressed;
-                    0x37
-                }
-                // Ctrl
-                (29, false) => {
-                    modifier.control = pressed;
-                    0x3B
-                }
-                // Left shift
-                (42, false) => {
-                    modifier.shift = pressed;
-                    0x38
-                }
-                // Left alt/option
-                (56, false) => {
-                    modifier.option = pressed;
-                    0x3A
-                }
-                // Return
-                (28, false) => 0x24,
-                // qwertyuiop
-                (16, false) => 0x0C,
-                (17, false) => 0x0D,
-                (18, false) => 0x0E,
-                (19, false) => 0x0F,
-                (20, false) => 0x11,
-                (21, false) => 0x10,
-                (22, false) => 0x20,
-                (23, false) => 0x22,
-                (24, false) => 0x1F,
-                (25, false) => 0x23,
-                // asdfghjkl;
-                (30, false) => 0x00,
-                (31, false) => 0x01,
-                (32, false) => 0x02,
-                (33, false) => 0x03,
-                (34, false) => 0x05,
-                (35, false) => 0x04,
-                (36, false) => 0x26,
-                (37, false) => 0x28,
-                (38, false) => 0x25,
-                (39, false) => 0x29,
-                // zxcvbnm
-                (44, false) => 0x06,
-                (45, false) => 0x07,
-                (46, false) => 0x08,
-                (47, false) => 0x09,
-                (48, false) => 0x0B,
-                (49, false) => 0x2D,
-                (50, false) => 0x2E,
-                // F1..F12
-                (59, false) => 0x7A,
-                (60, false) => 0x78,
-                (61, false) => 0x63,
-                (62, false) => 0x76,
-                (63, false) => 0x60,
-                (64, false) => 0x61,
-                (65, false) => 0x62,
-                (66, false) => 0x64,
-                (67, false) => 0x65,
-                (68, false) => 0x6D,
-                (87, false) => 0x67,
-                (88, false) => 0x6F,
-                // Tab
-                (15, false) => 0x30,
-                // Arrow(left, up, down, right)
-                (75, true) => 0x7B,
-                (72, true) => 0x7E,
-                (80, true) => 0x7D,
-                (77, true) => 0x7C,
-                // Del
-                (83, true) => 0x75,
-                // Home, End, PgUp, PgDn
-                (71, true) => 0x73,
-                (79, true) => 0x77,
-                (73, true) => 0x74,
-                (81, true) => 0x79,
-                // ESC
-                (1, false) => 0x35,
-                // PrintScr, ScrollLock, Break
-                (55, true) => return None,
-                (70, false) => return None,
-                (69, false) => return None,
-                // 1..0
-                (2, false) => 0x12,
-                (3, false) => 0x13,
-                (4, false) => 0x14,
-                (5, false) => 0x15,
-                (6, false) => 0x16,
-                (7, false) => 0x17,
-                (8, false) => 0x18,
-                (9, false) => 0x19,
-                (10, false) => 0x1A,
-                (11, false) => 0x1B,
-                _ => {
-                    tracing::info!(?code, ?extended);
-                    code as _
+                // Caps lock, the client sends a release for every press
+                (58, false) => {
+                    if pressed {
+                        modifier.caps_lock = !modifier.caps_lock;
+                    }
+                    0x39
                 }
+                _ => return layout.keycode(code, extended),
             })
         }
 
-        match event {
-            KeyboardEvent::Pressed { code, extended } => {
-                let code = convert_non_unicode_key(code, extended, true, &mut self.modifier_state)
-                    .with_context(|| format!("Unknown code - {code}, {extended}"))?;
-                unsafe { CGEvent::new_keyboard_event(None, code, true) }
-                    .map(|event| self.apply_modifier_to_event(event))
+        if let KeyboardEvent::Pressed { code, extended }
+        | KeyboardEvent::Released { code, extended } = event
+        {
+            let pressed = matches!(event, KeyboardEvent::Pressed { .. });
+            if let Some(event) = self.convert_shortcut_key(code, extended, pressed) {
+                return Ok(event);
             }
-            .ok_or_else(|| anyhow::anyhow!("Failed to convert keyboard pressed event")),
-            KeyboardEvent::Released { code, extended } => {
-                let code = convert_non_unicode_key(code, extended, false, &mut self.modifier_state)
-                    .with_context(|| format!("Unknown code - {code}, {extended}"))?;
-                (unsafe { CGEvent::new_keyboard_event(None, code, false) })
-                    .map(|event| self.apply_modifier_to_event(event))
-                    .ok_or_else(|| anyhow::anyhow!("Failed to convert keyboard pressed event"))
+            if let Some(event) = self.convert_character_key(code, extended, pressed) {
+                return Ok(event);
             }
-            KeyboardEvent::UnicodePressed(code) => {
-                let event =
-                    unsafe { CGEvent::new_keyboard_event(None, 0, true) }.ok_or_else(|| {
-                        anyhow::anyhow!("Failed to convert keyboard event - {event:?}")
-                    })?;
-                unsafe { CGEvent::keyboard_set_unicode_string(Some(event.as_ref()), 1, &code) };
-                Ok(event)
+        }
+
+        match event {
+            KeyboardEvent::Pressed { code, extended }
+            | KeyboardEvent::Released { code, extended } => {
+                let pressed = matches!(event, KeyboardEvent::Pressed { .. });
+                let keycode = convert_non_unicode_key(
+                    code,
+                    extended,
+                    pressed,
+                    &mut self.modifier_state,
+                    self.options.fn_key,
+                    &self.options.remap,
+                    self.options.keyboard_layout,
+                )
+                .with_context(|| format!("Unknown code - {code}, {extended}"))?;
+                Ok(SynthEvent::Key {
+                    keycode,
+                    pressed,
+                    flags: self.modifier_flags(),
+                })
             }
-            KeyboardEvent::UnicodeReleased(code) => {
-                let event =
-                    unsafe { CGEvent::new_keyboard_event(None, 0, false) }.ok_or_else(|| {
-                        anyhow::anyhow!("Failed to convert keyboard event - {event:?}")
-                    })?;
-                unsafe { CGEvent::keyboard_set_unicode_string(Some(event.as_ref()), 1, &code) };
-                Ok(event)
+            KeyboardEvent::UnicodePressed(code) | KeyboardEvent::UnicodeReleased(code) => {
+                Ok(SynthEvent::Unicode {
+                    code,
+                    pressed: matches!(event, KeyboardEvent::UnicodePressed(_)),
+                })
             }
             _ => Err(anyhow::anyhow!("Unhandled event - {event:?}")),
         }
     }
-}
 
-impl RdpServerInputHandler for InputHandler {
-    fn keyboard(&mut self, event: KeyboardEvent) {
-        let Ok(event) = self
-            .convert_keyboard_event(event)
-            .map_err(|e| tracing::error!(?e))
-        else {
-            return;
+    /// Click count of a button event. macOS only sees a double-click when the
+    /// second press says so, which clients don't send.
+    fn count_clicks(&mut self, button: CGMouseButton, pressed: bool, point: CGPoint) -> i64 {
+        let last = self.last_click.filter(|last| last.button == button);
+        if !pressed {
+            return last.map_or(1, |last| last.clicks);
+        }
+        let now = Instant::now();
+        // As set in System Settings, so it is read on every press
+        let interval = Duration::from_secs_f64(unsafe { NSEvent::doubleClickInterval() });
+        let clicks = match last {
+            Some(last)
+                if now.duration_since(last.time) <= interval
+                    && (point.x - last.point.x).hypot(point.y - last.point.y)
+                        <= DOUBLE_CLICK_DISTANCE =>
+            {
+                last.clicks + 1
+            }
+            _ => 1,
         };
-        unsafe { CGEvent::post(CGEventTapLocation::SessionEventTap, Some(&event)) };
+        self.last_click = Some(Click {
+            button,
+            time: now,
+            point,
+            clicks,
+        });
+        clicks
     }
 
-    fn mouse(&mut self, event: MouseEvent) {
-        use objc2_core_graphics::{CGDisplayMoveCursorToPoint, CGEventType};
-        let event = match event {
-            MouseEvent::LeftPressed => {
-                self.down_mouse_button = Some(CGMouseButton::Left);
-                unsafe {
-                    CGEvent::new_mouse_event(
-                        None,
-                        CGEventType::LeftMouseDown,
-                        self.last_mouse_point,
-                        CGMouseButton::Left,
-                    )
-                }
-            }
-            MouseEvent::LeftReleased => {
-                self.down_mouse_button = None;
-                unsafe {
-                    CGEvent::new_mouse_event(
-                        None,
-                        CGEventType::LeftMouseUp,
-                        self.last_mouse_point,
-                        CGMouseButton::Left,
-                    )
-                }
-            }
-            MouseEvent::RightPressed => {
-                self.down_mouse_button = Some(CGMouseButton::Right);
-                unsafe {
-                    CGEvent::new_mouse_event(
-                        None,
-                        CGEventType::RightMouseDown,
-                        self.last_mouse_point,
-                        CGMouseButton::Right,
-                    )
-                }
-            }
-            MouseEvent::RightReleased => {
-                self.down_mouse_button = None;
-                unsafe {
-                    CGEvent::new_mouse_event(
-                        None,
-                        CGEventType::RightMouseUp,
-                        self.last_mouse_point,
-                        CGMouseButton::Right,
-                    )
-                }
-            }
+    /// Converts a client mouse event, `None` when there is nothing to post.
+    fn convert_mouse_event(&mut self, event: MouseEvent) -> Option<SynthEvent> {
+        let (button, pressed) = match event {
+            MouseEvent::LeftPressed => (CGMouseButton::Left, true),
+            MouseEvent::LeftReleased => (CGMouseButton::Left, false),
+            MouseEvent::RightPressed => (CGMouseButton::Right, true),
+            MouseEvent::RightReleased => (CGMouseButton::Right, false),
+            MouseEvent::MiddlePressed => (CGMouseButton::Center, true),
+            MouseEvent::MiddleReleased => (CGMouseButton::Center, false),
+            MouseEvent::X1Pressed => (X1_BUTTON, true),
+            MouseEvent::X1Released => (X1_BUTTON, false),
+            MouseEvent::X2Pressed => (X2_BUTTON, true),
+            MouseEvent::X2Released => (X2_BUTTON, false),
             MouseEvent::Move { x, y } => {
-                let screen_size = *self.client_screen_size.borrow_and_update();
-                self.last_mouse_point.x =
-                    (x as u32 * screen_size.server.0 as u32) as f64 / screen_size.client.0 as f64;
-                self.last_mouse_point.y =
-                    (y as u32 * screen_size.server.1 as u32) as f64 / screen_size.client.1 as f64;
-
-                if let Some(down_button) = &self.down_mouse_button {
-                    let down_button = *down_button;
-                    unsafe {
-                        CGEvent::new_mouse_event(
-                            None,
-                            match down_button {
-                                CGMouseButton::Left => CGEventType::LeftMouseDragged,
-                                CGMouseButton::Center => CGEventType::OtherMouseDragged,
-                                CGMouseButton::Right => CGEventType::RightMouseDragged,
-                                _ => {
-                                    unreachable!("Unavailable case");
-                                }
-                            },
-                            self.last_mouse_point,
-                            down_button,
-                        )
+                let point = {
+                    let screen_size = self.client_screen_size.borrow_and_update();
+                    let (client_width, client_height) = screen_size.client;
+                    if client_width == 0 || client_height == 0 {
+                        tracing::debug!(?x, ?y, "Client size unknown, ignoring mouse move");
+                        return None;
                     }
-                } else {
-                    let err = unsafe { CGDisplayMoveCursorToPoint(0, self.last_mouse_point) };
-                    if err.0 != 0 {
-                        tracing::error!("[CGDisplayMoveCursorToPoint] error - {}", err.0);
+                    // Clients may report positions on or past their edge
+                    // while they resize
+                    let (canvas_width, canvas_height) = screen_size.layout_pixel_size();
+                    let canvas_x = scale_coord(x, client_width, canvas_width)
+                        .clamp(0.0, (canvas_width - 1.0).max(0.0));
+                    let canvas_y = scale_coord(y, client_height, canvas_height)
+                        .clamp(0.0, (canvas_height - 1.0).max(0.0));
+                    canvas_to_global(&screen_size.layout, canvas_x, canvas_y)
+                };
+                let Some(point) = point else {
+                    tracing::debug!(?x, ?y, "Mouse position outside of the captured sources");
+                    return None;
+                };
+                let last = std::mem::replace(&mut self.last_mouse_point, point);
+
+                return Some(match (self.options.pointer_mode, self.down_mouse_button) {
+                    (PointerMode::Relative, button) => SynthEvent::MouseDelta {
+                        button,
+                        point,
+                        delta: (
+                            (point.x - last.x).round() as i64,
+                            (point.y - last.y).round() as i64,
+                        ),
+                    },
+                    (PointerMode::Absolute, Some(button)) => {
+                        SynthEvent::MouseDrag { button, point }
                     }
-                    return;
-                }
+                    (PointerMode::Absolute, None) => SynthEvent::MouseWarp(point),
+                });
+            }
+            MouseEvent::VerticalScroll { value } => {
+                let (value, lines) = scroll_delta(value as i32, self.options.scroll_unit);
+                return Some(SynthEvent::VerticalScroll {
+                    value: scale_scroll(value, self.options.scroll_speed),
+                    lines,
+                });
+            }
+            // RDP scrolls right for positive values, CoreGraphics scrolls left.
+            MouseEvent::HorizontalScroll { value } => {
+                let (value, lines) = scroll_delta(-(value as i32), self.options.scroll_unit);
+                return Some(SynthEvent::HorizontalScroll {
+                    value: scale_scroll(value, self.options.scroll_speed),
+                    lines,
+                });
             }
-            MouseEvent::VerticalScroll { value } => unsafe {
-                CGEvent::new_scroll_wheel_event2(
-                    None,
-                    CGScrollEventUnit::Pixel,
-                    1,
-                    value as _,
-                    0,
-                    0,
-                )
-            },
             _ => {
                 tracing::info!("Unknown mouse event {event:?}");
-                return;
+                return None;
             }
         };
-        let Some(event) = event else {
-            tracing::error!("Failed to create mouse event");
+
+        self.audit_mouse_button(
+            match button {
+                CGMouseButton::Left => "left",
+                CGMouseButton::Right => "right",
+                X1_BUTTON => "x1",
+                X2_BUTTON => "x2",
+                _ => "middle",
+            },
+            pressed,
+        );
+        self.down_mouse_button = pressed.then_some(button);
+        let point = self.last_mouse_point;
+        let clicks = self.count_clicks(button, pressed, point);
+        Some(SynthEvent::MouseButton {
+            button,
+            pressed,
+            point,
+            clicks,
+        })
+    }
+}
+
+/// Runs when the session ends and the server drops its input handler.
+impl<S: EventSink> Drop for InputHandler<S> {
+    fn drop(&mut self) {
+        self.reset();
+    }
+}
+
+impl<S: EventSink + Send + 'static> RdpServerInputHandler for InputHandler<S> {
+    fn keyboard(&mut self, event: KeyboardEvent) {
+        if self.is_viewer() {
+            return;
+        }
+        self.audit_keyboard(&event);
+        if let Some(activity) = &self.options.activity {
+            activity.touch();
+        }
+        if self.is_dropped_repeat(&event) {
+            return;
+        }
+        if self.handle_refresh_key(&event) {
+            return;
+        }
+        match self.convert_keyboard_event(event) {
+            Ok(event) => self.sink.post(event),
+            Err(e) => tracing::error!(?e),
+        }
+    }
+
+    fn mouse(&mut self, event: MouseEvent) {
+        if self.is_viewer() {
             return;
+        }
+        if let Some(activity) = &self.options.activity {
+            activity.touch();
+        }
+        if let Some(event) = self.convert_mouse_event(event) {
+            self.sink.post(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::multi_client::{InputControl, MultiClientPolicy};
+
+    #[test]
+    fn synthesized_counts_and_drops_failed_events() {
+        let failures = FailureCounter::default();
+
+        assert_eq!(synthesized(None::<()>, &failures, "left press"), None);
+        assert_eq!(synthesized(None::<()>, &failures, "key 0x1e"), None);
+        assert_eq!(failures.get(), 2);
+    }
+
+    #[test]
+    fn synthesized_passes_created_events_through() {
+        let failures = FailureCounter::default();
+
+        assert_eq!(synthesized(Some(7), &failures, "left press"), Some(7));
+        assert_eq!(failures.get(), 0);
+    }
+
+    #[test]
+    fn scale_scroll_saturates() {
+        assert_eq!(scale_scroll(120, None), 120);
+        assert_eq!(scale_scroll(120, Some(0.5)), 60);
+        assert_eq!(scale_scroll(-120, Some(1.5)), -180);
+        assert_eq!(scale_scroll(i16::MAX as i32, Some(1e9)), i32::MAX);
+        assert_eq!(scale_scroll(i16::MIN as i32, Some(1e9)), i32::MIN);
+    }
+
+    #[test]
+    fn scroll_delta_converts_notches_to_lines() {
+        assert_eq!(scroll_delta(240, ScrollUnit::Pixel), (240, false));
+        assert_eq!(scroll_delta(-240, ScrollUnit::Line), (-2, true));
+        assert_eq!(scroll_delta(30, ScrollUnit::Line), (1, true));
+        assert_eq!(scroll_delta(-120, ScrollUnit::Auto), (-1, true));
+        assert_eq!(scroll_delta(30, ScrollUnit::Auto), (30, false));
+    }
+
+    #[test]
+    fn scale_coord_scales_to_server_dimension() {
+        assert_eq!(scale_coord(640, 1280, 2560.0), 1280.0);
+        assert_eq!(scale_coord(100, 1000, 1500.0), 150.0);
+        assert_eq!(scale_coord(1000, 2000, 1000.0), 500.0);
+    }
+
+    #[test]
+    fn scale_coord_is_identity_for_equal_dimensions() {
+        for pos in [0, 1, 959, 1919] {
+            assert_eq!(scale_coord(pos, 1920, 1920.0), pos as f64);
+        }
+    }
+
+    #[test]
+    fn scale_coord_falls_back_for_zero_client_dimension() {
+        assert_eq!(scale_coord(0, 0, 1920.0), 0.0);
+        assert_eq!(scale_coord(300, 0, 1920.0), 300.0);
+    }
+
+    #[test]
+    fn scale_coord_handles_max_values() {
+        assert_eq!(
+            scale_coord(u16::MAX, u16::MAX, u16::MAX as f64),
+            u16::MAX as f64
+        );
+        assert_eq!(
+            scale_coord(u16::MAX, 1, u16::MAX as f64),
+            u16::MAX as f64 * u16::MAX as f64
+        );
+        assert_eq!(scale_coord(u16::MAX, u16::MAX, 8192.0), 8192.0);
+    }
+
+    #[test]
+    fn canvas_to_global_uses_each_display_scale_factor() {
+        let displays = [
+            // Retina built-in display
+            DisplayGeometry {
+                origin: (0.0, 0.0),
+                size: (1440.0, 900.0),
+                scale_factor: 2.0,
+            },
+            // Regular external display to the right
+            DisplayGeometry {
+                origin: (1440.0, 0.0),
+                size: (1920.0, 1080.0),
+                scale_factor: 1.0,
+            },
+        ];
+
+        let point = canvas_to_global(&displays, 1440.0, 900.0).unwrap();
+        assert_eq!((point.x, point.y), (720.0, 450.0));
+
+        let point = canvas_to_global(&displays, 3880.0, 540.0).unwrap();
+        assert_eq!((point.x, point.y), (2440.0, 540.0));
+
+        assert!(canvas_to_global(&displays, 4800.0, 0.0).is_none());
+        assert!(canvas_to_global(&displays, 3000.0, 1080.0).is_none());
+    }
+
+    impl EventSink for Vec<SynthEvent> {
+        fn post(&mut self, event: SynthEvent) {
+            self.push(event);
+        }
+    }
+
+    /// A single Retina display, 960x540 points, shown to a 1920x1080 client.
+    fn screen_size() -> (watch::Sender<ScreenSize>, watch::Receiver<ScreenSize>) {
+        watch::channel(ScreenSize {
+            client: (1920, 1080),
+            server: (960, 540),
+            capture: (1920, 1080),
+            layout: Arc::from([DisplayGeometry {
+                origin: (0.0, 0.0),
+                size: (960.0, 540.0),
+                scale_factor: 2.0,
+            }]),
+        })
+    }
+
+    fn handler(
+        screen_size: watch::Receiver<ScreenSize>,
+        options: InputOptions,
+    ) -> InputHandler<Vec<SynthEvent>> {
+        InputHandler::with_sink(screen_size, options, None, Vec::new())
+    }
+
+    fn key(code: u8, extended: bool, pressed: bool) -> KeyboardEvent {
+        if pressed {
+            KeyboardEvent::Pressed { code, extended }
+        } else {
+            KeyboardEvent::Released { code, extended }
+        }
+    }
+
+    #[test]
+    fn losing_control_releases_held_input() {
+        let (_screen_size, receiver) = screen_size();
+        let control = InputControl::new(MultiClientPolicy::PrimaryInput);
+        let mut handler =
+            handler(receiver, InputOptions::default()).with_control(Some(control.join(1)));
+
+        handler.keyboard(key(56, false, true));
+        handler.sink.clear();
+        let _other = control.join(2);
+        handler.keyboard(key(30, false, true));
+        handler.keyboard(key(30, false, false));
+
+        assert_eq!(
+            handler.sink,
+            [SynthEvent::Key {
+                keycode: 0x3A,
+                pressed: false,
+                flags: CGEventFlags(0),
+            }]
+        );
+    }
+
+    #[test]
+    fn keys_carry_held_modifiers() {
+        let (_screen_size, receiver) = screen_size();
+        let mut handler = handler(receiver, InputOptions::default());
+
+        handler.keyboard(key(29, false, true));
+        handler.keyboard(key(30, false, true));
+        handler.keyboard(key(30, false, false));
+        handler.keyboard(key(29, false, false));
+
+        assert_eq!(
+            handler.sink,
+            [
+                SynthEvent::Key {
+                    keycode: 0x3B,
+                    pressed: true,
+                    flags: CGEventFlags::MaskControl,
+                },
+                SynthEvent::Key {
+                    keycode: 0x00,
+                    pressed: true,
+                    flags: CGEventFlags::MaskControl,
+                },
+                SynthEvent::Key {
+                    keycode: 0x00,
+                    pressed: false,
+                    flags: CGEventFlags::MaskControl,
+                },
+                SynthEvent::Key {
+                    keycode: 0x3B,
+                    pressed: false,
+                    flags: CGEventFlags(0),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn fn_key_sets_secondary_fn_flag() {
+        let (_screen_size, receiver) = screen_size();
+        let fn_key = ScanCode {
+            code: 93,
+            extended: true,
+        };
+        let mut handler = handler(
+            receiver,
+            InputOptions {
+                fn_key: Some(fn_key),
+                ..Default::default()
+            },
+        );
+
+        handler.keyboard(key(93, true, true));
+        handler.keyboard(key(14, false, true));
+
+        assert_eq!(
+            handler.sink,
+            [
+                SynthEvent::Key {
+                    keycode: FN_KEYCODE,
+                    pressed: true,
+                    flags: CGEventFlags::MaskSecondaryFn,
+                },
+                SynthEvent::Key {
+                    keycode: 0x33,
+                    pressed: true,
+                    flags: CGEventFlags::MaskSecondaryFn,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn secure_attention_accepts_cad_action_names() {
+        assert_eq!("forcequit".parse(), Ok(SecureAttentionAction::ForceQuit));
+        assert_eq!("Force-Quit".parse(), Ok(SecureAttentionAction::ForceQuit));
+        assert_eq!("ignore".parse(), Ok(SecureAttentionAction::None));
+    }
+
+    #[test]
+    fn ctrl_alt_del_posts_secure_attention_shortcut() {
+        let (_screen_size, receiver) = screen_size();
+        let mut handler = handler(receiver, InputOptions::default());
+
+        handler.keyboard(key(29, false, true));
+        handler.keyboard(key(56, false, true));
+        handler.sink.clear();
+        handler.keyboard(key(83, true, true));
+        // Releasing a modifier first must not leave the shortcut held.
+        handler.keyboard(key(56, false, false));
+        handler.keyboard(key(83, true, false));
+
+        let flags = CGEventFlags::MaskCommand | CGEventFlags::MaskAlternate;
+        assert_eq!(
+            handler.sink,
+            [
+                SynthEvent::Key {
+                    keycode: ESCAPE_KEYCODE,
+                    pressed: true,
+                    flags,
+                },
+                SynthEvent::Key {
+                    keycode: 0x3A,
+                    pressed: false,
+                    flags: CGEventFlags::MaskControl,
+                },
+                SynthEvent::Key {
+                    keycode: ESCAPE_KEYCODE,
+                    pressed: false,
+                    flags,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn input_source_key_posts_switch_shortcut() {
+        let (_screen_size, receiver) = screen_size();
+        let mut handler = handler(
+            receiver,
+            InputOptions {
+                input_source_key: Some("0xe01d".parse().unwrap()),
+                input_source_switch: InputSourceSwitch::CtrlOptionSpace,
+                ..Default::default()
+            },
+        );
+
+        handler.keyboard(key(29, true, true));
+
+        assert_eq!(
+            handler.sink,
+            [SynthEvent::Key {
+                keycode: SPACE_KEYCODE,
+                pressed: true,
+                flags: CGEventFlags::MaskControl | CGEventFlags::MaskAlternate,
+            }]
+        );
+    }
+
+    #[test]
+    fn refresh_key_requests_refresh_without_posting() {
+        let (_screen_size, receiver) = screen_size();
+        let refresh = RefreshRequest::default();
+        let mut handler = handler(
+            receiver,
+            InputOptions {
+                refresh_key: Some("ctrl+alt+0x13".parse().unwrap()),
+                refresh: refresh.clone(),
+                ..Default::default()
+            },
+        );
+
+        handler.keyboard(key(29, false, true));
+        handler.keyboard(key(56, false, true));
+        handler.sink.clear();
+        handler.keyboard(key(19, false, true));
+        handler.keyboard(key(19, false, false));
+
+        assert!(handler.sink.is_empty());
+        assert!(refresh.take(&mut 0));
+    }
+
+    #[test]
+    fn unicode_keys_are_posted_as_is() {
+        let (_screen_size, receiver) = screen_size();
+        let mut handler = handler(receiver, InputOptions::default());
+
+        handler.keyboard(KeyboardEvent::UnicodePressed(0xAC00));
+        handler.keyboard(KeyboardEvent::UnicodeReleased(0xAC00));
+
+        assert_eq!(
+            handler.sink,
+            [
+                SynthEvent::Unicode {
+                    code: 0xAC00,
+                    pressed: true,
+                },
+                SynthEvent::Unicode {
+                    code: 0xAC00,
+                    pressed: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn mouse_moves_warp_and_drag_with_a_button_held() {
+        let (_screen_size, receiver) = screen_size();
+        let mut handler = handler(receiver, InputOptions::default());
+
+        handler.mouse(MouseEvent::Move { x: 960, y: 540 });
+        handler.mouse(MouseEvent::LeftPressed);
+        handler.mouse(MouseEvent::Move { x: 0, y: 0 });
+        handler.mouse(MouseEvent::LeftReleased);
+
+        let center = CGPoint { x: 480.0, y: 270.0 };
+        let origin = CGPoint { x: 0.0, y: 0.0 };
+        assert_eq!(
+            handler.sink,
+            [
+                SynthEvent::MouseWarp(center),
+                SynthEvent::MouseButton {
+                    button: CGMouseButton::Left,
+                    pressed: true,
+                    point: center,
+                    clicks: 1,
+                },
+                SynthEvent::MouseDrag {
+                    button: CGMouseButton::Left,
+                    point: origin,
+                },
+                SynthEvent::MouseButton {
+                    button: CGMouseButton::Left,
+                    pressed: false,
+                    point: origin,
+                    clicks: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn mouse_follows_client_resize() {
+        let (screen_size, receiver) = screen_size();
+        let mut handler = handler(receiver, InputOptions::default());
+
+        screen_size.send_modify(|size| size.client = (960, 540));
+        handler.mouse(MouseEvent::Move { x: 480, y: 270 });
+
+        assert_eq!(
+            handler.sink,
+            [SynthEvent::MouseWarp(CGPoint { x: 480.0, y: 270.0 })]
+        );
+    }
+
+    #[test]
+    fn mouse_is_clamped_to_the_screen() {
+        let (screen_size, receiver) = screen_size();
+        let mut handler = handler(receiver, InputOptions::default());
+
+        handler.mouse(MouseEvent::Move { x: 5000, y: 1080 });
+        screen_size.send_modify(|size| size.client = (0, 0));
+        handler.mouse(MouseEvent::Move { x: 10, y: 10 });
+
+        assert_eq!(
+            handler.sink,
+            [SynthEvent::MouseWarp(CGPoint { x: 959.5, y: 539.5 })]
+        );
+    }
+
+    #[test]
+    fn punctuation_keys_map_to_us_keycodes() {
+        let (_screen_size, receiver) = screen_size();
+        let mut handler = handler(receiver, InputOptions::default());
+
+        let codes = [
+            2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 26, 27, 43, 39, 40, 41, 51, 52, 53,
+        ];
+        for code in codes {
+            handler.keyboard(key(code, false, true));
+        }
+
+        let keycodes: Vec<_> = handler
+            .sink
+            .iter()
+            .map(|event| match event {
+                SynthEvent::Key { keycode, .. } => *keycode,
+                event => panic!("unexpected {event:?}"),
+            })
+            .collect();
+        assert_eq!(
+            keycodes,
+            [
+                0x12, 0x13, 0x14, 0x15, 0x17, 0x16, 0x1A, 0x1C, 0x19, 0x1D, 0x1B, 0x18, 0x21, 0x1E,
+                0x2A, 0x29, 0x27, 0x32, 0x2B, 0x2F, 0x2C
+            ]
+        );
+        let distinct: std::collections::HashSet<_> = keycodes.iter().collect();
+        assert_eq!(distinct.len(), codes.len());
+    }
+
+    #[test]
+    fn iso_layouts_swap_the_extra_key() {
+        let keycodes = |keyboard_layout| {
+            let (_screen_size, receiver) = screen_size();
+            let mut handler = handler(
+                receiver,
+                InputOptions {
+                    keyboard_layout,
+                    ..Default::default()
+                },
+            );
+            handler.keyboard(key(41, false, true));
+            handler.keyboard(key(86, false, true));
+            handler.keyboard(key(16, false, true));
+            handler
+                .sink
+                .iter()
+                .map(|event| match event {
+                    SynthEvent::Key { keycode, .. } => *keycode,
+                    event => panic!("unexpected {event:?}"),
+                })
+                .collect::<Vec<_>>()
         };
-        unsafe { CGEvent::post(CGEventTapLocation::SessionEventTap, Some(&event)) };
+        assert_eq!(keycodes(KeyboardLayout::Us), [0x32, 0x0A, 0x0C]);
+        assert_eq!(keycodes(KeyboardLayout::German), [0x0A, 0x32, 0x0C]);
+        assert_eq!("FR".parse(), Ok(KeyboardLayout::French));
+    }
+
+    #[test]
+    fn layouts_cover_every_character_key() {
+        for layout in [
+            KeyboardLayout::Us,
+            KeyboardLayout::Uk,
+            KeyboardLayout::German,
+            KeyboardLayout::French,
+        ] {
+            for keys in layout.characters() {
+                assert_eq!(keys.chars().count(), CHARACTER_KEYS.len(), "{layout:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn preferred_unicode_types_layout_characters() {
+        let (_screen_size, receiver) = screen_size();
+        let mut handler = handler(
+            receiver,
+            InputOptions {
+                keyboard_layout: KeyboardLayout::German,
+                prefer_unicode: true,
+                ..Default::default()
+            },
+        );
+
+        // z on QWERTZ, then shift+2
+        handler.keyboard(key(21, false, true));
+        handler.keyboard(key(21, false, false));
+        handler.keyboard(key(42, false, true));
+        handler.keyboard(key(3, false, true));
+        handler.keyboard(key(3, false, false));
+        handler.keyboard(key(42, false, false));
+        // Ctrl+z goes by position for the shortcut
+        handler.keyboard(key(29, false, true));
+        handler.keyboard(key(21, false, true));
+
+        assert_eq!(
+            handler.sink[..4],
+            [
+                SynthEvent::Unicode {
+                    code: 'z' as u16,
+                    pressed: true,
+                },
+                SynthEvent::Unicode {
+                    code: 'z' as u16,
+                    pressed: false,
+                },
+                SynthEvent::Key {
+                    keycode: 0x38,
+                    pressed: true,
+                    flags: CGEventFlags::MaskShift,
+                },
+                SynthEvent::Unicode {
+                    code: '"' as u16,
+                    pressed: true,
+                },
+            ]
+        );
+        assert_eq!(
+            handler.sink.last(),
+            Some(&SynthEvent::Key {
+                keycode: 0x10,
+                pressed: true,
+                flags: CGEventFlags::MaskControl,
+            })
+        );
+    }
+
+    #[test]
+    fn repeated_presses_are_dropped_without_autorepeat() {
+        let (_screen_size, receiver) = screen_size();
+        let mut handler = handler(
+            receiver,
+            InputOptions {
+                no_autorepeat: true,
+                ..Default::default()
+            },
+        );
+
+        for _ in 0..3 {
+            handler.keyboard(key(14, false, true));
+        }
+        handler.keyboard(key(14, false, false));
+        handler.keyboard(key(14, false, true));
+
+        let pressed = handler.sink.iter().map(|event| match event {
+            SynthEvent::Key { pressed, .. } => *pressed,
+            event => panic!("unexpected {event:?}"),
+        });
+        assert!(pressed.eq([true, false, true]));
+    }
+
+    #[test]
+    fn remapped_modifiers_keep_their_side() {
+        assert_eq!(
+            "LWIN=CTRL".parse(),
+            Ok(KeyRemap {
+                from: ModifierKey {
+                    modifier: Modifier::Command,
+                    right: false,
+                },
+                to: Modifier::Control,
+            })
+        );
+        assert!("WIN=CTRL".parse::<KeyRemap>().is_err());
+
+        let (_screen_size, receiver) = screen_size();
+        let mut handler = handler(
+            receiver,
+            InputOptions {
+                remap: vec!["LWIN=CTRL".parse().unwrap(), "RALT=CMD".parse().unwrap()],
+                ..Default::default()
+            },
+        );
+        handler.keyboard(key(91, true, true));
+        handler.keyboard(key(56, true, true));
+
+        assert_eq!(
+            handler.sink,
+            [
+                SynthEvent::Key {
+                    keycode: 0x3B,
+                    pressed: true,
+                    flags: CGEventFlags::MaskControl,
+                },
+                SynthEvent::Key {
+                    keycode: 0x36,
+                    pressed: true,
+                    flags: CGEventFlags::MaskControl | CGEventFlags::MaskCommand,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn quick_clicks_count_up() {
+        let (_screen_size, receiver) = screen_size();
+        let mut handler = handler(receiver, InputOptions::default());
+
+        for _ in 0..2 {
+            handler.mouse(MouseEvent::LeftPressed);
+            handler.mouse(MouseEvent::LeftReleased);
+        }
+        handler.mouse(MouseEvent::Move { x: 500, y: 500 });
+        handler.mouse(MouseEvent::LeftPressed);
+
+        let clicks = handler.sink.iter().filter_map(|event| match event {
+            SynthEvent::MouseButton { clicks, .. } => Some(*clicks),
+            _ => None,
+        });
+        assert!(clicks.eq([1, 1, 2, 2, 1]));
+    }
+
+    #[test]
+    fn right_modifiers_use_right_keycodes() {
+        let (_screen_size, receiver) = screen_size();
+        let mut handler = handler(receiver, InputOptions::default());
+
+        handler.keyboard(key(54, false, true));
+        handler.keyboard(key(29, true, true));
+        handler.keyboard(key(56, true, true));
+        handler.keyboard(key(92, true, true));
+
+        assert_eq!(
+            handler.sink.last(),
+            Some(&SynthEvent::Key {
+                keycode: 0x36,
+                pressed: true,
+                flags: CGEventFlags(
+                    CGEventFlags::MaskShift.0
+                        | CGEventFlags::MaskControl.0
+                        | CGEventFlags::MaskAlternate.0
+                        | CGEventFlags::MaskCommand.0
+                ),
+            })
+        );
+        let keycodes = handler.sink.iter().map(|event| match event {
+            SynthEvent::Key { keycode, .. } => *keycode,
+            event => panic!("unexpected {event:?}"),
+        });
+        assert!(keycodes.eq([0x3C, 0x3E, 0x3D, 0x36]));
+    }
+
+    #[test]
+    fn caps_lock_toggles_on_press() {
+        let (_screen_size, receiver) = screen_size();
+        let mut handler = handler(receiver, InputOptions::default());
+
+        for _ in 0..2 {
+            handler.keyboard(key(58, false, true));
+            handler.keyboard(key(58, false, false));
+            handler.keyboard(key(30, false, true));
+        }
+
+        let flags = handler.sink.iter().map(|event| match event {
+            SynthEvent::Key { flags, .. } => *flags,
+            event => panic!("unexpected {event:?}"),
+        });
+        let caps = CGEventFlags::MaskAlphaShift;
+        let none = CGEventFlags(0);
+        assert!(flags.eq([caps, caps, caps, none, none, none]));
+    }
+
+    #[test]
+    fn horizontal_scroll_flips_sign() {
+        let (_screen_size, receiver) = screen_size();
+        let mut handler = handler(receiver, InputOptions::default());
+
+        handler.mouse(MouseEvent::HorizontalScroll { value: 120 });
+        handler.mouse(MouseEvent::VerticalScroll { value: 120 });
+
+        assert_eq!(
+            handler.sink,
+            [
+                SynthEvent::HorizontalScroll {
+                    value: -120,
+                    lines: false,
+                },
+                SynthEvent::VerticalScroll {
+                    value: 120,
+                    lines: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn middle_button_drags() {
+        let (_screen_size, receiver) = screen_size();
+        let mut handler = handler(receiver, InputOptions::default());
+
+        handler.mouse(MouseEvent::MiddlePressed);
+        handler.mouse(MouseEvent::Move { x: 200, y: 100 });
+        handler.mouse(MouseEvent::MiddleReleased);
+
+        let point = CGPoint { x: 100.0, y: 50.0 };
+        assert_eq!(
+            handler.sink,
+            [
+                SynthEvent::MouseButton {
+                    button: CGMouseButton::Center,
+                    pressed: true,
+                    point: CGPoint { x: 0.0, y: 0.0 },
+                    clicks: 1,
+                },
+                SynthEvent::MouseDrag {
+                    button: CGMouseButton::Center,
+                    point,
+                },
+                SynthEvent::MouseButton {
+                    button: CGMouseButton::Center,
+                    pressed: false,
+                    point,
+                    clicks: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reset_releases_held_modifiers_and_buttons() {
+        let (_screen_size, receiver) = screen_size();
+        let mut handler = handler(receiver, InputOptions::default());
+
+        handler.keyboard(key(42, false, true));
+        handler.keyboard(key(91, true, true));
+        handler.mouse(MouseEvent::LeftPressed);
+        handler.sink.clear();
+        handler.reset();
+
+        let origin = CGPoint { x: 0.0, y: 0.0 };
+        assert_eq!(
+            handler.sink,
+            [
+                SynthEvent::Key {
+                    keycode: 0x38,
+                    pressed: false,
+                    flags: CGEventFlags(0),
+                },
+                SynthEvent::Key {
+                    keycode: 0x37,
+                    pressed: false,
+                    flags: CGEventFlags(0),
+                },
+                SynthEvent::MouseButton {
+                    button: CGMouseButton::Left,
+                    pressed: false,
+                    point: origin,
+                    clicks: 1,
+                },
+            ]
+        );
+
+        handler.sink.clear();
+        handler.reset();
+        assert!(handler.sink.is_empty());
+    }
+
+    #[test]
+    fn x_buttons_are_other_buttons_3_and_4() {
+        let (_screen_size, receiver) = screen_size();
+        let mut handler = handler(receiver, InputOptions::default());
+
+        handler.mouse(MouseEvent::X1Pressed);
+        handler.mouse(MouseEvent::X1Released);
+        handler.mouse(MouseEvent::X2Pressed);
+
+        let buttons = handler.sink.iter().map(|event| match event {
+            SynthEvent::MouseButton {
+                button, pressed, ..
+            } => (button.0, *pressed),
+            event => panic!("unexpected {event:?}"),
+        });
+        assert!(buttons.eq([(3, true), (3, false), (4, true)]));
+    }
+
+    #[test]
+    fn relative_mode_posts_deltas() {
+        let (_screen_size, receiver) = screen_size();
+        let mut handler = handler(
+            receiver,
+            InputOptions {
+                pointer_mode: PointerMode::Relative,
+                ..Default::default()
+            },
+        );
+
+        handler.mouse(MouseEvent::Move { x: 200, y: 100 });
+        handler.mouse(MouseEvent::LeftPressed);
+        handler.mouse(MouseEvent::Move { x: 180, y: 140 });
+
+        assert_eq!(
+            handler.sink[0],
+            SynthEvent::MouseDelta {
+                button: None,
+                point: CGPoint { x: 100.0, y: 50.0 },
+                delta: (100, 50),
+            }
+        );
+        assert_eq!(
+            handler.sink[2],
+            SynthEvent::MouseDelta {
+                button: Some(CGMouseButton::Left),
+                point: CGPoint { x: 90.0, y: 70.0 },
+                delta: (-10, 20),
+            }
+        );
     }
 }
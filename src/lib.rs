@@ -0,0 +1,496 @@
+//! Remote desktop server for macOS.
+//!
+//! [`ArisuServer`] embeds the server in another app; the `arisu` binary is a
+//! command line front end to it.
+//!
+//! ```no_run
+//! # async fn run() -> arisu::error::Result<()> {
+//! use arisu::{credential::Password, ArisuServer, Security};
+//!
+//! ArisuServer::builder()
+//!     .with_port(3390)
+//!     .with_security(Security::None)
+//!     .with_credentials("user", Some(Password::Static("secret".to_owned())))
+//!     .build()?
+//!     .run()
+//!     .await
+//! # }
+//! ```
+
+use std::{
+    cell::{Cell, RefCell},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use ironrdp::server::TlsIdentityCtx;
+use strum::EnumString;
+
+use crate::{
+    audit::AuditLog,
+    clipboard::ClipboardMode,
+    counter::{BitrateCounter, IntervalCounter},
+    credential::{CredentialStore, LoginLimit, Password},
+    error::{ArisuError, Result},
+    input::InputOptions,
+    metrics::Metrics,
+    multi_client::{InputControl, MultiClientPolicy},
+    screen::{CaptureOptions, FreezeSwitch, ScreenCapture},
+    server::{Backoff, Codec, ConsentPrompt, ServerContext, ServerObserver, SessionEvent},
+};
+
+pub mod audit;
+pub mod clipboard;
+pub mod counter;
+pub mod credential;
+pub mod error;
+mod health;
+pub mod input;
+mod metrics;
+pub mod multi_client;
+pub mod recording;
+pub mod screen;
+pub mod server;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString)]
+#[strum(ascii_case_insensitive)]
+pub enum Security {
+    None,
+    Tls,
+    Hybrid,
+}
+
+/// RDP server sharing the screen of this Mac, made with
+/// [`ArisuServer::builder`].
+pub struct ArisuServer {
+    settings: ArisuServerBuilder,
+    credentials: CredentialStore,
+}
+
+/// Settings of an [`ArisuServer`], defaulting to what the command line
+/// defaults to.
+pub struct ArisuServerBuilder {
+    hosts: Vec<IpAddr>,
+    port: u16,
+    dual_stack: bool,
+    security: Security,
+    identity: Option<TlsIdentityCtx>,
+    username: String,
+    password: Option<Password>,
+    login_limit: LoginLimit,
+    capture_options: CaptureOptions,
+    input_options: InputOptions,
+    capture_counter: IntervalCounter,
+    send_counter: IntervalCounter,
+    bitrate: BitrateCounter,
+    freeze: FreezeSwitch,
+    #[cfg(feature = "mock-backend")]
+    mock_capture: Option<(u16, u16)>,
+    consent: Option<ConsentPrompt>,
+    max_sessions: Option<usize>,
+    takeover: bool,
+    multi_client: Option<MultiClientPolicy>,
+    audit: Option<AuditLog>,
+    tcp_nodelay: bool,
+    clipboard: ClipboardMode,
+    codec: Codec,
+    session_events: Option<std::sync::mpsc::Sender<SessionEvent>>,
+    observer: Option<Arc<dyn ServerObserver>>,
+    health_addr: Option<SocketAddr>,
+    metrics_port: Option<u16>,
+    aux_bind_backoff: Backoff,
+}
+
+impl Default for ArisuServerBuilder {
+    fn default() -> Self {
+        Self {
+            hosts: vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED)],
+            port: 3389,
+            dual_stack: true,
+            security: Security::None,
+            identity: None,
+            username: "user".to_owned(),
+            password: None,
+            login_limit: LoginLimit::default(),
+            capture_options: CaptureOptions::default(),
+            input_options: InputOptions::default(),
+            capture_counter: IntervalCounter::new(),
+            send_counter: IntervalCounter::new(),
+            bitrate: BitrateCounter::new(None),
+            freeze: FreezeSwitch::default(),
+            #[cfg(feature = "mock-backend")]
+            mock_capture: None,
+            consent: None,
+            max_sessions: None,
+            takeover: false,
+            multi_client: None,
+            audit: None,
+            tcp_nodelay: true,
+            clipboard: ClipboardMode::Both,
+            codec: Codec::Bitmap,
+            session_events: None,
+            observer: None,
+            health_addr: None,
+            metrics_port: None,
+            aux_bind_backoff: Backoff {
+                initial: Duration::from_secs(1),
+                max: Duration::from_secs(30),
+                attempts: 5,
+            },
+        }
+    }
+}
+
+impl ArisuServerBuilder {
+    /// Addresses to listen on, IPv4 or IPv6.
+    pub fn with_hosts(mut self, hosts: Vec<IpAddr>) -> Self {
+        self.hosts = hosts;
+        self
+    }
+
+    pub fn with_host(self, host: IpAddr) -> Self {
+        self.with_hosts(vec![host])
+    }
+
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Listen on both `0.0.0.0` and `::` when either is a host.
+    pub fn with_dual_stack(mut self, dual_stack: bool) -> Self {
+        self.dual_stack = dual_stack;
+        self
+    }
+
+    pub fn with_security(mut self, security: Security) -> Self {
+        self.security = security;
+        self
+    }
+
+    /// Certificate for TLS and Hybrid security.
+    pub fn with_identity(mut self, identity: Option<TlsIdentityCtx>) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    /// Login of clients. Without a password, which only works without
+    /// security, clients log in with `user`.
+    pub fn with_credentials(
+        mut self,
+        username: impl Into<String>,
+        password: Option<Password>,
+    ) -> Self {
+        self.username = username.into();
+        self.password = password;
+        self
+    }
+
+    pub fn with_login_limit(mut self, login_limit: LoginLimit) -> Self {
+        self.login_limit = login_limit;
+        self
+    }
+
+    pub fn with_capture_options(mut self, capture_options: CaptureOptions) -> Self {
+        self.capture_options = capture_options;
+        self
+    }
+
+    pub fn with_input_options(mut self, input_options: InputOptions) -> Self {
+        self.input_options = input_options;
+        self
+    }
+
+    /// Counters the capture reports to, for showing them elsewhere.
+    pub fn with_counters(
+        mut self,
+        capture_counter: IntervalCounter,
+        send_counter: IntervalCounter,
+        bitrate: BitrateCounter,
+    ) -> Self {
+        self.capture_counter = capture_counter;
+        self.send_counter = send_counter;
+        self.bitrate = bitrate;
+        self
+    }
+
+    pub fn with_freeze(mut self, freeze: FreezeSwitch) -> Self {
+        self.freeze = freeze;
+        self
+    }
+
+    /// Serve a `width` x `height` test pattern instead of the screen.
+    #[cfg(feature = "mock-backend")]
+    pub fn with_mock_capture(mut self, size: Option<(u16, u16)>) -> Self {
+        self.mock_capture = size;
+        self
+    }
+
+    /// Ask the local user before accepting each connection.
+    pub fn with_consent(mut self, consent: Option<ConsentPrompt>) -> Self {
+        self.consent = consent;
+        self
+    }
+
+    /// Concurrent sessions allowed, one unless a multi-client policy is set.
+    pub fn with_max_sessions(mut self, max_sessions: Option<usize>) -> Self {
+        self.max_sessions = max_sessions;
+        self
+    }
+
+    pub fn with_takeover(mut self, takeover: bool) -> Self {
+        self.takeover = takeover;
+        self
+    }
+
+    pub fn with_multi_client(mut self, policy: Option<MultiClientPolicy>) -> Self {
+        self.multi_client = policy;
+        self
+    }
+
+    pub fn with_audit(mut self, audit: Option<AuditLog>) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    pub fn with_tcp_nodelay(mut self, tcp_nodelay: bool) -> Self {
+        self.tcp_nodelay = tcp_nodelay;
+        self
+    }
+
+    pub fn with_clipboard(mut self, clipboard: ClipboardMode) -> Self {
+        self.clipboard = clipboard;
+        self
+    }
+
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Receives a [`SessionEvent`] whenever a client connects or leaves.
+    pub fn with_session_events(
+        mut self,
+        session_events: Option<std::sync::mpsc::Sender<SessionEvent>>,
+    ) -> Self {
+        self.session_events = session_events;
+        self
+    }
+
+    /// Calls `observer` as clients come and go.
+    pub fn with_observer(mut self, observer: impl ServerObserver + 'static) -> Self {
+        self.observer = Some(Arc::new(observer));
+        self
+    }
+
+    /// Serve `GET /healthz` on this address, and `GET /checksum` with
+    /// `CaptureOptions::debug_checksums`.
+    pub fn with_health_addr(mut self, health_addr: Option<SocketAddr>) -> Self {
+        self.health_addr = health_addr;
+        self
+    }
+
+    /// Serve Prometheus metrics on this port of the first host.
+    pub fn with_metrics_port(mut self, metrics_port: Option<u16>) -> Self {
+        self.metrics_port = metrics_port;
+        self
+    }
+
+    /// Retry schedule of the health and metrics listeners.
+    pub fn with_aux_bind_backoff(mut self, backoff: Backoff) -> Self {
+        self.aux_bind_backoff = backoff;
+        self
+    }
+
+    /// Checks the settings fit together.
+    pub fn build(mut self) -> Result<ArisuServer> {
+        let security = self.security;
+        let password = match self.password.take() {
+            Some(password) => password,
+            None if security == Security::None => {
+                tracing::warn!("No password set, clients log in with `user`");
+                Password::Static("user".to_string())
+            }
+            None => return Err(ArisuError::PasswordRequired(security)),
+        };
+        if self.identity.is_none() && security != Security::None {
+            return Err(ArisuError::CertificateRequired(security));
+        }
+        if self.hosts.is_empty() {
+            return Err(ArisuError::NoHost);
+        }
+
+        let credentials = CredentialStore::new(self.username.clone(), password)
+            .with_login_limit(self.login_limit);
+        Ok(ArisuServer {
+            settings: self,
+            credentials,
+        })
+    }
+}
+
+impl ArisuServer {
+    pub fn builder() -> ArisuServerBuilder {
+        ArisuServerBuilder::default()
+    }
+
+    /// Captures the screen and serves clients until the capture ends.
+    ///
+    /// The server runs on tasks local to the calling thread, so the future
+    /// is not `Send`; drive it with `block_on` of a current thread runtime.
+    pub async fn run(self) -> Result<()> {
+        let Self {
+            settings,
+            credentials,
+        } = self;
+        let local_set = tokio::task::LocalSet::new();
+        let metrics = Metrics {
+            capture_interval: settings.capture_counter.interval(),
+            send_interval: settings.send_counter.interval(),
+            skipped_frames: settings.capture_options.skipped_frames.clone(),
+            started: Instant::now(),
+        };
+
+        #[cfg(feature = "mock-backend")]
+        let mock = settings.mock_capture.map(|size| {
+            tracing::info!(?size, "Serving a test pattern instead of the screen");
+            ScreenCapture::mock(&local_set, size)
+        });
+        #[cfg(not(feature = "mock-backend"))]
+        let mock = None;
+        let (screen_handler, screen_job_processor) = match mock {
+            Some(mock) => mock,
+            None => ScreenCapture::new(
+                &local_set,
+                settings.capture_counter,
+                settings.send_counter,
+                settings.bitrate,
+                settings.freeze,
+                settings.capture_options,
+            )?,
+        };
+
+        let screen_handler = screen_handler.with_observer(settings.observer.clone());
+        let context = Rc::new(ServerContext {
+            security: settings.security,
+            identity: settings.identity,
+            credentials,
+            screen: screen_handler,
+            input_options: settings.input_options,
+            consent: settings.consent,
+            max_sessions: settings.max_sessions,
+            takeover: settings.takeover,
+            input_control: settings.multi_client.map(InputControl::new),
+            active_sessions: Cell::new(0),
+            session_senders: RefCell::new(Vec::new()),
+            audit: settings.audit,
+            next_session_id: Cell::new(1),
+            tcp_nodelay: settings.tcp_nodelay,
+            clipboard: settings.clipboard,
+            codec: settings.codec,
+            session_events: settings.session_events,
+            observer: settings.observer,
+        });
+
+        tracing::info!(tcp_nodelay = settings.tcp_nodelay, "TCP options");
+        let addrs = server::listen_addrs(&settings.hosts, settings.port, settings.dual_stack);
+        // IPv4 clients reach an IPv6 socket only with dual stack and no
+        // IPv4 listener on the same port
+        let only_v6 = !settings.dual_stack || addrs.iter().any(SocketAddr::is_ipv4);
+        let listeners = addrs
+            .into_iter()
+            .filter_map(|addr| match server::bind(addr, only_v6) {
+                Ok(listener) => Some(listener),
+                Err(e) => {
+                    tracing::error!(?e, %addr, "Failed to bind, not listening there");
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        if listeners.is_empty() {
+            return Err(ArisuError::NoListener(settings.hosts));
+        }
+
+        if let Some(health_addr) = settings.health_addr {
+            let backoff = settings.aux_bind_backoff;
+            let screen = context.screen.clone();
+            local_set.spawn_local(async move {
+                let Some(listener) = backoff.retry(|| server::bind(health_addr, false)).await
+                else {
+                    tracing::error!(%health_addr, "Health check server disabled");
+                    return;
+                };
+                if let Err(e) = health::serve(listener, screen).await {
+                    tracing::error!(?e, "Health check server error");
+                }
+            });
+        }
+
+        if let Some(metrics_port) = settings.metrics_port {
+            let metrics_addr = SocketAddr::new(settings.hosts[0], metrics_port);
+            let backoff = settings.aux_bind_backoff;
+            let context = Rc::clone(&context);
+            local_set.spawn_local(async move {
+                let Some(listener) = backoff.retry(|| server::bind(metrics_addr, false)).await
+                else {
+                    tracing::error!(%metrics_addr, "Metrics server disabled");
+                    return;
+                };
+                if let Err(e) = metrics::serve(listener, metrics, context).await {
+                    tracing::error!(?e, "Metrics server error");
+                }
+            });
+        }
+
+        let server_join_handlers = listeners
+            .into_iter()
+            .map(|listener| {
+                let context = Rc::clone(&context);
+                local_set.spawn_local(async move {
+                    tracing::info!("Starting server");
+                    if let Err(e) = server::serve(listener, context).await {
+                        tracing::error!(?e, "Server run error");
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        local_set.await;
+        for server_join_handler in server_join_handlers {
+            server_join_handler.await.map_err(ArisuError::Task)?;
+        }
+        screen_job_processor.await.map_err(ArisuError::Task)??;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn security_needs_a_password_and_certificate() {
+        assert!(ArisuServer::builder().build().is_ok());
+
+        let error = ArisuServer::builder()
+            .with_security(Security::Tls)
+            .build()
+            .err()
+            .unwrap();
+        assert!(matches!(error, ArisuError::PasswordRequired(Security::Tls)));
+
+        let error = ArisuServer::builder()
+            .with_security(Security::Hybrid)
+            .with_credentials("user", Some(Password::Static("secret".to_owned())))
+            .build()
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error,
+            ArisuError::CertificateRequired(Security::Hybrid)
+        ));
+    }
+}
@@ -1,62 +1,426 @@
-use std::{net::IpAddr, path::PathBuf, str::FromStr};
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use anyhow::Context as _;
-use clap::Parser;
-// use clipboard::StubCliprdrServerFactory;
-use counter::IntervalCounter;
-use ironrdp::server::{Credentials, RdpServer, TlsIdentityCtx};
-use screen::ScreenCapture;
-use strum::EnumString;
+use arisu::{
+    audit::AuditLog,
+    clipboard::ClipboardMode,
+    counter::{BitrateCounter, FailureCounter, IntervalCounter, DEFAULT_INTERVAL_WINDOW},
+    credential::{LoginLimit, Password, TotpSecret},
+    error::ArisuError,
+    input::{
+        self, InputOptions, InputSourceSwitch, InputTarget, KeyCombo, KeyRemap, KeyboardLayout,
+        PointerMode, ScanCode, ScrollUnit, SecureAttentionAction,
+    },
+    multi_client::MultiClientPolicy,
+    recording::Recording,
+    screen::{
+        CaptureControl, CaptureFormat, CaptureOptions, ColorDepth, DepthAdaptation,
+        DisplaySelection, FreezeSwitch, RefreshRequest, Region, WindowMatch,
+    },
+    server::{Backoff, Codec, ConsentPrompt},
+    ArisuServer, Security,
+};
+use clap::{parser::ValueSource, ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser};
+use config::Config;
+use ironrdp::server::TlsIdentityCtx;
 use tracing::error;
 
-mod counter;
-
-// mod clipboard;
-// mod credential;
+mod config;
 mod gui;
-mod input;
-mod screen;
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString)]
-#[strum(ascii_case_insensitive)]
-enum Security {
-    None,
-    Tls,
-    Hybrid,
-}
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// Name of the person to greet
-    #[arg(long, default_value = "0.0.0.0")]
-    host: String,
+    /// TOML file with settings, keyed by flag name (e.g. `max-fps = 20`), which flags override.
+    /// Supported keys: host, port, dual-stack, certificate, key, security, username, password,
+    /// totp-secret, max-fps, scale, power-save, show-cursor, max-sessions, max-bitrate,
+    /// require-consent, no-gui, health-addr, metrics-port and log-file
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Also log to this file, rotated daily to `<path>.YYYY-MM-DD`
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+    /// Address to listen on, IPv4 or IPv6 (e.g. `::1`), repeatable or comma-separated
+    #[arg(long, default_value = "0.0.0.0", value_delimiter = ',')]
+    host: Vec<IpAddr>,
     #[arg(long, default_value_t = 3389)]
     port: u16,
+    /// Listen on both `0.0.0.0` and `::` when either is a host, otherwise `::` is IPv6 only
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    dual_stack: bool,
     #[arg(long)]
     certificate: Option<PathBuf>,
     #[arg(long)]
     key: Option<PathBuf>,
     #[arg(long, default_value = "none")]
     security: Security,
+    /// Name clients log in with
+    #[arg(long, default_value = "user")]
+    username: String,
+    /// Password clients log in with, required with TLS or Hybrid security
+    #[arg(long, env = "ARISU_PASSWORD", hide_env_values = true)]
+    #[arg(conflicts_with_all = ["totp_secret", "one_time_password"])]
+    password: Option<String>,
+    /// Base32 TOTP secret, making the current 6 digit code the password
+    #[arg(long, conflicts_with = "one_time_password")]
+    totp_secret: Option<TotpSecret>,
+    /// Password which only works for the first successful login
+    #[arg(long)]
+    one_time_password: Option<String>,
+    /// Failed logins within a minute after which a client address is locked out
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+    max_attempts: u32,
+    /// Seconds the first lockout lasts, doubling for each further lockout of the address
+    #[arg(long, default_value_t = 60)]
+    lockout_seconds: u64,
+    /// Client scan code which acts as the macOS Fn key (`0xE0` prefix for extended keys)
+    #[arg(long, default_value = "0x46")]
+    fn_key: ScanCode,
+    /// Client key combo which switches the macOS input source, e.g. `shift+0x39` or `0xE038`
+    #[arg(long)]
+    input_source_key: Option<KeyCombo>,
+    /// Client key combo which forces a full screen refresh
+    #[arg(long, default_value = "ctrl+alt+0x13")]
+    refresh_key: KeyCombo,
+    /// macOS shortcut posted for --input-source-key (ctrl-space or ctrl-option-space)
+    #[arg(long, default_value = "ctrl-space")]
+    input_source_switch: InputSourceSwitch,
+    /// macOS action for the client's Ctrl+Alt+Del (force-quit, lock or none)
+    #[arg(long, visible_alias = "cad-action", default_value = "force-quit")]
+    secure_attention: SecureAttentionAction,
+    /// Post input to this process instead of the focused app (best effort, falls back to the session)
+    #[arg(long, conflicts_with = "target_app")]
+    target_pid: Option<i32>,
+    /// Post input to this app, by bundle identifier or name, instead of the focused app
+    #[arg(long)]
+    target_app: Option<String>,
+    /// How client pointer positions move the cursor (absolute or relative, for apps capturing the pointer)
+    #[arg(long, default_value = "absolute")]
+    pointer_mode: PointerMode,
+    /// Layout of the client's keyboard (us, uk, german or french), set the same input source on the Mac
+    #[arg(long, default_value = "us")]
+    keyboard_layout: KeyboardLayout,
+    /// Type characters of --keyboard-layout as such rather than by key position, except in shortcuts
+    #[arg(long)]
+    prefer_unicode_input: bool,
+    /// Client modifier keys acting as other Mac modifiers, e.g. `LWIN=CTRL,LALT=CMD`
+    #[arg(long, value_delimiter = ',')]
+    remap: Vec<KeyRemap>,
+    /// Drop the key presses clients repeat while a key is held
+    #[arg(long)]
+    no_autorepeat: bool,
+    /// Multiplier for the client's scroll deltas
+    #[arg(long, default_value_t = 1.0)]
+    scroll_speed: f64,
+    /// Unit scroll deltas are posted in (pixel, line, or auto for lines per wheel notch)
+    #[arg(long, default_value = "pixel")]
+    scroll_unit: ScrollUnit,
+    /// Upper bound of captured frames per second (default 30, 15 with --power-save)
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_fps: Option<u32>,
+    /// Capture at this fraction of the display resolution (default 1, 0.5 with --power-save)
+    #[arg(long, value_parser = parse_scale)]
+    scale: Option<f64>,
+    /// Draw the cursor into the captured frames, for clients which don't draw their own
+    #[arg(long)]
+    show_cursor: bool,
+    /// Channels system audio is sent in, 1 (mono) or 2 (stereo)
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u16).range(1..=2))]
+    audio_channels: u16,
+    /// Frames the FPS shown in the status bar is averaged over
+    #[arg(long, default_value_t = DEFAULT_INTERVAL_WINDOW)]
+    fps_window: usize,
+    /// Lower frame rate, resolution and queue depth to save battery and heat
+    #[arg(long)]
+    power_save: bool,
+    /// Maximum number of concurrent client sessions (default 1, unlimited with --multi-client)
+    #[arg(long, visible_alias = "max-clients")]
+    max_sessions: Option<usize>,
+    /// Let a client logging in past --max-sessions disconnect the oldest session instead
+    #[arg(long)]
+    takeover: bool,
+    /// Who controls input with several clients connected (mirror, exclusive or primary-input)
+    #[arg(long)]
+    multi_client: Option<MultiClientPolicy>,
+    /// Directions the clipboard is shared in (off, client-to-server, server-to-client or both)
+    #[arg(long, default_value = "both")]
+    clipboard: ClipboardMode,
+    /// Codec display updates are compressed with (bitmap or remotefx), bitmap for clients without it
+    #[arg(long, default_value = "bitmap")]
+    codec: Codec,
+    /// Seconds without captured frames, while a client is connected, before the capture restarts
+    #[arg(long, default_value_t = 10)]
+    stall_timeout: u64,
+    /// Capture restarts attempted before stalled clients are disconnected
+    #[arg(long, default_value_t = 3)]
+    max_capture_restarts: u32,
+    /// Ask the local user to approve each incoming connection
+    #[arg(long)]
+    require_consent: bool,
+    /// Seconds to wait for the local user before rejecting a connection
+    #[arg(long, default_value_t = 30)]
+    consent_timeout: u64,
+    /// Append a record of remote input events to this file
+    #[arg(long)]
+    audit_input: Option<PathBuf>,
+    /// Include key content in the input audit log
+    #[arg(long, requires = "audit_input")]
+    audit_keystrokes: bool,
+    /// Append the screen updates sent to clients, with timestamps, to this file for later replay
+    #[arg(long)]
+    record: Option<PathBuf>,
+    /// Address to serve an HTTP `/healthz` endpoint on, e.g. `127.0.0.1:3390`
+    #[arg(long)]
+    health_addr: Option<SocketAddr>,
+    /// Port to serve Prometheus metrics on at `/metrics`, on the first --host address
+    #[arg(long)]
+    metrics_port: Option<u16>,
+    /// Attempts to bind auxiliary listeners such as --health-addr before giving up on them
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+    aux_bind_attempts: u32,
+    /// Longest wait in seconds between attempts to bind auxiliary listeners
+    #[arg(long, default_value_t = 30)]
+    aux_bind_max_backoff: u64,
+    /// Cap on outbound display and audio data in kbit/s
+    #[arg(long)]
+    max_bitrate: Option<u64>,
+    /// Seconds to keep capturing after the last client leaves (capture never stops if unset)
+    #[arg(long)]
+    capture_linger: Option<u64>,
+    /// Switch the display to the mode matching the client's size when there is one, restored on exit
+    #[arg(long)]
+    match_client_resolution: bool,
+    /// Capture the display at this position in the display list instead of the first one
+    #[arg(long, conflicts_with_all = ["display_id", "virtual_display"])]
+    display: Option<usize>,
+    /// Capture the display with this CGDirectDisplayID instead of the first one
+    #[arg(long, conflicts_with = "virtual_display")]
+    display_id: Option<u32>,
+    /// Capture every display, laid out side by side as one desktop
+    #[arg(long, conflicts_with_all = ["display", "display_id", "virtual_display", "regions"])]
+    all_displays: bool,
+    /// Capture a virtual display instead of the main display, starting at this size (e.g. `1920x1080`) and following the client's resolution
+    #[arg(long, value_parser = parse_size)]
+    virtual_display: Option<(u16, u16)>,
+    /// Region of the display in points (`x,y,width,height`), up to two are shown side by side
+    #[arg(long = "region")]
+    regions: Vec<Region>,
+    /// Capture only windows with this id or title substring (repeatable)
+    #[arg(long = "include-window")]
+    include_windows: Vec<WindowMatch>,
+    /// Leave windows with this id or title substring out of the capture (repeatable)
+    #[arg(long = "exclude-window")]
+    exclude_windows: Vec<WindowMatch>,
+    /// Leave the app with this bundle identifier out of the capture (repeatable)
+    #[arg(long = "exclude-app")]
+    exclude_apps: Vec<String>,
+    /// Leave the Dock out of the capture
+    #[arg(long)]
+    exclude_dock: bool,
+    /// Leave the menu bar and its status items out of the capture
+    #[arg(long)]
+    exclude_menubar: bool,
+    /// Cover the local displays with black while a client is connected; clients still see them
+    #[arg(long)]
+    blank_local_display: bool,
+    /// Send only changed regions while the client is interacting and whole frames when it is idle
+    #[arg(long)]
+    adaptive_quality: bool,
+    /// Present only every Nth captured frame to clients falling behind
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    frame_skip: u32,
+    /// Log a checksum of every frame sent and serve the last one on --health-addr at `/checksum`
+    #[arg(long)]
+    debug_checksums: bool,
+    /// Pixel format frames are captured in (bgra, bgrx to ignore alpha, or l10r for 10-bit color)
+    #[arg(long, default_value = "bgra")]
+    pixel_format: CaptureFormat,
+    /// Highest color depth sent to clients in bits per pixel (15, 16, 24 or 32)
+    #[arg(long, default_value = "32")]
+    max_color_depth: ColorDepth,
+    /// Lower the color depth while frames are sent slower than they are captured
+    #[arg(long)]
+    adapt_color_depth: bool,
+    /// Send interval over capture interval above which --adapt-color-depth lowers the depth
+    #[arg(long, default_value_t = 1.5)]
+    depth_lag_ratio: f64,
+    /// Send interval over capture interval below which --adapt-color-depth raises the depth back
+    #[arg(long, default_value_t = 1.1)]
+    depth_recover_ratio: f64,
+    /// Check at startup that synthetic input reaches the session, e.g. that Accessibility is granted
+    #[arg(long)]
+    self_test: bool,
+    /// Run without the status bar item, e.g. as a launchd service
+    #[arg(long)]
+    no_gui: bool,
+    /// Disable Nagle's algorithm on RDP connections for lower input latency
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    tcp_nodelay: bool,
+    /// POSIX shared memory name to publish captured frames to (e.g. `/arisu`)
+    #[cfg(feature = "shm")]
+    #[arg(long, conflicts_with = "all_displays")]
+    shm_name: Option<String>,
+    /// Serve a test pattern of this size (e.g. `64x48`) instead of capturing the screen
+    #[cfg(feature = "mock-backend")]
+    #[arg(long, value_parser = parse_size)]
+    mock_capture: Option<(u16, u16)>,
+}
+
+impl Args {
+    /// Fills in settings from `config` which weren't given on the command line.
+    fn merge(&mut self, config: Config, matches: &ArgMatches) {
+        let given = |id: &str| {
+            matches!(
+                matches.value_source(id),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            )
+        };
+        macro_rules! merge {
+            ($($field:ident),*) => {$(
+                if let Some(value) = config.$field {
+                    if !given(stringify!($field)) {
+                        self.$field = value;
+                    }
+                }
+            )*};
+        }
+        macro_rules! merge_optional {
+            ($($field:ident),*) => {$(
+                if self.$field.is_none() {
+                    self.$field = config.$field;
+                }
+            )*};
+        }
+        merge!(
+            host,
+            port,
+            dual_stack,
+            security,
+            username,
+            power_save,
+            show_cursor,
+            require_consent,
+            no_gui
+        );
+        merge_optional!(
+            certificate,
+            key,
+            max_fps,
+            scale,
+            max_sessions,
+            max_bitrate,
+            health_addr,
+            metrics_port,
+            log_file
+        );
+        // Any password on the command line replaces the file's
+        if self.password.is_none() && self.totp_secret.is_none() && self.one_time_password.is_none()
+        {
+            merge_optional!(password, totp_secret);
+        }
+    }
+}
+
+/// Regions the compositor lays out side by side.
+const MAX_REGIONS: usize = 2;
+
+fn parse_size(s: &str) -> Result<(u16, u16), String> {
+    let (width, height) = s
+        .split_once('x')
+        .ok_or_else(|| format!("invalid size {s:?}, expected WIDTHxHEIGHT"))?;
+    let parse = |v: &str| {
+        v.parse::<u16>()
+            .ok()
+            .filter(|v| *v != 0)
+            .ok_or_else(|| format!("invalid size {s:?}"))
+    };
+    Ok((parse(width)?, parse(height)?))
+}
+
+fn parse_scale(s: &str) -> Result<f64, String> {
+    s.parse::<f64>()
+        .ok()
+        .filter(|scale| *scale > 0.0 && *scale <= 1.0)
+        .ok_or_else(|| format!("invalid scale {s:?}, expected a factor in (0, 1]"))
 }
 
 fn main() -> Result<(), anyhow::Error> {
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Some(path) = &args.config {
+        let config = Config::load(path)?;
+        args.merge(config, &matches);
+    }
+    if args.no_gui {
+        if args.require_consent {
+            anyhow::bail!("--require-consent asks through the GUI and can't be used with --no-gui");
+        }
+        if args.blank_local_display {
+            anyhow::bail!("--blank-local-display needs the GUI and can't be used with --no-gui");
+        }
+    }
 
-    let capture_counter = IntervalCounter::new();
-    let display_send_counter = IntervalCounter::new();
+    let capture_counter = IntervalCounter::with_window(args.fps_window);
+    let display_send_counter = IntervalCounter::with_window(args.fps_window);
+    let bitrate_counter = BitrateCounter::new(args.max_bitrate);
 
     let capture_counter_interval = capture_counter.interval();
     let display_send_counter_interval = display_send_counter.interval();
+    let bitrate_counter_stats = bitrate_counter.clone();
+    let freeze = FreezeSwitch::default();
+    let synth_failures = FailureCounter::default();
+    let synth_failures_stats = synth_failures.clone();
+    let input_activity = args.adaptive_quality.then(IntervalCounter::new);
+    let freeze_control = freeze.clone();
+    let capture = CaptureControl::default();
+    let capture_control = capture.clone();
 
-    use tracing_subscriber::{filter::LevelFilter, fmt, EnvFilter};
-    fmt()
-        .with_max_level(LevelFilter::INFO)
-        .with_env_filter(EnvFilter::from_default_env())
+    let power_save = args.power_save;
+    let blank_local_display = args.blank_local_display;
+    let no_gui = args.no_gui;
+    let (session_event_sender, session_event_receiver) = std::sync::mpsc::channel();
+    let (consent_sender, consent_receiver) = if args.require_consent {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        (Some(sender), Some(receiver))
+    } else {
+        (None, None)
+    };
+
+    use tracing_subscriber::{
+        fmt, layer::SubscriberExt as _, util::SubscriberInitExt as _, EnvFilter,
+    };
+    // Written directly rather than through a background writer, which would
+    // lose the last lines when AppKit exits the process on quit
+    let file_layer = args
+        .log_file
+        .as_deref()
+        .map(|path| {
+            let file_name = path
+                .file_name()
+                .with_context(|| format!("invalid log file {path:?}"))?;
+            let directory = path
+                .parent()
+                .filter(|directory| !directory.as_os_str().is_empty())
+                .unwrap_or(Path::new("."));
+            let appender = tracing_appender::rolling::daily(directory, file_name);
+            anyhow::Ok(fmt::layer().with_ansi(false).with_writer(appender))
+        })
+        .transpose()?;
+    tracing_subscriber::registry()
+        .with(EnvFilter::from_default_env())
+        .with(fmt::layer())
+        .with(file_layer)
         .init();
 
-    std::thread::spawn(move || {
+    if args.self_test {
+        input::self_test(&synth_failures);
+    }
+
+    let server_thread = std::thread::spawn(move || {
         let rt = tokio::runtime::Builder::new_current_thread()
             .enable_all()
             .build()
@@ -67,66 +431,163 @@ fn main() -> Result<(), anyhow::Error> {
             let mut join_set = tokio::task::JoinSet::new();
             let _server_handle = join_set.spawn_local_on(
                 async move {
-                    let local_set = tokio::task::LocalSet::new();
-                    let security = args.security;
-
                     tracing::info!("Building RDP server");
-                    let server_builder =
-                        RdpServer::builder().with_addr((IpAddr::from_str(&args.host)?, args.port));
-
-                    let server_builder =
-                        if let Some((cert_path, key_path)) = args.certificate.zip(args.key) {
-                            let identity = TlsIdentityCtx::init_from_paths(&cert_path, &key_path)
-                                .context("failed to init TLS identity")?;
-                            let acceptor = identity
-                                .make_acceptor()
-                                .context("failed to build TLS acceptor")?;
-
-                            if security == Security::Hybrid {
-                                server_builder.with_hybrid(acceptor, identity.pub_key)
-                            } else {
-                                server_builder.with_tls(acceptor)
-                            }
-                        } else if security == Security::None {
-                            server_builder.with_no_security()
-                        } else {
-                            anyhow::bail!("Security is specified. but cert is not specified");
-                        };
-
-                    tracing::info!("Create clipboard server");
-                    // let cliprdr = Box::new(StubCliprdrServerFactory::new());
+
+                    let password = match (args.totp_secret, args.one_time_password, args.password) {
+                        (Some(secret), _, _) => Some(Password::Totp(secret)),
+                        (None, Some(password), _) => Some(Password::OneTime(password)),
+                        (None, None, password) => password.map(Password::Static),
+                    };
+
+                    let identity = args
+                        .certificate
+                        .zip(args.key)
+                        .map(|(cert_path, key_path)| {
+                            TlsIdentityCtx::init_from_paths(&cert_path, &key_path)
+                                .context("failed to init TLS identity")
+                        })
+                        .transpose()?;
 
                     tracing::info!("Create display handler");
-                    let (screen_handler, screen_job_processor) =
-                        ScreenCapture::new(&local_set, capture_counter, display_send_counter)?;
-
-                    let mut server = server_builder
-                        .with_input_handler(screen_handler.input_handler())
-                        .with_display_handler(screen_handler.clone())
-                        // .with_cliprdr_factory(Some(cliprdr))
-                        // .with_sound_factory(Some(Box::new(screen_handler)))
-                        .build();
-
-                    server.set_credentials(Some(Credentials {
-                        username: "user".to_string(),
-                        password: "user".to_string(),
-                        domain: None,
-                    }));
-
-                    let server_join_handler = local_set.spawn_local(async move {
-                        tracing::info!("Starting server");
-                        if let Err(e) = server.run().await {
-                            tracing::error!(?e, "Server run error");
+                    let refresh = RefreshRequest::default();
+                    let mut capture_options = if args.power_save {
+                        tracing::info!("Power save profile enabled");
+                        CaptureOptions::power_save()
+                    } else {
+                        CaptureOptions::default()
+                    };
+                    if let Some(max_fps) = args.max_fps {
+                        capture_options.max_fps = Some(max_fps);
+                    }
+                    if let Some(scale) = args.scale {
+                        capture_options.scale = scale;
+                    }
+                    capture_options.show_cursor = args.show_cursor;
+                    capture_options.audio_channels = args.audio_channels;
+                    capture_options.stall_timeout = Duration::from_secs(args.stall_timeout);
+                    capture_options.max_restarts = args.max_capture_restarts;
+                    capture_options.capture_linger = args.capture_linger.map(Duration::from_secs);
+                    capture_options.virtual_display = args.virtual_display;
+                    capture_options.display = args
+                        .display
+                        .map(DisplaySelection::Index)
+                        .or(args.display_id.map(DisplaySelection::Id));
+                    capture_options.all_displays = args.all_displays;
+                    if args.regions.len() > MAX_REGIONS {
+                        anyhow::bail!("At most {MAX_REGIONS} regions can be composited");
+                    }
+                    capture_options.regions = args.regions;
+                    capture_options.include_windows = args.include_windows;
+                    capture_options.exclude_windows = args.exclude_windows;
+                    capture_options.exclude_apps = args.exclude_apps;
+                    capture_options.exclude_dock = args.exclude_dock;
+                    capture_options.exclude_menubar = args.exclude_menubar;
+                    capture_options.exclude_self = args.blank_local_display;
+                    capture_options.pixel_format = args.pixel_format;
+                    capture_options.color_depth = args.max_color_depth;
+                    if args.adapt_color_depth {
+                        if args.depth_recover_ratio >= args.depth_lag_ratio {
+                            anyhow::bail!("--depth-recover-ratio must be below --depth-lag-ratio");
                         }
-                    });
+                        capture_options.depth_adaptation = Some(DepthAdaptation {
+                            lag_ratio: args.depth_lag_ratio,
+                            recover_ratio: args.depth_recover_ratio,
+                        });
+                    }
+                    capture_options.refresh = refresh.clone();
+                    capture_options.control = capture;
+                    capture_options.frame_skip = args.frame_skip;
+                    capture_options.debug_checksums = args.debug_checksums;
+                    capture_options.recording = args
+                        .record
+                        .as_deref()
+                        .map(|path| {
+                            tracing::info!(?path, "Recording screen updates");
+                            Recording::open(path)
+                                .with_context(|| format!("failed to open recording {path:?}"))
+                        })
+                        .transpose()?;
+                    capture_options.match_client_resolution = args.match_client_resolution;
+                    capture_options.input_activity =
+                        input_activity.as_ref().map(IntervalCounter::interval);
+                    #[cfg(feature = "shm")]
+                    {
+                        capture_options.shm_name = args.shm_name.clone();
+                    }
+
+                    let audit = args
+                        .audit_input
+                        .as_deref()
+                        .map(|path| {
+                            tracing::info!(?path, "Auditing remote input");
+                            AuditLog::open(path, args.audit_keystrokes)
+                                .with_context(|| format!("failed to open audit log {path:?}"))
+                        })
+                        .transpose()?;
 
-                    local_set.await;
-                    server_join_handler.await.context("server error")?;
-                    screen_job_processor
-                        .await
-                        .context("display job join error")
-                        .and_then(|i| i.context("diaply job error"))?;
+                    let server = ArisuServer::builder()
+                        .with_hosts(args.host)
+                        .with_port(args.port)
+                        .with_dual_stack(args.dual_stack)
+                        .with_security(args.security)
+                        .with_identity(identity)
+                        .with_credentials(args.username, password)
+                        .with_login_limit(LoginLimit {
+                            max_failures: args.max_attempts as usize,
+                            lockout: Duration::from_secs(args.lockout_seconds),
+                        })
+                        .with_capture_options(capture_options)
+                        .with_input_options(InputOptions {
+                            fn_key: Some(args.fn_key),
+                            input_source_key: args.input_source_key,
+                            input_source_switch: args.input_source_switch,
+                            secure_attention: args.secure_attention,
+                            refresh_key: Some(args.refresh_key),
+                            refresh,
+                            target: args
+                                .target_pid
+                                .map(InputTarget::Pid)
+                                .or(args.target_app.map(InputTarget::App)),
+                            synth_failures,
+                            activity: input_activity,
+                            scroll_speed: Some(args.scroll_speed),
+                            scroll_unit: args.scroll_unit,
+                            pointer_mode: args.pointer_mode,
+                            keyboard_layout: args.keyboard_layout,
+                            remap: args.remap,
+                            prefer_unicode: args.prefer_unicode_input,
+                            no_autorepeat: args.no_autorepeat,
+                        })
+                        .with_counters(capture_counter, display_send_counter, bitrate_counter)
+                        .with_freeze(freeze)
+                        .with_consent(consent_sender.map(|sender| {
+                            ConsentPrompt::new(sender, Duration::from_secs(args.consent_timeout))
+                        }))
+                        .with_max_sessions(args.max_sessions)
+                        .with_takeover(args.takeover)
+                        .with_multi_client(args.multi_client)
+                        .with_audit(audit)
+                        .with_tcp_nodelay(args.tcp_nodelay)
+                        .with_clipboard(args.clipboard)
+                        .with_codec(args.codec)
+                        .with_session_events(Some(session_event_sender))
+                        .with_health_addr(args.health_addr)
+                        .with_metrics_port(args.metrics_port)
+                        .with_aux_bind_backoff(Backoff {
+                            initial: Duration::from_secs(1),
+                            max: Duration::from_secs(args.aux_bind_max_backoff),
+                            attempts: args.aux_bind_attempts,
+                        });
+                    #[cfg(feature = "mock-backend")]
+                    let server = server.with_mock_capture(args.mock_capture);
 
+                    let server = match server.build() {
+                        Err(e @ ArisuError::PasswordRequired(_)) => {
+                            anyhow::bail!("{e}, set --password or ARISU_PASSWORD")
+                        }
+                        server => server?,
+                    };
+                    server.run().await?;
                     Ok(())
                 },
                 &top_local_set,
@@ -143,7 +604,27 @@ fn main() -> Result<(), anyhow::Error> {
         });
     });
 
-    gui::run(capture_counter_interval, display_send_counter_interval);
+    if no_gui {
+        tracing::info!("Running without GUI");
+        drop(session_event_receiver);
+        server_thread
+            .join()
+            .map_err(|_| anyhow::anyhow!("server thread panicked"))?;
+        return Ok(());
+    }
+
+    gui::run(
+        capture_counter_interval,
+        display_send_counter_interval,
+        bitrate_counter_stats,
+        freeze_control,
+        capture_control,
+        synth_failures_stats,
+        consent_receiver,
+        session_event_receiver,
+        power_save,
+        blank_local_display,
+    );
 
     Ok(())
 }
@@ -0,0 +1,155 @@
+use std::{fmt::Write as _, rc::Rc, time::Instant};
+
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::{
+    counter::{FrameCounter, Interval},
+    health::{read_request_line, write_response, REQUEST_TIMEOUT},
+    server::ServerContext,
+};
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Values exported on `/metrics`.
+pub struct Metrics {
+    pub capture_interval: Interval,
+    pub send_interval: Interval,
+    pub skipped_frames: FrameCounter,
+    pub started: Instant,
+}
+
+/// Metrics at one point in time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Sample {
+    capture_fps: f64,
+    send_fps: f64,
+    skipped_frames: u64,
+    connected_clients: usize,
+    uptime_seconds: f64,
+}
+
+impl Sample {
+    /// Prometheus text exposition format.
+    fn render(&self) -> String {
+        let gauges = [
+            (
+                "arisu_capture_fps",
+                "Frames captured per second.",
+                self.capture_fps,
+            ),
+            (
+                "arisu_send_fps",
+                "Frames sent to clients per second.",
+                self.send_fps,
+            ),
+            (
+                "arisu_connected_clients",
+                "Client sessions currently connected.",
+                self.connected_clients as f64,
+            ),
+            (
+                "arisu_uptime_seconds",
+                "Seconds since the server started.",
+                self.uptime_seconds,
+            ),
+        ];
+        let counters = [(
+            "arisu_skipped_frames_total",
+            "Frames skipped for clients falling behind the capture.",
+            self.skipped_frames,
+        )];
+        let mut body = String::new();
+        for (name, help, value) in gauges {
+            let _ = write!(
+                body,
+                "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n"
+            );
+        }
+        for (name, help, value) in counters {
+            let _ = write!(
+                body,
+                "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}\n"
+            );
+        }
+        body
+    }
+}
+
+/// Serves `GET /metrics` in the Prometheus text format.
+pub async fn serve(
+    listener: TcpListener,
+    metrics: Metrics,
+    context: Rc<ServerContext>,
+) -> anyhow::Result<()> {
+    tracing::info!("Serving metrics on {}", listener.local_addr()?);
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                tracing::error!(?e, "Failed to accept metrics connection");
+                continue;
+            }
+        };
+
+        let sample = Sample {
+            capture_fps: 1.0 / metrics.capture_interval.get().as_secs_f64(),
+            send_fps: 1.0 / metrics.send_interval.get().as_secs_f64(),
+            skipped_frames: metrics.skipped_frames.get(),
+            connected_clients: context.active_sessions.get(),
+            uptime_seconds: metrics.started.elapsed().as_secs_f64(),
+        };
+        tokio::task::spawn_local(async move {
+            let respond = respond(stream, sample);
+            if let Err(e) = tokio::time::timeout(REQUEST_TIMEOUT, respond).await {
+                tracing::debug!(?e, "Metrics request timed out");
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, sample: Sample) {
+    let Some(request_line) = read_request_line(&mut stream).await else {
+        return;
+    };
+
+    let mut request_line = request_line.split(|&b| b == b' ');
+    match (request_line.next(), request_line.next()) {
+        (Some(b"GET"), Some(b"/metrics")) => {
+            write_response(&mut stream, "200 OK", CONTENT_TYPE, &sample.render()).await
+        }
+        _ => {
+            write_response(
+                &mut stream,
+                "404 Not Found",
+                "text/plain",
+                "404 Not Found\n",
+            )
+            .await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_render_as_gauges() {
+        let sample = Sample {
+            capture_fps: 30.0,
+            send_fps: 29.5,
+            skipped_frames: 7,
+            connected_clients: 2,
+            uptime_seconds: 12.25,
+        };
+        let body = sample.render();
+        assert!(body.contains("# TYPE arisu_capture_fps gauge\narisu_capture_fps 30\n"));
+        assert!(body.contains("\narisu_send_fps 29.5\n"));
+        assert!(body.contains("\narisu_connected_clients 2\n"));
+        assert!(body.contains("\narisu_uptime_seconds 12.25\n"));
+        assert!(body.ends_with(
+            "# TYPE arisu_skipped_frames_total counter\narisu_skipped_frames_total 7\n"
+        ));
+    }
+}
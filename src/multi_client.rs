@@ -0,0 +1,147 @@
+//! Decides which of several connected clients controls the Mac's single
+//! cursor and keyboard.
+
+use std::sync::{Arc, Mutex, PoisonError};
+
+use strum::EnumString;
+
+/// What happens when more than one client is connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString)]
+#[strum(serialize_all = "kebab-case", ascii_case_insensitive)]
+pub enum MultiClientPolicy {
+    /// Everyone sees the screen, the longest connected client has input.
+    Mirror,
+    /// Only one client can be connected at a time.
+    Exclusive,
+    /// Everyone sees the screen, the most recently connected client has
+    /// input.
+    PrimaryInput,
+}
+
+#[derive(Debug, Default)]
+struct ControlState {
+    /// Connected sessions, oldest first.
+    sessions: Vec<u64>,
+    holder: Option<u64>,
+}
+
+impl ControlState {
+    fn hand_over(&mut self, holder: Option<u64>) {
+        if self.holder != holder {
+            tracing::info!(from = ?self.holder, to = ?holder, "Input control changed");
+            self.holder = holder;
+        }
+    }
+}
+
+/// Input control shared by the sessions of one server.
+#[derive(Debug, Clone)]
+pub struct InputControl {
+    policy: MultiClientPolicy,
+    state: Arc<Mutex<ControlState>>,
+}
+
+impl InputControl {
+    pub fn new(policy: MultiClientPolicy) -> Self {
+        Self {
+            policy,
+            state: Default::default(),
+        }
+    }
+
+    pub fn policy(&self) -> MultiClientPolicy {
+        self.policy
+    }
+
+    /// Registers a new session, which keeps its place until the lease is
+    /// dropped.
+    pub fn join(&self, session_id: u64) -> ControlLease {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.sessions.push(session_id);
+        let holder = match self.policy {
+            MultiClientPolicy::PrimaryInput => Some(session_id),
+            MultiClientPolicy::Mirror | MultiClientPolicy::Exclusive => {
+                state.holder.or(Some(session_id))
+            }
+        };
+        state.hand_over(holder);
+
+        ControlLease {
+            control: self.clone(),
+            session_id,
+        }
+    }
+}
+
+/// A session's place in [`InputControl`].
+#[derive(Debug)]
+pub struct ControlLease {
+    control: InputControl,
+    session_id: u64,
+}
+
+impl ControlLease {
+    /// Whether the session's input reaches the Mac.
+    pub fn has_control(&self) -> bool {
+        let state = self
+            .control
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        state.holder == Some(self.session_id)
+    }
+}
+
+impl Drop for ControlLease {
+    fn drop(&mut self) {
+        let mut state = self
+            .control
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        state.sessions.retain(|session| *session != self.session_id);
+        if state.holder == Some(self.session_id) {
+            let next = match self.control.policy {
+                MultiClientPolicy::PrimaryInput => state.sessions.last().copied(),
+                MultiClientPolicy::Mirror | MultiClientPolicy::Exclusive => {
+                    state.sessions.first().copied()
+                }
+            };
+            state.hand_over(next);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mirror_keeps_control_with_the_oldest_session() {
+        let control = InputControl::new(MultiClientPolicy::Mirror);
+        let first = control.join(1);
+        let second = control.join(2);
+        let third = control.join(3);
+        assert!(first.has_control());
+        assert!(!second.has_control());
+
+        drop(first);
+        assert!(second.has_control());
+        assert!(!third.has_control());
+    }
+
+    #[test]
+    fn primary_input_gives_control_to_the_newest_session() {
+        let control = InputControl::new(MultiClientPolicy::PrimaryInput);
+        let first = control.join(1);
+        let second = control.join(2);
+        assert!(!first.has_control());
+        assert!(second.has_control());
+
+        let third = control.join(3);
+        drop(third);
+        assert!(second.has_control());
+        drop(second);
+        assert!(first.has_control());
+    }
+}
@@ -0,0 +1,232 @@
+//! Records what clients were shown, for support and compliance review.
+//!
+//! The file starts with the magic `ARISUREC` and a little-endian `u16`
+//! version, followed by records. Every record starts with a `u8` kind, the
+//! `u64` display stream it belongs to and a `u64` Unix time in milliseconds:
+//!
+//! - kind 0, desktop size: `u16` width and height.
+//! - kind 1, bitmap: `u16` x, y, width and height, then `width * height`
+//!   BGRA pixels, row after row.
+//!
+//! All integers are little-endian. Replaying the bitmaps of a stream in order
+//! onto a canvas of its desktop size reproduces what its client saw.
+
+use std::{
+    fs::OpenOptions,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{channel, Sender},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+
+const MAGIC: &[u8; 8] = b"ARISUREC";
+const VERSION: u16 = 1;
+/// Bytes of records which can be queued before new ones are dropped, about
+/// two whole 4K frames.
+const MAX_QUEUED_BYTES: usize = 64 * 1024 * 1024;
+
+const KIND_DESKTOP_SIZE: u8 = 0;
+const KIND_BITMAP: u8 = 1;
+
+enum Record {
+    DesktopSize(u16, u16),
+    Bitmap {
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+        data: Bytes,
+    },
+}
+
+struct Entry {
+    stream: u64,
+    timestamp: u64,
+    record: Record,
+}
+
+impl Entry {
+    /// Bytes the entry takes up in the file.
+    fn len(&self) -> usize {
+        let body = match &self.record {
+            Record::DesktopSize(..) => 4,
+            Record::Bitmap { data, .. } => 8 + data.len(),
+        };
+        17 + body
+    }
+
+    fn write(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        let kind = match self.record {
+            Record::DesktopSize(..) => KIND_DESKTOP_SIZE,
+            Record::Bitmap { .. } => KIND_BITMAP,
+        };
+        writer.write_all(&[kind])?;
+        writer.write_all(&self.stream.to_le_bytes())?;
+        writer.write_all(&self.timestamp.to_le_bytes())?;
+        match &self.record {
+            Record::DesktopSize(width, height) => {
+                writer.write_all(&width.to_le_bytes())?;
+                writer.write_all(&height.to_le_bytes())
+            }
+            Record::Bitmap {
+                x,
+                y,
+                width,
+                height,
+                data,
+            } => {
+                for value in [x, y, width, height] {
+                    writer.write_all(&value.to_le_bytes())?;
+                }
+                writer.write_all(data)
+            }
+        }
+    }
+}
+
+/// Recording shared by every session, written by its own thread so a slow
+/// disk never delays display updates. Records are dropped with an error log
+/// when the queue holds [`MAX_QUEUED_BYTES`].
+#[derive(Clone)]
+pub struct Recording {
+    sender: Sender<Entry>,
+    queued_bytes: Arc<AtomicUsize>,
+    next_stream: Arc<AtomicU64>,
+}
+
+impl std::fmt::Debug for Recording {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Recording").finish_non_exhaustive()
+    }
+}
+
+impl Recording {
+    /// Opens `path` for appending, writing the header to a new file.
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut writer = BufWriter::new(file);
+        if writer.get_ref().metadata()?.len() == 0 {
+            writer.write_all(MAGIC)?;
+            writer.write_all(&VERSION.to_le_bytes())?;
+            writer.flush()?;
+        }
+        let (sender, receiver) = channel::<Entry>();
+        let queued_bytes = Arc::new(AtomicUsize::new(0));
+
+        let written_bytes = queued_bytes.clone();
+        std::thread::Builder::new()
+            .name("recording".to_owned())
+            .spawn(move || {
+                let write = |entry: Entry, writer: &mut BufWriter<_>| {
+                    written_bytes.fetch_sub(entry.len(), Ordering::Relaxed);
+                    entry.write(writer)
+                };
+                while let Ok(entry) = receiver.recv() {
+                    let mut result = write(entry, &mut writer);
+                    while result.is_ok() {
+                        match receiver.try_recv() {
+                            Ok(entry) => result = write(entry, &mut writer),
+                            Err(_) => break,
+                        }
+                    }
+                    if let Err(e) = result.and_then(|_| writer.flush()) {
+                        tracing::error!(?e, "Failed to write recording");
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            sender,
+            queued_bytes,
+            next_stream: Default::default(),
+        })
+    }
+
+    /// Recording of one client's display updates.
+    pub(crate) fn stream(&self) -> StreamRecording {
+        StreamRecording {
+            recording: self.clone(),
+            stream: self.next_stream.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+}
+
+pub(crate) struct StreamRecording {
+    recording: Recording,
+    stream: u64,
+}
+
+impl StreamRecording {
+    pub(crate) fn desktop_size(&self, width: u16, height: u16) {
+        self.record(Record::DesktopSize(width, height));
+    }
+
+    /// Bitmap of `width` x `height` BGRA pixels without row padding.
+    pub(crate) fn bitmap(&self, x: u16, y: u16, width: u16, height: u16, data: Bytes) {
+        self.record(Record::Bitmap {
+            x,
+            y,
+            width,
+            height,
+            data,
+        });
+    }
+
+    fn record(&self, record: Record) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let entry = Entry {
+            stream: self.stream,
+            timestamp,
+            record,
+        };
+        let len = entry.len();
+        let queued_bytes = &self.recording.queued_bytes;
+        if queued_bytes.fetch_add(len, Ordering::Relaxed) + len > MAX_QUEUED_BYTES {
+            queued_bytes.fetch_sub(len, Ordering::Relaxed);
+            tracing::error!("Recording queue is full - update dropped");
+            return;
+        }
+        if self.recording.sender.send(entry).is_err() {
+            tracing::error!("Recording writer is gone");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitmaps_are_written_with_their_placement() {
+        let entry = Entry {
+            stream: 2,
+            timestamp: 1000,
+            record: Record::Bitmap {
+                x: 1,
+                y: 2,
+                width: 1,
+                height: 1,
+                data: Bytes::from_static(&[0xAA, 0xBB, 0xCC, 0xFF]),
+            },
+        };
+        let mut written = Vec::new();
+        entry.write(&mut written).unwrap();
+        assert_eq!(written.len(), entry.len());
+
+        let mut expected = vec![KIND_BITMAP];
+        expected.extend(2u64.to_le_bytes());
+        expected.extend(1000u64.to_le_bytes());
+        expected.extend([1, 0, 2, 0, 1, 0, 1, 0]);
+        expected.extend([0xAA, 0xBB, 0xCC, 0xFF]);
+        assert_eq!(written, expected);
+    }
+}
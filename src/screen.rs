@@ -1,26 +1,64 @@
+use core_media_rs::cm_time::CMTime;
 use ironrdp::server::ServerEvent;
 use objc::runtime::Object;
+use objc2_core_graphics::{
+    CGDirectDisplayID, CGDisplayBounds, CGDisplayCopyDisplayMode, CGDisplayIsOnline, CGDisplayMode,
+    CGMainDisplayID, CGPreflightScreenCaptureAccess,
+};
 use screencapturekit::{
-    shareable_content::SCShareableContent,
-    stream::{
-        configuration::{pixel_format::PixelFormat, SCStreamConfiguration},
-        content_filter::SCContentFilter,
-        SCStream,
+    output::CMSampleBuffer,
+    shareable_content::{SCDisplay, SCRunningApplication, SCShareableContent, SCWindow},
+    stream::{configuration::SCStreamConfiguration, content_filter::SCContentFilter, SCStream},
+};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::RecvTimeoutError,
+        Arc, Mutex, OnceLock, PoisonError, RwLock,
     },
+    time::{Duration, Instant},
 };
-use std::sync::{Arc, RwLock};
 use tokio::{
-    sync::{mpsc, watch},
+    sync::{mpsc, watch, Notify},
     task::{JoinHandle, LocalSet},
 };
 
-use crate::{counter::IntervalCounter, input::InputHandler};
+use crate::{
+    audit::SessionAudit,
+    counter::{BitrateCounter, FrameCounter, Interval, IntervalCounter},
+    error::{ArisuError, Result},
+    input::{InputHandler, InputOptions},
+    recording::Recording,
+    server::ServerObserver,
+};
 
+mod color;
+mod compositor;
 mod display;
+mod display_mode;
+mod format;
+#[cfg(feature = "mock-backend")]
+mod mock;
+pub use color::ColorDepth;
+use color::SharedColorDepth;
+use compositor::Compositor;
+pub use compositor::Region;
+pub use display::DepthAdaptation;
+use display::{CapturedData, DisplayOutput};
+use display_mode::DisplayModeSwitch;
+pub use format::CaptureFormat;
+use virtual_display::VirtualDisplay;
+use window_filter::SystemUi;
+pub use window_filter::WindowMatch;
+#[cfg(feature = "shm")]
+mod shm;
+mod virtual_display;
+mod window_filter;
 
 mod sound;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 struct ScreenOutputIndex(usize);
 
 impl ScreenOutputIndex {
@@ -33,15 +71,368 @@ impl ScreenOutputIndex {
     }
 }
 
+/// Presentation time of a captured sample in milliseconds.
+///
+/// ScreenCaptureKit stamps audio and video samples against the same host
+/// clock, so these values are comparable between the two. Audio is sent to
+/// clients with this time, truncated to the 32 bits of the RDP wave
+/// timestamp, and video frames carry it in `CapturedData`. RDP bitmap updates
+/// have no timestamp field, so clients should treat a frame as due when it
+/// arrives and use the wave timestamps, which are echoed back in the wave
+/// confirm, to measure how far audio playback lags behind.
+fn presentation_millis(sample_buffer: &CMSampleBuffer) -> Option<u64> {
+    const VALID: u32 = 1;
+
+    let time = sample_buffer.get_presentation_timestamp();
+    if time.flags & VALID == 0 || time.timescale <= 0 || time.value < 0 {
+        return None;
+    }
+    Some((time.value as i128 * 1000 / time.timescale as i128) as u64)
+}
+
+/// Holds clients on a snapshot of the screen while the presenter prepares it.
+#[derive(Clone, Default)]
+pub struct FreezeSwitch {
+    frozen: Arc<AtomicBool>,
+    /// Per captured display, the whole frame its clients were last shown
+    /// before freezing.
+    snapshots: Arc<Mutex<HashMap<usize, Arc<CapturedData>>>>,
+}
+
+impl FreezeSwitch {
+    pub fn is_frozen(&self) -> bool {
+        self.frozen.load(Ordering::Acquire)
+    }
+
+    pub fn set_frozen(&self, frozen: bool) {
+        if self.frozen.swap(frozen, Ordering::AcqRel) != frozen {
+            tracing::info!(frozen, "Capture freeze toggled");
+        }
+        if !frozen {
+            self.snapshots
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clear();
+        }
+    }
+
+    /// Whole frame of the `stream`'s display shown while frozen, the one
+    /// `published` returns for the first session to see the freeze.
+    fn snapshot(
+        &self,
+        stream: usize,
+        published: impl FnOnce() -> Option<CapturedData>,
+    ) -> Option<Arc<CapturedData>> {
+        let mut snapshots = self
+            .snapshots
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if !snapshots.contains_key(&stream) {
+            snapshots.insert(stream, Arc::new(published()?));
+        }
+        snapshots.get(&stream).cloned()
+    }
+}
+
+/// Asks display captures to send their next frame whole, for clients whose
+/// view got stale or corrupted. Requests are counted, so every stream and
+/// session handles each of them by comparing with the count it saw last.
+#[derive(Debug, Clone, Default)]
+pub struct RefreshRequest(Arc<RefreshState>);
+
+#[derive(Debug, Default)]
+struct RefreshState {
+    generation: AtomicU64,
+    notify: Notify,
+}
+
+impl RefreshRequest {
+    pub fn request(&self) {
+        self.0.generation.fetch_add(1, Ordering::AcqRel);
+        self.0.notify.notify_waiters();
+    }
+
+    /// Whether there was a request since `seen`, which is moved up to it.
+    pub(crate) fn take(&self, seen: &mut u64) -> bool {
+        let generation = self.0.generation.load(Ordering::Acquire);
+        std::mem::replace(seen, generation) != generation
+    }
+
+    /// Waits for a request after `seen`.
+    pub(crate) async fn requested(&self, seen: u64) {
+        loop {
+            let notified = self.0.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if self.0.generation.load(Ordering::Acquire) != seen {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Pauses and stops capture from outside the job loop, e.g. from the status
+/// bar menu.
+#[derive(Debug, Clone, Default)]
+pub struct CaptureControl {
+    paused: Arc<AtomicBool>,
+    job_sender: Arc<OnceLock<mpsc::Sender<ScreenJob>>>,
+}
+
+impl CaptureControl {
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Acquire)
+    }
+
+    /// Stops or restarts the streams. Clients stay connected and see the
+    /// last frame while capture is paused.
+    pub fn set_paused(&self, paused: bool) {
+        if self.paused.swap(paused, Ordering::AcqRel) == paused {
+            return;
+        }
+        tracing::info!(paused, "Capture pause toggled");
+        self.send(ScreenJob::Pause(paused));
+    }
+
+    /// Ends the job loop, which stops the streams and puts back the display
+    /// mode, waiting up to `timeout` for it. Called before the app exits.
+    pub fn shutdown(&self, timeout: Duration) {
+        let (reply, stopped) = std::sync::mpsc::channel();
+        if self.send(ScreenJob::Shutdown(reply))
+            && stopped.recv_timeout(timeout) == Err(RecvTimeoutError::Timeout)
+        {
+            tracing::warn!(?timeout, "Capture did not stop in time");
+        }
+    }
+
+    fn attach(&self, job_sender: mpsc::Sender<ScreenJob>) {
+        if self.job_sender.set(job_sender).is_err() {
+            tracing::warn!("Capture control is already attached to a capture");
+        }
+    }
+
+    fn send(&self, job: ScreenJob) -> bool {
+        let Some(job_sender) = self.job_sender.get() else {
+            tracing::warn!("No capture to control");
+            return false;
+        };
+        if let Err(e) = job_sender.try_send(job) {
+            tracing::error!("Failed to send capture control job - {e}");
+            return false;
+        }
+        true
+    }
+}
+
 enum ScreenJob {
     Display(display::Job),
     Sound(sound::Job),
+    /// Applies [`CaptureControl::is_paused`].
+    Pause(bool),
+    /// Ends the job loop, replying once the context is dropped.
+    Shutdown(std::sync::mpsc::Sender<()>),
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct ScreenSize {
     pub client: (u16, u16),
     pub server: (u16, u16),
+    /// Size of the captured frames, which is what the client is told the
+    /// desktop size is.
+    pub capture: (u16, u16),
+    /// Captured sources in the order they are laid out side by side on the
+    /// client's desktop.
+    pub layout: Arc<[DisplayGeometry]>,
+}
+
+impl ScreenSize {
+    /// Size of `layout` at the native pixel size of each source.
+    pub fn layout_pixel_size(&self) -> (f64, f64) {
+        self.layout.iter().map(DisplayGeometry::pixel_size).fold(
+            (0.0, 0.0),
+            |(width, height), (source_width, source_height)| {
+                (width + source_width, f64::max(height, source_height))
+            },
+        )
+    }
+}
+
+/// Placement and density of a captured display.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DisplayGeometry {
+    /// Origin in the global display space, in points.
+    pub origin: (f64, f64),
+    /// Size in points.
+    pub size: (f64, f64),
+    /// Backing scale factor, pixels per point.
+    pub scale_factor: f64,
+}
+
+impl DisplayGeometry {
+    fn from_display_id(display_id: CGDirectDisplayID) -> Self {
+        let bounds = unsafe { CGDisplayBounds(display_id) };
+        let scale_factor = unsafe { CGDisplayCopyDisplayMode(display_id) }
+            .map(|mode| {
+                let width = unsafe { CGDisplayMode::width(Some(&mode)) };
+                let pixel_width = unsafe { CGDisplayMode::pixel_width(Some(&mode)) };
+                if width == 0 {
+                    1.0
+                } else {
+                    pixel_width as f64 / width as f64
+                }
+            })
+            .unwrap_or(1.0);
+
+        Self {
+            origin: (bounds.origin.x, bounds.origin.y),
+            size: (bounds.size.width, bounds.size.height),
+            scale_factor,
+        }
+    }
+
+    /// Size in physical pixels.
+    pub fn pixel_size(&self) -> (f64, f64) {
+        (
+            self.size.0 * self.scale_factor,
+            self.size.1 * self.scale_factor,
+        )
+    }
+}
+
+/// Display captured instead of the first one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplaySelection {
+    /// Position in ScreenCaptureKit's display list.
+    Index(usize),
+    Id(CGDirectDisplayID),
+}
+
+/// Tunables for the `SCStream` capture.
+#[derive(Debug, Clone)]
+pub struct CaptureOptions {
+    /// Upper bound of frames per second delivered by ScreenCaptureKit.
+    pub max_fps: Option<u32>,
+    /// Capture resolution relative to the display size. Clients are given
+    /// the scaled size as the desktop size. A layout they request later
+    /// changes how their input is mapped, and the display mode with
+    /// `match_client_resolution`, but never the capture size.
+    pub scale: f64,
+    /// Draw the cursor into the captured frames.
+    pub show_cursor: bool,
+    /// Pixel format frames are captured in.
+    pub pixel_format: CaptureFormat,
+    /// Channels system audio is captured and sent in, 1 or 2.
+    pub audio_channels: u16,
+    /// Number of frames ScreenCaptureKit may keep in flight.
+    pub queue_depth: Option<u32>,
+    /// How long capture may deliver nothing while a client is connected
+    /// before the stream is restarted.
+    pub stall_timeout: Duration,
+    /// Restarts attempted for a stalled stream before clients are disconnected.
+    pub max_restarts: u32,
+    /// How long the stream keeps running after the last display client left.
+    /// The stream is never stopped when unset.
+    pub capture_linger: Option<Duration>,
+    /// Capture a virtual display instead of the main display, created at
+    /// this size and resized to the resolution clients request.
+    pub virtual_display: Option<(u16, u16)>,
+    /// Display captured instead of the first one.
+    pub display: Option<DisplaySelection>,
+    /// Capture every display, laid out side by side as one desktop.
+    pub all_displays: bool,
+    /// Regions of the display composited side by side instead of the whole
+    /// display.
+    pub regions: Vec<Region>,
+    /// Windows captured instead of the whole display.
+    pub include_windows: Vec<WindowMatch>,
+    /// Windows left out of the capture.
+    pub exclude_windows: Vec<WindowMatch>,
+    /// Bundle identifiers of applications left out of the capture.
+    pub exclude_apps: Vec<String>,
+    /// Leave the windows of this process, such as the privacy screen, out of
+    /// the capture.
+    pub exclude_self: bool,
+    /// Leave the Dock out of the capture.
+    pub exclude_dock: bool,
+    /// Leave the menu bar and its status items out of the capture.
+    pub exclude_menubar: bool,
+    /// Client input activity. When set, only changed regions are sent while
+    /// the client is interacting and whole frames once it is idle, which
+    /// also repairs regions lost to frames skipped under load.
+    pub input_activity: Option<Interval>,
+    /// Depth the pixels sent to clients are reduced to.
+    pub color_depth: ColorDepth,
+    /// Lower the color depth while clients fall behind the capture.
+    pub depth_adaptation: Option<DepthAdaptation>,
+    /// Refresh requested by clients.
+    pub refresh: RefreshRequest,
+    /// Pause and shutdown requested by the local user.
+    pub control: CaptureControl,
+    /// Present only every Nth captured frame to clients falling behind.
+    pub frame_skip: u32,
+    /// Frames skipped with `frame_skip`.
+    pub skipped_frames: FrameCounter,
+    /// Log a checksum of every frame sent, to verify the pipeline is lossless.
+    pub debug_checksums: bool,
+    /// Where the updates sent to clients are recorded.
+    pub recording: Option<Recording>,
+    /// Switch the display to the mode matching the client's size, if there
+    /// is one.
+    pub match_client_resolution: bool,
+    /// POSIX shared memory name the captured frames are published to.
+    #[cfg(feature = "shm")]
+    pub shm_name: Option<String>,
+}
+
+impl Default for CaptureOptions {
+    fn default() -> Self {
+        Self {
+            max_fps: Some(DEFAULT_MAX_FPS),
+            scale: 1.0,
+            show_cursor: false,
+            pixel_format: CaptureFormat::default(),
+            audio_channels: 2,
+            queue_depth: None,
+            stall_timeout: Duration::from_secs(10),
+            max_restarts: 3,
+            capture_linger: None,
+            virtual_display: None,
+            display: None,
+            all_displays: false,
+            regions: Vec::new(),
+            include_windows: Vec::new(),
+            exclude_windows: Vec::new(),
+            exclude_apps: Vec::new(),
+            exclude_self: false,
+            exclude_dock: false,
+            exclude_menubar: false,
+            input_activity: None,
+            color_depth: ColorDepth::default(),
+            depth_adaptation: None,
+            refresh: RefreshRequest::default(),
+            control: CaptureControl::default(),
+            frame_skip: 1,
+            skipped_frames: FrameCounter::default(),
+            debug_checksums: false,
+            recording: None,
+            match_client_resolution: false,
+            #[cfg(feature = "shm")]
+            shm_name: None,
+        }
+    }
+}
+
+impl CaptureOptions {
+    /// Preset for laptops which trades fidelity for less heat and battery drain.
+    pub fn power_save() -> Self {
+        Self {
+            max_fps: Some(15),
+            scale: 0.5,
+            queue_depth: Some(3),
+            ..Default::default()
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -49,6 +440,13 @@ pub struct ScreenCapture {
     job_sender: mpsc::Sender<ScreenJob>,
     rdp_event_sender: Arc<RwLock<Option<mpsc::UnboundedSender<ServerEvent>>>>,
     screen_size: watch::Receiver<ScreenSize>,
+    capture_failed: watch::Receiver<bool>,
+    last_checksum: Option<Arc<AtomicU64>>,
+    audio_channels: u16,
+    /// Set once a session asks for display updates, which only happens
+    /// after the client logged in.
+    logged_in: Option<Arc<AtomicBool>>,
+    observer: Option<Arc<dyn ServerObserver>>,
 }
 
 struct ScreenCaptureContext {
@@ -57,7 +455,421 @@ struct ScreenCaptureContext {
     rdp_event_sender: Arc<RwLock<Option<mpsc::UnboundedSender<ServerEvent>>>>,
     capture_counter: IntervalCounter,
     send_counter: IntervalCounter,
-    stream: SCStream,
+    bitrate: BitrateCounter,
+    freeze: FreezeSwitch,
+    compositor: Option<Arc<Compositor>>,
+    /// Capture of each display, the first of which also captures audio.
+    streams: Vec<SCStream>,
+    /// Horizontal position of each stream's frames on the client's desktop.
+    stream_offsets: Vec<u16>,
+    options: CaptureOptions,
+    /// Depth the captured pixels are currently reduced to, at most
+    /// `options.color_depth`.
+    color_depth: SharedColorDepth,
+    /// When `color_depth` was last changed.
+    depth_changed: Instant,
+    /// Display output handlers attached to `streams`.
+    display_outputs: Vec<DisplayOutput>,
+    /// Audio output handler attached to the first stream.
+    sound_output: Option<ScreenOutputIndex>,
+    restart_attempts: u32,
+    capture_failed: watch::Sender<bool>,
+    capture_running: bool,
+    /// When the last display or audio output was removed.
+    idle_since: Option<Instant>,
+    /// Checksum of the last frame sent to a client, with `debug_checksums`.
+    last_checksum: Option<Arc<AtomicU64>>,
+    /// Display mode following the client, restored when the job loop ends.
+    /// macOS also restores it when the process exits.
+    display_mode: Option<DisplayModeSwitch>,
+    /// Whole display being captured, which is replaced by the main display
+    /// when it is disconnected.
+    followed_display: Option<CGDirectDisplayID>,
+    /// Resized to the client's resolution, and removed together with the
+    /// context when the job loop ends.
+    virtual_display: Option<VirtualDisplay>,
+}
+
+const WATCHDOG_PERIOD: Duration = Duration::from_secs(1);
+/// Frame rate cap unless configured otherwise, plenty for desktop use.
+const DEFAULT_MAX_FPS: u32 = 30;
+/// How long after the last client input the session counts as interactive.
+const INTERACTIVE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Tries to find a newly created virtual display in the shareable content.
+const VIRTUAL_DISPLAY_ATTEMPTS: u32 = 20;
+
+impl ScreenCaptureContext {
+    /// Starts the stream if it was stopped for being idle.
+    fn ensure_capture_running(&mut self) {
+        self.idle_since = None;
+        if self.capture_running || self.options.control.is_paused() {
+            return;
+        }
+        tracing::info!("Resuming idle capture");
+        for stream in &self.streams {
+            if let Err(e) = stream.start_capture() {
+                tracing::error!("Failed to resume capture - {e:?}");
+            }
+        }
+        self.capture_running = true;
+        // Give the resumed stream a full stall timeout
+        self.capture_counter.touch();
+    }
+
+    /// Starts the linger period once no display or audio output is left.
+    fn mark_idle_if_unused(&mut self) {
+        if self.display_outputs.is_empty() && self.sound_output.is_none() {
+            self.idle_since = Some(Instant::now());
+            self.check_capture_linger();
+        }
+    }
+
+    /// Stops the stream once no display or audio client was attached for
+    /// `capture_linger`.
+    fn check_capture_linger(&mut self) {
+        #[cfg(feature = "shm")]
+        if self.options.shm_name.is_some() {
+            return;
+        }
+        let (Some(linger), Some(idle_since)) = (self.options.capture_linger, self.idle_since)
+        else {
+            return;
+        };
+        // The first stream also carries the audio
+        if !self.capture_running || self.sound_output.is_some() || idle_since.elapsed() < linger {
+            return;
+        }
+
+        tracing::info!(?linger, "No client left, stopping capture");
+        for stream in &self.streams {
+            if let Err(e) = stream.stop_capture() {
+                tracing::warn!("Failed to stop idle capture - {e:?}");
+            }
+        }
+        self.capture_running = false;
+        self.idle_since = None;
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        if !paused {
+            tracing::info!("Resuming paused capture");
+            if !self.display_outputs.is_empty() || self.sound_output.is_some() {
+                self.ensure_capture_running();
+                self.options.refresh.request();
+            }
+            return;
+        }
+        if !self.capture_running {
+            return;
+        }
+        tracing::info!("Pausing capture");
+        for stream in &self.streams {
+            if let Err(e) = stream.stop_capture() {
+                tracing::warn!("Failed to pause capture - {e:?}");
+            }
+        }
+        self.capture_running = false;
+    }
+
+    /// Restarts the stream when no frame arrived for `stall_timeout` while a
+    /// client is connected, and flags capture as failed once restarts run out.
+    fn check_capture_stall(&mut self) {
+        if self.display_outputs.is_empty() || self.options.control.is_paused() {
+            self.restart_attempts = 0;
+            self.capture_failed
+                .send_if_modified(|failed| std::mem::take(failed));
+            return;
+        }
+
+        let since_last_frame = self.capture_counter.interval().since_last_seen();
+        if since_last_frame < self.options.stall_timeout {
+            if self.restart_attempts != 0 {
+                tracing::info!("Capture recovered");
+                self.restart_attempts = 0;
+                self.capture_failed
+                    .send_if_modified(|failed| std::mem::take(failed));
+            }
+            return;
+        }
+        if *self.capture_failed.borrow() {
+            return;
+        }
+
+        if self.restart_attempts >= self.options.max_restarts {
+            tracing::error!(
+                ?since_last_frame,
+                "Capture stalled after {} restarts, disconnecting clients",
+                self.restart_attempts
+            );
+            self.capture_failed.send_replace(true);
+            return;
+        }
+
+        self.restart_attempts += 1;
+        tracing::warn!(
+            ?since_last_frame,
+            attempt = self.restart_attempts,
+            "Capture stalled with a connected client, restarting stream"
+        );
+        for stream in &self.streams {
+            if let Err(e) = stream.stop_capture() {
+                tracing::warn!("Failed to stop stalled capture - {e:?}");
+            }
+            if let Err(e) = stream.start_capture() {
+                tracing::error!("Failed to restart capture - {e:?}");
+            }
+        }
+        // Give the restarted stream a full timeout before the next attempt
+        self.capture_counter.touch();
+    }
+
+    /// Switches the virtual display, or the display with
+    /// `match_client_resolution`, to the client's size, resizes the capture
+    /// to the new mode and remaps input to the new geometry.
+    fn match_client_resolution(&mut self, width: u16, height: u16) {
+        let geometry = if let Some(virtual_display) = &self.virtual_display {
+            if let Err(e) = virtual_display.set_size(width, height) {
+                tracing::error!(?e, "Failed to resize virtual display");
+                return;
+            }
+            // The bounds may not reflect the new mode yet.
+            DisplayGeometry {
+                size: (width as f64, height as f64),
+                scale_factor: 1.0,
+                ..DisplayGeometry::from_display_id(virtual_display.display_id())
+            }
+        } else {
+            let Some(display_mode) = &mut self.display_mode else {
+                return;
+            };
+            if !display_mode.switch_to(width, height) {
+                return;
+            }
+            DisplayGeometry::from_display_id(display_mode.display_id())
+        };
+        let size = (geometry.size.0 as u16, geometry.size.1 as u16);
+        let capture_size = match self.resize_capture(size) {
+            Ok(capture_size) => capture_size,
+            Err(e) => {
+                tracing::error!(?e, "Failed to resize capture to the new display mode");
+                self.display_size.borrow().capture
+            }
+        };
+        tracing::info!(?geometry, ?capture_size, "captured display");
+        self.display_size.send_modify(|screen_size| {
+            screen_size.server = size;
+            screen_size.capture = capture_size;
+            screen_size.layout = Arc::from([geometry]);
+        });
+    }
+
+    /// Scales the frames of the first stream to a display of `size`,
+    /// returning the capture size.
+    fn resize_capture(&mut self, size: (u16, u16)) -> Result<(u16, u16)> {
+        let capture_size = scaled_size(size, self.options.scale);
+        let config = with_size(stream_configuration(&self.options, true)?, capture_size)?;
+        self.streams[0]
+            .update_configuration(&config)
+            .map_err(|e| ArisuError::StreamConfiguration(format!("updateConfiguration - {e:?}")))?;
+        Ok(capture_size)
+    }
+
+    /// Follows the captured display when displays are connected,
+    /// disconnected, rearranged or change mode, falling back to the main
+    /// display once it is gone.
+    fn check_display_topology(&mut self) {
+        let Some(display_id) = self.followed_display else {
+            return;
+        };
+        if unsafe { CGDisplayIsOnline(display_id) } {
+            let geometry = DisplayGeometry::from_display_id(display_id);
+            let previous = self.display_size.borrow().layout[0];
+            if previous == geometry {
+                return;
+            }
+            let size = (geometry.size.0 as u16, geometry.size.1 as u16);
+            let capture_size = if previous.size == geometry.size {
+                self.display_size.borrow().capture
+            } else {
+                match self.resize_capture(size) {
+                    Ok(capture_size) => capture_size,
+                    Err(e) => {
+                        tracing::error!(?e, "Failed to resize capture to the changed display");
+                        self.display_size.borrow().capture
+                    }
+                }
+            };
+            tracing::info!(?geometry, ?capture_size, "Captured display changed");
+            self.display_size.send_modify(|screen_size| {
+                screen_size.server = size;
+                screen_size.capture = capture_size;
+                screen_size.layout = Arc::from([geometry]);
+            });
+            return;
+        }
+
+        let main_display_id = unsafe { CGMainDisplayID() };
+        tracing::warn!(
+            display_id,
+            main_display_id,
+            "Captured display disconnected, falling back to the main display"
+        );
+        if let Err(e) = self.capture_display(main_display_id) {
+            tracing::error!(
+                ?e,
+                "Failed to capture the main display, no longer following displays"
+            );
+            self.followed_display = None;
+        }
+    }
+
+    /// Points the stream at another display and resizes the capture to it.
+    fn capture_display(&mut self, display_id: CGDirectDisplayID) -> Result<()> {
+        let shareable_content = SCShareableContent::get()
+            .map_err(|e| ArisuError::ShareableContent(format!("{e:?}")))?;
+        let display = shareable_content
+            .displays()
+            .into_iter()
+            .find(|display| display.display_id() == display_id)
+            .ok_or(ArisuError::NoDisplay)?;
+        let filter = content_filter(
+            &self.options,
+            &display,
+            &shareable_content.windows(),
+            &shareable_content.applications(),
+        );
+        let size = (display.width() as u16, display.height() as u16);
+        self.streams[0]
+            .update_content_filter(&filter)
+            .map_err(|e| ArisuError::StreamConfiguration(format!("updateContentFilter - {e:?}")))?;
+        let capture_size = self.resize_capture(size)?;
+
+        self.followed_display = Some(display_id);
+        if self.display_mode.is_some() {
+            self.display_mode = DisplayModeSwitch::new(display_id);
+        }
+        let geometry = DisplayGeometry::from_display_id(display_id);
+        tracing::info!(?geometry, ?capture_size, "captured display");
+        self.display_size.send_modify(|screen_size| {
+            screen_size.server = size;
+            screen_size.capture = capture_size;
+            screen_size.layout = Arc::from([geometry]);
+        });
+        Ok(())
+    }
+}
+
+/// Stream settings shared by every captured display. Only one stream needs
+/// to capture audio.
+fn stream_configuration(
+    options: &CaptureOptions,
+    captures_audio: bool,
+) -> Result<SCStreamConfiguration> {
+    let mut config = SCStreamConfiguration::new()
+        .set_captures_audio(captures_audio)
+        .map_err(|e| ArisuError::StreamConfiguration(format!("setCapturesAudio - {e:?}")))?
+        // .set_sample_rate(sound::SAMPLE_RATE as _)
+        // .map_err(|e| ArisuError::StreamConfiguration(format!("setSampleRate - {e:?}")))?
+        .set_channel_count(options.audio_channels as _)
+        .map_err(|e| ArisuError::StreamConfiguration(format!("setChannelCount - {e:?}")))?
+        .set_pixel_format(options.pixel_format.stream_format())
+        .map_err(|e| ArisuError::StreamConfiguration(format!("setPixelFormat - {e:?}")))?
+        .set_shows_cursor(options.show_cursor)
+        .map_err(|e| ArisuError::StreamConfiguration(format!("setShowsCursor - {e:?}")))?;
+    if let Some(max_fps) = options.max_fps {
+        config = config
+            .set_minimum_frame_interval(&CMTime {
+                value: 1,
+                timescale: max_fps as _,
+                flags: 1,
+                epoch: 0,
+            })
+            .map_err(|e| {
+                ArisuError::StreamConfiguration(format!("setMinimumFrameInterval - {e:?}"))
+            })?;
+    }
+    if let Some(queue_depth) = options.queue_depth {
+        config = config
+            .set_queue_depth(queue_depth)
+            .map_err(|e| ArisuError::StreamConfiguration(format!("setQueueDepth - {e:?}")))?;
+    }
+    Ok(config)
+}
+
+/// What of `display` the stream captures, following the window and
+/// application options.
+fn content_filter(
+    options: &CaptureOptions,
+    display: &SCDisplay,
+    windows: &[SCWindow],
+    applications: &[SCRunningApplication],
+) -> SCContentFilter {
+    let mut excluded = window_filter::select(&options.exclude_windows, windows);
+    if options.exclude_dock {
+        excluded.extend(window_filter::system_windows(SystemUi::Dock, windows));
+    }
+    if options.exclude_menubar {
+        excluded.extend(window_filter::system_windows(SystemUi::MenuBar, windows));
+    }
+    if !options.include_windows.is_empty() {
+        let included = window_filter::select(&options.include_windows, windows)
+            .into_iter()
+            .filter(|window| {
+                !excluded
+                    .iter()
+                    .any(|excluded| excluded.window_id() == window.window_id())
+            })
+            .collect::<Vec<_>>();
+        tracing::info!(windows = included.len(), "Capturing included windows");
+        SCContentFilter::new().with_display_including_windows(display, &included)
+    } else if !options.exclude_apps.is_empty() || options.exclude_self {
+        let mut excluded_apps = window_filter::applications(&options.exclude_apps, applications);
+        // Excluding the application also covers windows opened after the
+        // stream started, such as the privacy screen
+        if options.exclude_self {
+            excluded_apps.extend(window_filter::own_application(applications));
+        }
+        if !excluded.is_empty() {
+            tracing::warn!("Excluded windows are ignored when applications are excluded");
+        }
+        tracing::info!(
+            applications = excluded_apps.len(),
+            "Excluding applications from capture"
+        );
+        SCContentFilter::new().with_display_excluding_applications_excepting_windows(
+            display,
+            &excluded_apps,
+            &[],
+        )
+    } else if !excluded.is_empty() {
+        tracing::info!(windows = excluded.len(), "Excluding windows from capture");
+        SCContentFilter::new().with_display_excluding_windows(display, &excluded)
+    } else {
+        SCContentFilter::new().with_display_excluding_applications_excepting_windows(
+            display,
+            &[],
+            &[],
+        )
+    }
+}
+
+fn scaled_size((width, height): (u16, u16), scale: f64) -> (u16, u16) {
+    (
+        (width as f64 * scale).round() as u16,
+        (height as f64 * scale).round() as u16,
+    )
+}
+
+/// Sets the size of the captured frames, which are scaled to it.
+fn with_size(
+    config: SCStreamConfiguration,
+    (width, height): (u16, u16),
+) -> Result<SCStreamConfiguration> {
+    config
+        .set_width(width as _)
+        .map_err(|e| ArisuError::StreamConfiguration(format!("setWidth - {e:?}")))?
+        .set_height(height as _)
+        .map_err(|e| ArisuError::StreamConfiguration(format!("setHeight - {e:?}")))
 }
 
 impl ScreenCapture {
@@ -65,66 +877,309 @@ impl ScreenCapture {
         main_thread_local_set: &LocalSet,
         capture_counter: IntervalCounter,
         display_send_counter: IntervalCounter,
-    ) -> anyhow::Result<(Self, JoinHandle<anyhow::Result<()>>)> {
-        let config = SCStreamConfiguration::new()
-            .set_captures_audio(true)
-            .map_err(|e| anyhow::anyhow!("Failed to setCapturesAudio - {e:?}"))?
-            // .set_sample_rate(sound::SAMPLE_RATE as _)
-            // .map_err(|e| anyhow::anyhow!("Failed to setSampleRate - {e:?}"))?
-            .set_channel_count(sound::CHANNELS as _)
-            .map_err(|e| anyhow::anyhow!("Failed to setChannelCount - {e:?}"))?
-            .set_pixel_format(PixelFormat::BGRA)
-            .map_err(|e| anyhow::anyhow!("Failed setPixelFormat - {e:?}"))?;
+        bitrate: BitrateCounter,
+        freeze: FreezeSwitch,
+        options: CaptureOptions,
+    ) -> Result<(Self, JoinHandle<Result<()>>)> {
+        if !unsafe { CGPreflightScreenCaptureAccess() } {
+            return Err(ArisuError::PermissionDenied);
+        }
+
         let screen_chnnal = mpsc::channel::<ScreenJob>(10);
-        let display = {
-            let shareable_content = SCShareableContent::get()
-                .map_err(|e| anyhow::anyhow!("Failed to get SCShareableContent - {e:?}"))?;
-            let mut displays = shareable_content.displays();
-            displays.swap_remove(0)
+        let virtual_display = options
+            .virtual_display
+            .map(|(width, height)| VirtualDisplay::create(width, height))
+            .transpose()?;
+        let (displays, windows, applications) = {
+            let mut attempts = 0;
+            loop {
+                let shareable_content = SCShareableContent::get()
+                    .map_err(|e| ArisuError::ShareableContent(format!("{e:?}")))?;
+                let mut displays = shareable_content.displays();
+                if options.all_displays && !displays.is_empty() {
+                    // Lay the displays out left to right as they are arranged.
+                    displays.sort_by(|a, b| {
+                        let a = unsafe { CGDisplayBounds(a.display_id()) }.origin;
+                        let b = unsafe { CGDisplayBounds(b.display_id()) }.origin;
+                        a.x.total_cmp(&b.x).then(a.y.total_cmp(&b.y))
+                    });
+                    break (
+                        displays,
+                        shareable_content.windows(),
+                        shareable_content.applications(),
+                    );
+                }
+                let index = match (&virtual_display, options.display) {
+                    (Some(virtual_display), _) => displays
+                        .iter()
+                        .position(|display| display.display_id() == virtual_display.display_id()),
+                    (None, None) => (!displays.is_empty()).then_some(0),
+                    (None, Some(selection)) => {
+                        let index = match selection {
+                            DisplaySelection::Index(index) => {
+                                (index < displays.len()).then_some(index)
+                            }
+                            DisplaySelection::Id(id) => displays
+                                .iter()
+                                .position(|display| display.display_id() == id),
+                        };
+                        if index.is_none() {
+                            let available = displays
+                                .iter()
+                                .enumerate()
+                                .map(|(index, display)| {
+                                    format!(
+                                        "{index}: id {} ({}x{})",
+                                        display.display_id(),
+                                        display.width(),
+                                        display.height()
+                                    )
+                                })
+                                .collect::<Vec<_>>()
+                                .join(", ");
+                            return Err(ArisuError::DisplayNotFound {
+                                selection,
+                                available,
+                            });
+                        }
+                        index
+                    }
+                };
+                if let Some(index) = index {
+                    break (
+                        vec![displays.swap_remove(index)],
+                        shareable_content.windows(),
+                        shareable_content.applications(),
+                    );
+                }
+                // A new virtual display shows up in the shareable content
+                // only after a moment.
+                if virtual_display.is_none() || attempts >= VIRTUAL_DISPLAY_ATTEMPTS {
+                    return Err(ArisuError::NoDisplay);
+                }
+                attempts += 1;
+                std::thread::sleep(Duration::from_millis(100));
+            }
         };
 
         let rdp_event_sender: Arc<RwLock<Option<mpsc::UnboundedSender<ServerEvent>>>> =
             Default::default();
 
-        let filter = SCContentFilter::new().with_display_excluding_applications_excepting_windows(
-            &display,
-            &[],
-            &[],
-        );
-        let width = display.width() as u16;
-        let height = display.height() as u16;
-        tracing::info!("screen initial size - width: {width}, height: {height}");
-        let (display_size, screen_size) = watch::channel(ScreenSize {
-            client: (width, height),
-            server: (width, height),
-        });
-        let stream = SCStream::new(&filter, &config);
-        stream
-            .start_capture()
-            .map_err(|e| anyhow::anyhow!("Failed to start capture - {e:?}"))?;
+        #[allow(unused_mut)]
+        let (mut streams, stream_offsets, compositor, display_mode, followed_display, screen_size) =
+            if options.all_displays {
+                let mut streams = Vec::new();
+                let mut stream_offsets = Vec::new();
+                let mut layout = Vec::new();
+                let mut server_size = (0, 0);
+                let mut canvas_size = (0, 0);
+                for (index, display) in displays.iter().enumerate() {
+                    let size = (display.width() as u16, display.height() as u16);
+                    let capture_size = scaled_size(size, options.scale);
+                    let config =
+                        with_size(stream_configuration(&options, index == 0)?, capture_size)?;
+                    streams.push(SCStream::new(
+                        &content_filter(&options, display, &windows, &applications),
+                        &config,
+                    ));
+                    stream_offsets.push(canvas_size.0);
+                    canvas_size = (
+                        canvas_size.0 + capture_size.0,
+                        canvas_size.1.max(capture_size.1),
+                    );
+                    server_size = (server_size.0 + size.0, server_size.1.max(size.1));
+                    // Every display is captured in points, so the canvas keeps
+                    // the proportions of the layout whatever their densities.
+                    let geometry = DisplayGeometry {
+                        scale_factor: 1.0,
+                        ..DisplayGeometry::from_display_id(display.display_id())
+                    };
+                    tracing::info!(?geometry, ?capture_size, "captured display");
+                    layout.push(geometry);
+                }
+                tracing::info!(
+                    displays = streams.len(),
+                    ?canvas_size,
+                    "Capturing all displays as one desktop"
+                );
+                if options.match_client_resolution {
+                    tracing::warn!(
+                        "Client resolution is only matched when capturing a single display"
+                    );
+                }
+                let screen_size = ScreenSize {
+                    client: canvas_size,
+                    server: server_size,
+                    capture: canvas_size,
+                    layout: layout.into(),
+                };
+                (streams, stream_offsets, None, None, None, screen_size)
+            } else {
+                let display = &displays[0];
+                let width = display.width() as u16;
+                let height = display.height() as u16;
+                tracing::info!("screen initial size - width: {width}, height: {height}");
+                let mut config = stream_configuration(&options, true)?;
+                let capture_size = if options.scale != 1.0 {
+                    let capture_size = scaled_size((width, height), options.scale);
+                    config = with_size(config, capture_size)?;
+                    tracing::info!(
+                        "capture size - width: {}, height: {}",
+                        capture_size.0,
+                        capture_size.1
+                    );
+                    capture_size
+                } else {
+                    (width, height)
+                };
+                let geometry = DisplayGeometry::from_display_id(display.display_id());
+                tracing::info!(?geometry, "captured display");
+                let (compositor, capture_size, layout) = if options.regions.is_empty() {
+                    (None, capture_size, vec![geometry])
+                } else {
+                    let compositor =
+                        Compositor::new(&options.regions, capture_size.0 as f64 / width as f64);
+                    let canvas_size = compositor.canvas_size();
+                    tracing::info!(regions = ?options.regions, ?canvas_size, "Compositing regions");
+                    (
+                        Some(Arc::new(compositor)),
+                        canvas_size,
+                        Compositor::layout(&options.regions, &geometry),
+                    )
+                };
+                // A virtual display follows the client's resolution anyway.
+                let display_mode = if !options.match_client_resolution || virtual_display.is_some()
+                {
+                    None
+                } else if compositor.is_some() {
+                    tracing::warn!(
+                        "Client resolution is only matched when capturing a whole display"
+                    );
+                    None
+                } else {
+                    DisplayModeSwitch::new(display.display_id())
+                };
+                let screen_size = ScreenSize {
+                    client: capture_size,
+                    server: (width, height),
+                    capture: capture_size,
+                    layout: layout.into(),
+                };
+                let stream = SCStream::new(
+                    &content_filter(&options, display, &windows, &applications),
+                    &config,
+                );
+                let followed_display = (virtual_display.is_none() && compositor.is_none())
+                    .then_some(display.display_id());
+                (
+                    vec![stream],
+                    vec![0],
+                    compositor,
+                    display_mode,
+                    followed_display,
+                    screen_size,
+                )
+            };
+        #[cfg(feature = "shm")]
+        let shared_frame = options
+            .shm_name
+            .as_ref()
+            .map(|name| {
+                let (width, height) = screen_size.capture;
+                shm::SharedFrame::create(name, width, height)
+                    .inspect(|_| tracing::info!(name, "Publishing frames to shared memory"))
+                    .map_err(|source| ArisuError::SharedMemory {
+                        name: name.clone(),
+                        source,
+                    })
+            })
+            .transpose()?;
+        let (display_size, screen_size) = watch::channel(screen_size);
+        for stream in &streams {
+            stream
+                .start_capture()
+                .map_err(|e| ArisuError::CaptureStart(format!("{e:?}")))?;
+        }
 
+        let (capture_failed, capture_failed_receiver) = watch::channel(false);
+        let last_checksum = options.debug_checksums.then(Arc::default);
+        let audio_channels = options.audio_channels;
+        options.control.attach(screen_chnnal.0.clone());
         let mut context = ScreenCaptureContext {
             job_sender: screen_chnnal.0.clone(),
             rdp_event_sender: rdp_event_sender.clone(),
             capture_counter,
             send_counter: display_send_counter,
+            bitrate,
+            freeze,
+            compositor,
             display_size,
-            stream,
+            streams,
+            stream_offsets,
+            color_depth: SharedColorDepth::new(options.color_depth),
+            depth_changed: Instant::now(),
+            options,
+            display_outputs: Vec::new(),
+            sound_output: None,
+            restart_attempts: 0,
+            capture_failed,
+            capture_running: true,
+            idle_since: None,
+            last_checksum: last_checksum.clone(),
+            display_mode,
+            followed_display,
+            virtual_display,
         };
+        #[cfg(feature = "shm")]
+        if let Some(frame) = shared_frame {
+            context.attach_shared_frame(frame);
+        }
         let handle = main_thread_local_set.spawn_local(async move {
             let mut job_receiver = screen_chnnal.1;
+            let mut watchdog = tokio::time::interval(WATCHDOG_PERIOD);
 
             tracing::info!("Display handling loop started");
 
-            while let Some(job) = job_receiver.recv().await {
-                tracing::debug!("Received display job");
-                match job {
-                    ScreenJob::Display(job) => context.handle_display_job(job),
-                    ScreenJob::Sound(job) => context.handle_sound_job(job),
+            let mut shutdown = None;
+            loop {
+                tokio::select! {
+                    job = job_receiver.recv() => {
+                        let Some(job) = job else {
+                            break;
+                        };
+                        tracing::debug!("Received display job");
+                        match job {
+                            ScreenJob::Display(job) => context.handle_display_job(job),
+                            ScreenJob::Sound(job) => context.handle_sound_job(job),
+                            ScreenJob::Pause(paused) => context.set_paused(paused),
+                            ScreenJob::Shutdown(reply) => {
+                                shutdown = Some(reply);
+                                break;
+                            }
+                        }
+                    }
+                    _ = watchdog.tick() => {
+                        context.remove_orphaned_outputs();
+                        context.adapt_color_depth();
+                        context.check_capture_stall();
+                        context.check_capture_linger();
+                        context.check_display_topology();
+                    }
                 }
             }
 
             tracing::info!("Display handler stopped");
+            if shutdown.is_some() && context.capture_running {
+                for stream in &context.streams {
+                    if let Err(e) = stream.stop_capture() {
+                        tracing::warn!("Failed to stop capture - {e:?}");
+                    }
+                }
+            }
+            drop(context);
+            if let Some(reply) = shutdown {
+                let _ = reply.send(());
+            }
 
             Ok(())
         });
@@ -134,12 +1189,50 @@ impl ScreenCapture {
                 job_sender: screen_chnnal.0,
                 rdp_event_sender,
                 screen_size,
+                capture_failed: capture_failed_receiver,
+                last_checksum,
+                audio_channels,
+                logged_in: None,
+                observer: None,
             },
             handle,
         ))
     }
 
-    pub fn input_handler(&self) -> InputHandler {
-        InputHandler::new(self.screen_size.clone())
+    /// Tells `observer` about the resolutions sessions ask for.
+    pub fn with_observer(self, observer: Option<Arc<dyn ServerObserver>>) -> Self {
+        Self { observer, ..self }
+    }
+
+    pub fn input_handler(
+        &self,
+        options: InputOptions,
+        audit: Option<SessionAudit>,
+    ) -> InputHandler {
+        InputHandler::new(self.screen_size.clone(), options, audit)
+    }
+
+    /// Whether the job processor is running and capture hasn't given up
+    /// restarting a stalled stream.
+    pub fn is_healthy(&self) -> bool {
+        !self.job_sender.is_closed() && !*self.capture_failed.borrow()
+    }
+
+    /// Display handler for a single session, with a flag which tells whether
+    /// the client got to log in.
+    pub fn for_session(&self) -> (Self, Arc<AtomicBool>) {
+        let logged_in = Arc::new(AtomicBool::new(false));
+        let screen = Self {
+            logged_in: Some(logged_in.clone()),
+            ..self.clone()
+        };
+        (screen, logged_in)
+    }
+
+    /// Checksum of the last frame sent to a client, when `debug_checksums`
+    /// is enabled and a frame was sent.
+    pub fn last_frame_checksum(&self) -> Option<u64> {
+        let checksum = self.last_checksum.as_ref()?.load(Ordering::Acquire);
+        (checksum != 0).then_some(checksum)
     }
 }
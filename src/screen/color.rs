@@ -0,0 +1,173 @@
+//! Reduces captured BGRA pixels to a lower color depth.
+//!
+//! ironrdp only takes 32-bit bitmaps and doesn't tell the display handler
+//! which depth the client negotiated, so pixels stay BGRA but keep only the
+//! precision of the configured depth. The bitmap codecs compress the coarser
+//! pixels much better, which is where the bandwidth is saved.
+
+use std::sync::{
+    atomic::{AtomicU8, Ordering},
+    Arc,
+};
+
+use strum::EnumString;
+
+/// Color depth of the pixels sent to clients, in bits per pixel, ordered
+/// from the lowest.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, EnumString)]
+pub enum ColorDepth {
+    /// RGB 555
+    #[strum(serialize = "15")]
+    Rgb555,
+    /// RGB 565
+    #[strum(serialize = "16")]
+    Rgb565,
+    /// RGB 888, alpha is dropped.
+    #[strum(serialize = "24")]
+    Rgb888,
+    /// BGRA 8888, as captured.
+    #[default]
+    #[strum(serialize = "32")]
+    Bgra8888,
+}
+
+impl ColorDepth {
+    const ALL: [Self; 4] = [Self::Rgb555, Self::Rgb565, Self::Rgb888, Self::Bgra8888];
+
+    /// Next depth which saves bandwidth. 24 bits saves nothing over 32 bits
+    /// as alpha is never sent.
+    pub(super) fn lower(self) -> Option<Self> {
+        match self {
+            Self::Bgra8888 | Self::Rgb888 => Some(Self::Rgb565),
+            Self::Rgb565 => Some(Self::Rgb555),
+            Self::Rgb555 => None,
+        }
+    }
+
+    /// Depth `lower` came from, up to `max`.
+    pub(super) fn higher(self, max: Self) -> Option<Self> {
+        let higher = match self {
+            Self::Rgb555 => Self::Rgb565,
+            Self::Rgb565 | Self::Rgb888 => Self::Bgra8888,
+            Self::Bgra8888 => return None,
+        };
+        (self < max).then_some(higher.min(max))
+    }
+
+    /// Reduces BGRA `data` to this depth in place.
+    pub(super) fn reduce(self, data: &mut [u8]) {
+        let reduce: fn(u8, u8, u8) -> [u8; 3] = match self {
+            Self::Bgra8888 => return,
+            Self::Rgb888 => |b, g, r| [b, g, r],
+            Self::Rgb565 => |b, g, r| unpack_rgb565(pack_rgb565(b, g, r)),
+            Self::Rgb555 => |b, g, r| unpack_rgb555(pack_rgb555(b, g, r)),
+        };
+        for pixel in data.chunks_exact_mut(4) {
+            let [b, g, r] = reduce(pixel[0], pixel[1], pixel[2]);
+            pixel.copy_from_slice(&[b, g, r, 0xFF]);
+        }
+    }
+}
+
+/// Depth the captured pixels are reduced to, changed while capture runs.
+#[derive(Debug, Clone)]
+pub(super) struct SharedColorDepth(Arc<AtomicU8>);
+
+impl SharedColorDepth {
+    pub(super) fn new(depth: ColorDepth) -> Self {
+        Self(Arc::new(AtomicU8::new(depth as u8)))
+    }
+
+    pub(super) fn get(&self) -> ColorDepth {
+        ColorDepth::ALL[self.0.load(Ordering::Relaxed) as usize]
+    }
+
+    pub(super) fn set(&self, depth: ColorDepth) {
+        self.0.store(depth as u8, Ordering::Relaxed);
+    }
+}
+
+fn pack_rgb565(b: u8, g: u8, r: u8) -> u16 {
+    (r as u16 >> 3) << 11 | (g as u16 >> 2) << 5 | b as u16 >> 3
+}
+
+/// Widens the channels back to 8 bits by repeating their high bits, so full
+/// intensity stays 0xFF.
+fn unpack_rgb565(pixel: u16) -> [u8; 3] {
+    let r = (pixel >> 11) as u8 & 0x1F;
+    let g = (pixel >> 5) as u8 & 0x3F;
+    let b = pixel as u8 & 0x1F;
+    [b << 3 | b >> 2, g << 2 | g >> 4, r << 3 | r >> 2]
+}
+
+fn pack_rgb555(b: u8, g: u8, r: u8) -> u16 {
+    (r as u16 >> 3) << 10 | (g as u16 >> 3) << 5 | b as u16 >> 3
+}
+
+fn unpack_rgb555(pixel: u16) -> [u8; 3] {
+    let r = (pixel >> 10) as u8 & 0x1F;
+    let g = (pixel >> 5) as u8 & 0x1F;
+    let b = pixel as u8 & 0x1F;
+    [b << 3 | b >> 2, g << 3 | g >> 2, r << 3 | r >> 2]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack_rgb565_places_channels() {
+        assert_eq!(pack_rgb565(0x00, 0x00, 0x00), 0x0000);
+        assert_eq!(pack_rgb565(0xFF, 0xFF, 0xFF), 0xFFFF);
+        assert_eq!(pack_rgb565(0x00, 0x00, 0xFF), 0xF800);
+        assert_eq!(pack_rgb565(0x00, 0xFF, 0x00), 0x07E0);
+        assert_eq!(pack_rgb565(0xFF, 0x00, 0x00), 0x001F);
+        assert_eq!(pack_rgb565(0x56, 0x34, 0x12), 0x11AA);
+    }
+
+    #[test]
+    fn unpack_rgb565_keeps_full_intensity() {
+        assert_eq!(unpack_rgb565(0x0000), [0x00, 0x00, 0x00]);
+        assert_eq!(unpack_rgb565(0xFFFF), [0xFF, 0xFF, 0xFF]);
+        assert_eq!(unpack_rgb565(0xF800), [0x00, 0x00, 0xFF]);
+    }
+
+    #[test]
+    fn rgb565_round_trip_is_stable() {
+        for value in 0..=u16::MAX {
+            let [b, g, r] = unpack_rgb565(value);
+            assert_eq!(pack_rgb565(b, g, r), value);
+        }
+    }
+
+    #[test]
+    fn reduce_to_16_bits_drops_low_bits() {
+        let mut data = [0x56, 0x34, 0x12, 0x00, 0xFF, 0xFF, 0xFF, 0x80];
+        ColorDepth::Rgb565.reduce(&mut data);
+        assert_eq!(data, [0x52, 0x34, 0x10, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn depth_steps_stay_below_the_maximum() {
+        assert_eq!(ColorDepth::Bgra8888.lower(), Some(ColorDepth::Rgb565));
+        assert_eq!(ColorDepth::Rgb555.lower(), None);
+        assert_eq!(
+            ColorDepth::Rgb555.higher(ColorDepth::Bgra8888),
+            Some(ColorDepth::Rgb565)
+        );
+        assert_eq!(
+            ColorDepth::Rgb565.higher(ColorDepth::Rgb888),
+            Some(ColorDepth::Rgb888)
+        );
+        assert_eq!(ColorDepth::Rgb565.higher(ColorDepth::Rgb565), None);
+        let depth = SharedColorDepth::new(ColorDepth::Rgb888);
+        assert_eq!(depth.get(), ColorDepth::Rgb888);
+    }
+
+    #[test]
+    fn reduce_to_32_bits_is_a_no_op() {
+        let mut data = [0x56, 0x34, 0x12, 0x00];
+        ColorDepth::Bgra8888.reduce(&mut data);
+        assert_eq!(data, [0x56, 0x34, 0x12, 0x00]);
+    }
+}
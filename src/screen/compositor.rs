@@ -0,0 +1,138 @@
+//! Lays several regions of the captured display out side by side on one
+//! canvas, so unrelated parts of the screen can be shown together.
+
+use screencapturekit::output::CVPixelBuffer;
+
+use super::{
+    display::{CapturedData, Rect},
+    DisplayGeometry,
+};
+
+/// Rectangle on the captured display in points, written as `x,y,width,height`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl std::str::FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid region {s:?} - {e}"))?;
+        let [x, y, width, height] = values[..] else {
+            return Err(format!("invalid region {s:?}, expected x,y,width,height"));
+        };
+        if x < 0.0 || y < 0.0 || width <= 0.0 || height <= 0.0 {
+            return Err(format!("invalid region {s:?}"));
+        }
+        Ok(Self {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+}
+
+/// Placement of a region, in pixels of the captured frame and of the canvas.
+#[derive(Debug)]
+struct Slot {
+    src_x: usize,
+    src_y: usize,
+    width: usize,
+    height: usize,
+    dst_x: usize,
+}
+
+#[derive(Debug)]
+pub(super) struct Compositor {
+    slots: Vec<Slot>,
+    canvas: (usize, usize),
+}
+
+impl Compositor {
+    /// `pixels_per_point` converts display points into captured frame pixels.
+    pub(super) fn new(regions: &[Region], pixels_per_point: f64) -> Self {
+        let mut dst_x = 0;
+        let mut canvas_height = 0;
+        let slots = regions
+            .iter()
+            .map(|region| {
+                let slot = Slot {
+                    src_x: (region.x * pixels_per_point).round() as usize,
+                    src_y: (region.y * pixels_per_point).round() as usize,
+                    width: (region.width * pixels_per_point).round() as usize,
+                    height: (region.height * pixels_per_point).round() as usize,
+                    dst_x,
+                };
+                dst_x += slot.width;
+                canvas_height = canvas_height.max(slot.height);
+                slot
+            })
+            .collect();
+
+        Self {
+            slots,
+            canvas: (dst_x, canvas_height),
+        }
+    }
+
+    pub(super) fn canvas_size(&self) -> (u16, u16) {
+        (self.canvas.0 as u16, self.canvas.1 as u16)
+    }
+
+    /// Where each region sits in the global display space, in canvas order.
+    pub(super) fn layout(regions: &[Region], display: &DisplayGeometry) -> Vec<DisplayGeometry> {
+        regions
+            .iter()
+            .map(|region| DisplayGeometry {
+                origin: (display.origin.0 + region.x, display.origin.1 + region.y),
+                size: (region.width, region.height),
+                scale_factor: display.scale_factor,
+            })
+            .collect()
+    }
+
+    /// Renders the whole canvas from a captured frame. Parts of a region
+    /// outside of the frame, and the space below shorter regions, stay black.
+    pub(super) fn compose(&self, input: &CVPixelBuffer, output: &mut CapturedData) -> bool {
+        let (canvas_width, canvas_height) = self.canvas;
+        let input_width = input.get_width() as usize;
+        let input_height = input.get_height() as usize;
+
+        output.data.clear();
+        output.data.resize(canvas_width * canvas_height * 4, 0);
+        let composed = super::display::with_pixels(input, |base_address, bytes_per_row| {
+            for slot in &self.slots {
+                let width = slot.width.min(input_width.saturating_sub(slot.src_x));
+                let height = slot.height.min(input_height.saturating_sub(slot.src_y));
+                for row in 0..height {
+                    let src = unsafe {
+                        base_address.add((slot.src_y + row) * bytes_per_row + slot.src_x * 4)
+                    };
+                    let dst =
+                        &mut output.data[(row * canvas_width + slot.dst_x) * 4..][..width * 4];
+                    unsafe { std::ptr::copy_nonoverlapping(src, dst.as_mut_ptr(), width * 4) };
+                }
+            }
+        });
+        if composed.is_none() {
+            return false;
+        }
+
+        output.rects = vec![Rect {
+            x: 0,
+            y: 0,
+            width: canvas_width as _,
+            height: canvas_height as _,
+        }];
+        true
+    }
+}
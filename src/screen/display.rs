@@ -6,82 +6,570 @@ use ironrdp::server::{
 use screencapturekit::{
     output::{
         sc_stream_frame_info::{SCFrameStatus, SCStreamFrameInfo},
-        CVPixelBuffer, LockTrait,
+        CMSampleBuffer, CVPixelBuffer, LockTrait,
     },
     stream::{output_trait::SCStreamOutputTrait, output_type::SCStreamOutputType},
 };
-use std::{cell::RefCell, num::NonZeroU16, sync::Arc};
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    num::NonZeroU16,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError, Weak,
+    },
+    time::{Duration, Instant},
+};
 use tokio::sync::{mpsc, oneshot, watch, Notify};
 
-use crate::{counter::IntervalCounter, screen::ScreenJob};
+use crate::{
+    counter::{BitrateCounter, FrameCounter, Interval, IntervalCounter},
+    recording::{Recording, StreamRecording},
+    screen::ScreenJob,
+};
 
-use super::{ScreenOutputIndex, ScreenSize};
+use super::{
+    color::SharedColorDepth,
+    compositor::Compositor,
+    format::{self, BufferFormat, Plane},
+    ColorDepth, FreezeSwitch, RefreshRequest, ScreenOutputIndex, ScreenSize,
+};
 
 pub(super) enum Job {
     GetSize(oneshot::Sender<(u16, u16)>),
     SetSize(u16, u16),
-    CaptureStart(oneshot::Sender<anyhow::Result<DisplayUpdates>>),
-    CaptureStop(ScreenOutputIndex),
+    CaptureStart(oneshot::Sender<DisplayUpdates>),
+    /// Adds the output handler of the `DisplayUpdates` owning the token.
+    CaptureAttach(
+        DisplayCaptureDelegate,
+        Weak<()>,
+        oneshot::Sender<anyhow::Result<ScreenOutputIndex>>,
+    ),
+    /// Removes the output handler from the stream with the given index.
+    CaptureStop(usize, ScreenOutputIndex),
+}
+
+/// Dirty rects beyond which a frame is sent as their bounding box, since
+/// every bitmap update has its own overhead.
+const MAX_DIRTY_RECTS: usize = 16;
+
+/// Time the averaged intervals get to reflect a color depth change before
+/// the next one.
+const DEPTH_ADAPTATION_PERIOD: Duration = Duration::from_secs(3);
+/// How long a session may go without asking for display updates before it
+/// is logged as audio-only.
+const AUDIO_ONLY_GRACE: Duration = Duration::from_secs(10);
+/// Which reasons to skip frames for their pixel format were reported by a
+/// delegate. Each is reported once, as every frame after them is skipped too.
+#[derive(Debug, Default)]
+struct SkippedFormats {
+    /// The pixel format isn't supported.
+    unsupported: Cell<bool>,
+    /// Regions can't be composited from YCbCr frames.
+    not_composited: Cell<bool>,
 }
 
-#[derive(Debug, Clone)]
-struct CapturedData {
-    x: u16,
-    y: u16,
+/// Thresholds of the send interval relative to the capture interval. Above
+/// `lag_ratio` clients are sent frames slower than they are captured, so the
+/// link is the bottleneck and the color depth is lowered. Below
+/// `recover_ratio` it is raised back, up to the configured depth.
+///
+/// The send interval is shared by every session, so with several clients
+/// only one falling behind doesn't lower the depth.
+#[derive(Debug, Clone, Copy)]
+pub struct DepthAdaptation {
+    pub lag_ratio: f64,
+    pub recover_ratio: f64,
+}
+
+impl DepthAdaptation {
+    /// Depth to use after `current` given the intervals measured with it.
+    fn adapt(
+        &self,
+        current: ColorDepth,
+        max: ColorDepth,
+        capture_interval: Duration,
+        send_interval: Duration,
+    ) -> ColorDepth {
+        let ratio = send_interval.as_secs_f64() / capture_interval.as_secs_f64().max(f64::EPSILON);
+        if ratio > self.lag_ratio {
+            current.lower().unwrap_or(current)
+        } else if ratio < self.recover_ratio {
+            current.higher(max).unwrap_or(current)
+        } else {
+            current
+        }
+    }
+}
+
+/// Rect of a captured frame, in pixels.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(super) struct Rect {
+    pub(super) x: u16,
+    pub(super) y: u16,
+    pub(super) width: u16,
+    pub(super) height: u16,
+}
+
+impl Rect {
+    fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    fn intersects(&self, other: &Self) -> bool {
+        self.x < other.x + other.width
+            && other.x < self.x + self.width
+            && self.y < other.y + other.height
+            && other.y < self.y + self.height
+    }
+
+    fn union(self, other: Self) -> Self {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+        Self {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub(super) struct CapturedData {
+    /// Presentation time, see [`super::presentation_millis`].
+    pub(super) timestamp: u64,
+    /// Changed rects, whose pixels are packed one after another in `data`.
+    pub(super) rects: Vec<Rect>,
+    pub(super) data: Vec<u8>,
+}
+
+impl CapturedData {
+    /// Each rect with its pixels.
+    pub(super) fn rect_data(&self) -> impl Iterator<Item = (Rect, &[u8])> {
+        let mut offset = 0;
+        self.rects.iter().map(move |rect| {
+            let len = rect.width as usize * rect.height as usize * 4;
+            let data = self.data.get(offset..offset + len).unwrap_or_default();
+            offset += len;
+            (*rect, data)
+        })
+    }
+}
+
+/// [`PublishedFrame`] shared by the capture delegate drawing it and the
+/// session re-sending it.
+pub(super) type SharedPublishedFrame = Arc<Mutex<PublishedFrame>>;
+
+fn lock_published(published: &SharedPublishedFrame) -> MutexGuard<'_, PublishedFrame> {
+    published.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Whole frame of a display as last published, with the rects of every
+/// published frame drawn over it.
+#[derive(Debug, Default)]
+pub(super) struct PublishedFrame {
     width: u16,
     height: u16,
+    timestamp: u64,
     data: Vec<u8>,
+    /// Whether a whole frame was drawn since the size last changed.
+    whole: bool,
+}
+
+impl PublishedFrame {
+    /// Draws the rects of `frame`, relative to the display. A `whole` frame
+    /// has one rect covering the display and sets the size.
+    fn draw(&mut self, frame: &CapturedData, whole: bool) {
+        if whole {
+            let Some(rect) = frame.rects.first() else {
+                return;
+            };
+            self.width = rect.width;
+            self.height = rect.height;
+            self.data.clone_from(&frame.data);
+            self.whole = true;
+        } else {
+            let stride = self.width as usize * 4;
+            for (rect, data) in frame.rect_data() {
+                if rect.x + rect.width > self.width || rect.y + rect.height > self.height {
+                    continue;
+                }
+                let row_len = rect.width as usize * 4;
+                for (row, pixels) in data.chunks_exact(row_len).enumerate() {
+                    let start = (rect.y as usize + row) * stride + rect.x as usize * 4;
+                    self.data[start..start + row_len].copy_from_slice(pixels);
+                }
+            }
+        }
+        self.timestamp = frame.timestamp;
+    }
+
+    fn is_whole(&self) -> bool {
+        self.whole
+    }
+
+    /// Forgets the frame until the next whole one, e.g. once the display
+    /// was resized.
+    fn invalidate(&mut self) {
+        self.whole = false;
+    }
+
+    /// Replaces the rects of `frame` with `rects` of this frame, clipped to
+    /// it. Leaves `frame` as is until a whole frame was drawn.
+    fn copy_rects(&self, rects: &[Rect], frame: &mut CapturedData) {
+        if !self.whole {
+            return;
+        }
+        let stride = self.width as usize * 4;
+        frame.rects.clear();
+        frame.data.clear();
+        for rect in rects {
+            let width = rect.width.min(self.width.saturating_sub(rect.x));
+            let height = rect.height.min(self.height.saturating_sub(rect.y));
+            if width == 0 || height == 0 {
+                continue;
+            }
+            for row in rect.y as usize..(rect.y + height) as usize {
+                let start = row * stride + rect.x as usize * 4;
+                frame
+                    .data
+                    .extend_from_slice(&self.data[start..start + width as usize * 4]);
+            }
+            frame.rects.push(Rect {
+                width,
+                height,
+                ..*rect
+            });
+        }
+    }
+
+    /// The frame as one rect covering the display, once a whole frame was
+    /// drawn.
+    pub(super) fn to_captured(&self) -> Option<CapturedData> {
+        self.whole.then(|| CapturedData {
+            timestamp: self.timestamp,
+            rects: vec![Rect {
+                x: 0,
+                y: 0,
+                width: self.width,
+                height: self.height,
+            }],
+            data: self.data.clone(),
+        })
+    }
 }
 
 pub(super) struct DisplayUpdates {
-    index: ScreenOutputIndex,
+    /// Output handlers attached so far, with the stream they are attached to.
+    indices: Vec<(usize, ScreenOutputIndex)>,
+    /// Whether the output handlers of every stream were attached.
+    attached: bool,
+    /// Dropped once display updates are requested, see `watch_audio_only`.
+    display_requested: Option<oneshot::Sender<()>>,
+    pending_delegates: Vec<DisplayCaptureDelegate>,
+    attach_receivers: Vec<(usize, oneshot::Receiver<anyhow::Result<ScreenOutputIndex>>)>,
     display_sender: mpsc::Sender<ScreenJob>,
-    capture_receiver: triple_buffer::Output<CapturedData>,
+    /// Frames of each captured display.
+    capture_receivers: Vec<triple_buffer::Output<CapturedData>>,
     display_size: watch::Receiver<ScreenSize>,
+    /// Desktop size the client was last given.
+    desktop_size: (u16, u16),
     update_notification: Arc<Notify>,
     send_counter: IntervalCounter,
+    bitrate: BitrateCounter,
+    capture_failed: watch::Receiver<bool>,
+    frame_skip: u32,
+    skipped_frames: FrameCounter,
+    refresh: RefreshRequest,
+    /// Refresh requests handled so far.
+    refresh_seen: u64,
+    /// Frame each stream last published, with its horizontal offset, to
+    /// re-send on a refresh.
+    published: Vec<(u16, SharedPublishedFrame)>,
+    last_checksum: Option<Arc<AtomicU64>>,
+    /// Updates for the remaining rects of the last frame.
+    pending: VecDeque<BitmapUpdate>,
+    /// Tee of the updates sent, with `--record`.
+    recording: Option<StreamRecording>,
+    /// Format of the bitmap updates.
+    format: ironrdp::server::PixelFormat,
+    /// Dropped together with the updates, after which the capture context
+    /// removes any of their output handlers still attached.
+    owner: Arc<()>,
+}
+
+/// Display output handler attached to a stream.
+pub(super) struct DisplayOutput {
+    stream: usize,
+    index: ScreenOutputIndex,
+    owner: Weak<()>,
 }
 
 impl Drop for DisplayUpdates {
     fn drop(&mut self) {
-        let _ = self
-            .display_sender
-            .try_send(ScreenJob::Display(Job::CaptureStop(self.index)));
+        for (stream, mut receiver) in self.attach_receivers.drain(..) {
+            if let Ok(Ok(index)) = receiver.try_recv() {
+                self.indices.push((stream, index));
+            }
+        }
+        if self.indices.is_empty() {
+            tracing::info!("Session ended without requesting display updates (audio-only)");
+            return;
+        }
+        for (stream, index) in self.indices.drain(..) {
+            if let Err(e) = self
+                .display_sender
+                .try_send(ScreenJob::Display(Job::CaptureStop(stream, index)))
+            {
+                tracing::warn!(
+                    ?e,
+                    "Failed to stop display capture, leaving it to the watchdog"
+                );
+            }
+        }
+    }
+}
+
+impl DisplayUpdates {
+    /// Registers the capture output handler on first use so that sessions
+    /// which never ask for a frame don't pay for video conversion.
+    ///
+    /// Cancel safe: the pending reply is kept until it resolves.
+    async fn attach(&mut self) -> anyhow::Result<()> {
+        self.display_requested = None;
+        for delegate in std::mem::take(&mut self.pending_delegates) {
+            let stream = delegate.stream;
+            let (sender, receiver) = oneshot::channel();
+            self.display_sender
+                .try_send(ScreenJob::Display(Job::CaptureAttach(
+                    delegate,
+                    Arc::downgrade(&self.owner),
+                    sender,
+                )))
+                .map_err(|_| anyhow::anyhow!("Failed to send display job to main thread"))?;
+            self.attach_receivers.push((stream, receiver));
+        }
+        while let Some((stream, receiver)) = self.attach_receivers.first_mut() {
+            let stream = *stream;
+            let index = receiver.await;
+            self.attach_receivers.remove(0);
+            self.indices.push((stream, index??));
+        }
+        self.attached = true;
+        tracing::info!("Display capture started");
+
+        Ok(())
     }
 }
 
 #[async_trait::async_trait]
 impl RdpServerDisplayUpdates for DisplayUpdates {
     async fn next_update(&mut self) -> Option<DisplayUpdate> {
-        self.update_notification.notified().await;
-        self.capture_receiver.update();
-        let CapturedData {
-            x,
-            y,
+        if !self.attached {
+            if let Err(e) = self.attach().await {
+                tracing::error!(?e, "Failed to start display capture");
+                return None;
+            }
+        }
+        loop {
+            if let Some(size) = self.resized() {
+                return Some(DisplayUpdate::Resize(size));
+            }
+            if self.refresh.take(&mut self.refresh_seen) {
+                tracing::info!("Re-sending the last frame for a refresh request");
+                self.republish();
+            }
+            if let Some(update) = self.pending.pop_front() {
+                return Some(DisplayUpdate::Bitmap(update));
+            }
+            self.wait_for_frame().await?;
+        }
+    }
+}
+
+impl DisplayUpdates {
+    /// New desktop size once the capture was resized, e.g. after falling
+    /// back to another display. Pending updates are dropped and the next
+    /// frame is captured whole instead.
+    fn resized(&mut self) -> Option<DesktopSize> {
+        let capture_size = self.display_size.borrow().capture;
+        if capture_size == self.desktop_size {
+            return None;
+        }
+        tracing::info!(?capture_size, "Resizing client desktop");
+        self.desktop_size = capture_size;
+        if let Some(recording) = &self.recording {
+            recording.desktop_size(capture_size.0, capture_size.1);
+        }
+        self.pending.clear();
+        for (_, published) in &self.published {
+            lock_published(published).invalidate();
+        }
+        Some(DesktopSize {
+            width: capture_size.0,
+            height: capture_size.1,
+        })
+    }
+
+    /// Queues the frames last published whole, in place of pending updates.
+    fn republish(&mut self) {
+        self.pending.clear();
+        for (offset, published) in &self.published {
+            let Some(mut frame) = lock_published(published).to_captured() else {
+                continue;
+            };
+            for rect in &mut frame.rects {
+                rect.x += offset;
+            }
+            self.bitrate.record(frame.data.len());
+            queue_updates(
+                &frame,
+                self.format,
+                self.recording.as_ref(),
+                &mut self.pending,
+            );
+        }
+    }
+
+    /// Waits for the next frame to present and queues an update for each of
+    /// its rects, or for the whole frames when frames were skipped. Returns
+    /// `None` once capture failed.
+    async fn wait_for_frame(&mut self) -> Option<()> {
+        // A frame published before the client asked for this update means
+        // it is falling behind the capture.
+        let behind = self
+            .capture_receivers
+            .iter()
+            .any(triple_buffer::Output::updated);
+        let mut skipped = 0;
+        // Without skipping, the frame published meanwhile is presented now
+        while !behind || skipped + 1 < self.frame_skip {
+            tokio::select! {
+                _ = self.update_notification.notified() => {}
+                // Handled by the caller without waiting for a capture
+                _ = self.refresh.requested(self.refresh_seen) => return Some(()),
+                _ = self.capture_failed.wait_for(|failed| *failed) => {
+                    tracing::error!("Capture failed, ending display updates");
+                    return None;
+                }
+            }
+            if !behind {
+                break;
+            }
+            skipped += 1;
+        }
+        if skipped > 0 {
+            tracing::debug!(skipped, "Client falling behind, skipped frames");
+            self.skipped_frames.add(skipped.into());
+            // Regions changed in the skipped frames were never sent, while
+            // the published frames have them.
+            for capture_receiver in &mut self.capture_receivers {
+                capture_receiver.update();
+            }
+            self.send_counter.update();
+            self.republish();
+            return Some(());
+        }
+        // Frames captured meanwhile replace the pending one, so holding back
+        // lowers the frame rate instead of queueing stale frames. They carry
+        // the rects of the frames they replace, so the latest one has the
+        // union of the regions changed while throttled.
+        loop {
+            let delay = self.bitrate.throttle_delay();
+            if delay.is_zero() {
+                break;
+            }
+            tracing::trace!(?delay, "Throttling display update");
+            tokio::time::sleep(delay).await;
+        }
+        self.send_counter.update();
+        for capture_receiver in &mut self.capture_receivers {
+            if !capture_receiver.update() {
+                continue;
+            }
+            let frame = capture_receiver.peek_output_buffer();
+            tracing::trace!(
+                timestamp = frame.timestamp,
+                "Received display update: {:?}, buffer size: {}, {}, {:?}",
+                frame.rects,
+                frame.data.len(),
+                if frame.data.iter().all(|&b| b == 0) {
+                    "black"
+                } else {
+                    "data"
+                },
+                frame.data.as_ptr()
+            );
+            if let Some(last_checksum) = &self.last_checksum {
+                let checksum = frame_checksum(&frame.data);
+                last_checksum.store(checksum, Ordering::Release);
+                tracing::info!(
+                    timestamp = frame.timestamp,
+                    rects = ?frame.rects,
+                    checksum = %format_args!("{checksum:016x}"),
+                    "Sent frame"
+                );
+            }
+            self.bitrate.record(frame.data.len());
+            queue_updates(
+                frame,
+                self.format,
+                self.recording.as_ref(),
+                &mut self.pending,
+            );
+        }
+        Some(())
+    }
+}
+
+/// Logs the session as audio-only unless the returned sender is dropped,
+/// because it asked for display updates or ended, within
+/// [`AUDIO_ONLY_GRACE`].
+fn watch_audio_only() -> oneshot::Sender<()> {
+    let (sender, receiver) = oneshot::channel();
+    tokio::task::spawn_local(async move {
+        if tokio::time::timeout(AUDIO_ONLY_GRACE, receiver)
+            .await
+            .is_err()
+        {
+            tracing::info!("Session has not requested display updates, running audio-only");
+        }
+    });
+    sender
+}
+
+/// Queues an update for each rect of `frame`, recording it with `--record`.
+fn queue_updates(
+    frame: &CapturedData,
+    format: ironrdp::server::PixelFormat,
+    recording: Option<&StreamRecording>,
+    pending: &mut VecDeque<BitmapUpdate>,
+) {
+    for (rect, data) in frame.rect_data() {
+        let (Some(width), Some(height)) =
+            (NonZeroU16::new(rect.width), NonZeroU16::new(rect.height))
+        else {
+            tracing::debug!(?rect, "Skipping empty rect");
+            continue;
+        };
+        // Owned, since the session may hold the update past the next swap of
+        // the triple buffer.
+        let data = Bytes::copy_from_slice(data);
+        if let Some(recording) = recording {
+            recording.bitmap(rect.x, rect.y, rect.width, rect.height, data.clone());
+        }
+        pending.push_back(BitmapUpdate {
+            x: rect.x,
+            y: rect.y,
             width,
             height,
-            data: buffer,
-        } = self.capture_receiver.peek_output_buffer();
-        tracing::trace!(
-            "Received display update: ({x}, {y}) {width} x {height}, buffer size: {}, {}, {:?}",
-            buffer.len(),
-            if buffer.iter().all(|&b| b == 0) {
-                "black"
-            } else {
-                "data"
-            },
-            buffer.as_ptr()
-        );
-        self.send_counter.update();
-        Some(DisplayUpdate::Bitmap(BitmapUpdate {
-            x: *x,
-            y: *y,
-            width: unsafe { NonZeroU16::new_unchecked(*width as u16) },
-            height: unsafe { NonZeroU16::new_unchecked(*height as u16) },
-            format: ironrdp::server::PixelFormat::BgrA32,
-            data: Bytes::from_static(unsafe { &*(buffer.as_slice() as *const [u8]) }),
-            stride: (4 * width) as usize,
-        }))
+            format,
+            data,
+            stride: rect.width as usize * 4,
+        });
     }
 }
 
@@ -104,12 +592,15 @@ impl RdpServerDisplay for super::ScreenCapture {
     }
 
     async fn updates(&mut self) -> anyhow::Result<Box<dyn RdpServerDisplayUpdates>> {
+        if let Some(logged_in) = &self.logged_in {
+            logged_in.store(true, Ordering::Release);
+        }
         let (sender, receiver) = oneshot::channel();
         self.job_sender
             .send(ScreenJob::Display(Job::CaptureStart(sender)))
             .await?;
         tracing::info!("Starting capture requested");
-        let received = receiver.await??;
+        let received = receiver.await?;
 
         Ok(Box::new(received))
     }
@@ -123,30 +614,123 @@ impl RdpServerDisplay for super::ScreenCapture {
             let device_scale_factor = layout.device_scale_factor();
             let desktop_scale_factor = layout.desktop_scale_factor();
             tracing::info!(?width, ?height, ?device_scale_factor, ?desktop_scale_factor);
+            let Some((width, height)) = client_size(width, height) else {
+                tracing::warn!(width, height, "Ignoring invalid monitor size from client");
+                continue;
+            };
             if let Err(e) = self
                 .job_sender
-                .try_send(ScreenJob::Display(Job::SetSize(width as _, height as _)))
+                .try_send(ScreenJob::Display(Job::SetSize(width, height)))
             {
                 tracing::error!("Failed to send display size job: {e:?}");
             }
+            if let Some(observer) = &self.observer {
+                observer.on_resolution_changed(width, height);
+            }
         }
     }
 }
 
-fn convert_buffer(
-    x: usize,
-    y: usize,
-    width: usize,
-    height: usize,
+/// 64-bit FNV-1a of the pixels, which a test client can compute over what it
+/// received to check that no pixel was altered on the way.
+fn frame_checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Hash of a rect's pixels, a word at a time so hashing every captured frame
+/// stays cheap.
+fn content_hash(data: &[u8]) -> u64 {
+    const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+    let mut words = data.chunks_exact(8);
+    let mut hash = 0u64;
+    for word in &mut words {
+        let word = u64::from_le_bytes(word.try_into().unwrap_or_default());
+        hash = (hash.rotate_left(5) ^ word).wrapping_mul(SEED);
+    }
+    for &byte in words.remainder() {
+        hash = (hash.rotate_left(5) ^ byte as u64).wrapping_mul(SEED);
+    }
+    hash
+}
+
+/// Rects kept in [`SentRects`], older ones are forgotten beyond this.
+const MAX_SENT_RECTS: usize = 64;
+
+/// Content hashes of the rects published last, to skip rects which didn't
+/// change since.
+#[derive(Debug, Default)]
+struct SentRects(Vec<(Rect, u64)>);
+
+impl SentRects {
+    /// Removes the rects of `frame` whose pixels were already published at
+    /// the same place. Returns whether anything is left to publish.
+    fn drop_unchanged(&mut self, frame: &mut CapturedData) -> bool {
+        let hashed = frame
+            .rect_data()
+            .map(|(rect, data)| (rect, content_hash(data)))
+            .collect::<Vec<_>>();
+        let mut changed = Vec::with_capacity(hashed.len());
+        let (mut read, mut write) = (0, 0);
+        for entry @ (rect, _) in hashed {
+            let len = rect.width as usize * rect.height as usize * 4;
+            if !self.0.contains(&entry) {
+                frame.data.copy_within(read..read + len, write);
+                write += len;
+                changed.push(entry);
+            }
+            read += len;
+        }
+        frame.data.truncate(write);
+        frame.rects = changed.iter().map(|(rect, _)| *rect).collect();
+
+        // What is published now covers the hashes of overlapping rects
+        self.0
+            .retain(|(rect, _)| !changed.iter().any(|(other, _)| other.intersects(rect)));
+        self.0.extend(changed);
+        let excess = self.0.len().saturating_sub(MAX_SENT_RECTS);
+        self.0.drain(..excess);
+
+        !frame.rects.is_empty()
+    }
+
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+/// Recording of a new session's updates, starting with its desktop size.
+fn stream_recording(
+    recording: Option<&Recording>,
+    (width, height): (u16, u16),
+) -> Option<StreamRecording> {
+    let recording = recording?.stream();
+    recording.desktop_size(width, height);
+    Some(recording)
+}
+
+/// Largest monitor dimension MS-RDPEDISP allows.
+pub(super) const MAX_CLIENT_DIMENSION: u32 = 8192;
+
+/// Validates a monitor size sent by the client. Zero sizes would break input
+/// scaling and oversized ones can't be a real monitor.
+fn client_size(width: u32, height: u32) -> Option<(u16, u16)> {
+    let valid = 1..=MAX_CLIENT_DIMENSION;
+    (valid.contains(&width) && valid.contains(&height)).then_some((width as u16, height as u16))
+}
+
+/// Runs `f` with the locked base address and row stride of `input`.
+pub(super) fn with_pixels<R>(
     input: &CVPixelBuffer,
-    output: &mut CapturedData,
-) -> bool {
+    f: impl FnOnce(*const u8, usize) -> R,
+) -> Option<R> {
     let plane_count = input.get_plane_count();
     let Ok(locked) = input
         .lock()
         .map_err(|e| tracing::error!("Failed to lock buffer - {e:?}"))
     else {
-        return false;
+        return None;
     };
     let (base_address, bytes_per_row) = if plane_count == 0 {
         (locked.as_slice().as_ptr(), input.get_bytes_per_row())
@@ -156,101 +740,359 @@ fn convert_buffer(
             input.get_bytes_per_row_of_plane(0),
         )
     };
-    let data_size = width * height * 4; // 4 bytes per pixel (BGRA)
-    if output.data.len() < data_size {
-        let reserve_size = data_size - output.data.len();
-        tracing::trace!("reserve: {reserve_size}");
-        output.data.reserve(reserve_size);
-    }
-    unsafe {
-        output.data.set_len(data_size);
+    Some(f(base_address, bytes_per_row as usize))
+}
+
+/// Runs `f` with the locked luma and chroma planes of a bi-planar `input`.
+fn with_planes<R>(input: &CVPixelBuffer, f: impl FnOnce(&Plane, &Plane) -> R) -> Option<R> {
+    let plane_count = input.get_plane_count();
+    if plane_count != 2 {
+        tracing::error!(plane_count, "Expected a luma and a chroma plane");
+        return None;
     }
-    let out_addr = output.data.as_mut_ptr();
-    for rect_y in 0..height {
-        let src_addr = unsafe { base_address.add((y + rect_y) * (bytes_per_row as usize) + x * 4) };
-        let out_addr = unsafe { out_addr.add(rect_y * width * 4 + x * 4) };
-        unsafe {
-            std::ptr::copy_nonoverlapping(src_addr, out_addr, width * 4);
+    let Ok(locked) = input
+        .lock()
+        .map_err(|e| tracing::error!("Failed to lock buffer - {e:?}"))
+    else {
+        return None;
+    };
+    let luma = Plane {
+        data: locked.as_slice_plane(0),
+        bytes_per_row: input.get_bytes_per_row_of_plane(0) as usize,
+    };
+    let chroma = Plane {
+        data: locked.as_slice_plane(1),
+        bytes_per_row: input.get_bytes_per_row_of_plane(1) as usize,
+    };
+    Some(f(&luma, &chroma))
+}
+
+fn convert_buffer(
+    rects: &[Rect],
+    input: &CVPixelBuffer,
+    buffer_format: BufferFormat,
+    output: &mut CapturedData,
+) -> bool {
+    let input_width = input.get_width() as u16;
+    let input_height = input.get_height() as u16;
+    // Dirty rects can reach past a frame which shrank meanwhile.
+    let rects = rects
+        .iter()
+        .map(|rect| Rect {
+            width: rect.width.min(input_width.saturating_sub(rect.x)),
+            height: rect.height.min(input_height.saturating_sub(rect.y)),
+            ..*rect
+        })
+        .filter(|rect| !rect.is_empty());
+    output.rects.clear();
+    output.rects.extend(rects);
+    output.data.clear();
+    let (width, height) = (input_width as usize, input_height as usize);
+    let converted = match buffer_format {
+        BufferFormat::Bgra | BufferFormat::L10r => {
+            with_pixels(input, |base_address, bytes_per_row| {
+                // Reading past the rows would read past the buffer
+                if bytes_per_row < width * 4 {
+                    tracing::error!(bytes_per_row, width, "Rows are shorter than the frame");
+                    return false;
+                }
+                let input =
+                    unsafe { std::slice::from_raw_parts(base_address, bytes_per_row * height) };
+                for rect in &output.rects {
+                    copy_rect(
+                        input,
+                        bytes_per_row,
+                        rect.x as usize,
+                        rect.y as usize,
+                        rect.width as usize,
+                        rect.height as usize,
+                        &mut output.data,
+                    );
+                }
+                true
+            })
         }
-    }
+        BufferFormat::Yuv420 { full_range } => with_planes(input, |luma, chroma| {
+            if !luma.holds(width, height)
+                || !chroma.holds(width.div_ceil(2) * 2, height.div_ceil(2))
+            {
+                tracing::error!(width, height, "Planes are smaller than the frame");
+                return false;
+            }
+            for rect in &output.rects {
+                let rect = (
+                    rect.x as usize,
+                    rect.y as usize,
+                    rect.width as usize,
+                    rect.height as usize,
+                );
+                format::yuv420_to_bgra(luma, chroma, full_range, rect, &mut output.data);
+            }
+            true
+        }),
+    };
 
-    output.x = x as _;
-    output.y = y as _;
-    output.width = width as _;
-    output.height = height as _;
+    converted == Some(true)
+}
 
-    true
+/// Appends the `width` x `height` BGRA rect at (`x`, `y`) of `input`, whose
+/// rows are `bytes_per_row` apart, to `output`. `output` grows as needed,
+/// e.g. when the capture became larger than the buffer was first sized for.
+fn copy_rect(
+    input: &[u8],
+    bytes_per_row: usize,
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+    output: &mut Vec<u8>,
+) {
+    output.reserve(width * height * 4);
+    for row in y..y + height {
+        let start = row * bytes_per_row + x * 4;
+        output.extend_from_slice(&input[start..start + width * 4]);
+    }
 }
 
-struct DisplayCaptureDelegate {
+pub(super) struct DisplayCaptureDelegate {
+    /// Index of the stream, i.e. of the display, this delegate is for.
+    stream: usize,
+    /// Horizontal position of the display's frames on the client's desktop.
+    offset: u16,
     sender: RefCell<triple_buffer::Input<CapturedData>>,
     update_notifier: Arc<Notify>,
     capture_counter: RefCell<IntervalCounter>,
+    freeze: FreezeSwitch,
+    compositor: Option<Arc<Compositor>>,
+    /// Whether the client was last sent the frozen snapshot.
+    showing_snapshot: Cell<bool>,
+    input_activity: Option<Interval>,
+    interactive: Cell<bool>,
+    color_depth: SharedColorDepth,
+    refresh: RefreshRequest,
+    /// Refresh requests handled so far.
+    refresh_seen: RefCell<u64>,
+    /// Rects published last, to skip publishing unchanged content.
+    sent_rects: RefCell<SentRects>,
+    /// Rects of the frame published last, relative to the display, which
+    /// the session may not have taken yet.
+    last_rects: RefCell<Vec<Rect>>,
+    skipped_formats: SkippedFormats,
+    /// What the client was last shown, frozen as is when capture freezes
+    /// and re-sent on a refresh.
+    published: SharedPublishedFrame,
+    /// Segment local processes read the published frames from.
+    #[cfg(feature = "shm")]
+    shared_frame: Option<Arc<Mutex<super::shm::SharedFrame>>>,
+}
+
+impl DisplayCaptureDelegate {
+    /// Whether to send whole frames because the client is idle.
+    fn prefers_full_frame(&self) -> bool {
+        let Some(input_activity) = &self.input_activity else {
+            return false;
+        };
+        let interactive = input_activity.since_last_seen() < super::INTERACTIVE_TIMEOUT;
+        if self.interactive.replace(interactive) != interactive {
+            tracing::debug!(
+                "Quality mode: {}",
+                if interactive {
+                    "interactive"
+                } else {
+                    "quality"
+                }
+            );
+        }
+        !interactive
+    }
+
+    /// Publishes the frame in `input_buffer`, relative to the display, to the
+    /// session. A frame the session didn't take yet is replaced by it, so
+    /// its rects are published again, from the published frame which has
+    /// them at their latest.
+    fn publish(&self, input_buffer: &mut triple_buffer::Input<CapturedData>) {
+        let replacing = !input_buffer.consumed();
+        // Nobody reads the frames of the segment's delegate but the segment
+        #[cfg(feature = "shm")]
+        let replacing = replacing && self.shared_frame.is_none();
+        let frame = input_buffer.input_buffer_mut();
+        if !self.sent_rects.borrow_mut().drop_unchanged(frame) {
+            tracing::trace!("Frame unchanged, not publishing");
+            return;
+        }
+        if replacing {
+            let rects =
+                merge_dirty_rects(self.last_rects.borrow().iter().chain(&frame.rects).copied());
+            tracing::trace!(?rects, "Session missed a frame, publishing its rects again");
+            lock_published(&self.published).copy_rects(&rects, frame);
+        }
+        self.last_rects.borrow_mut().clone_from(&frame.rects);
+        for rect in &mut frame.rects {
+            rect.x += self.offset;
+        }
+        #[cfg(feature = "shm")]
+        if let Some(shared_frame) = &self.shared_frame {
+            shared_frame
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .write(frame);
+        }
+        input_buffer.publish();
+        self.update_notifier.notify_waiters();
+        self.capture_counter.borrow_mut().update();
+    }
 }
 
 impl SCStreamOutputTrait for DisplayCaptureDelegate {
-    fn did_output_sample_buffer(
-        &self,
-        sample_buffer: screencapturekit::output::CMSampleBuffer,
-        of_type: SCStreamOutputType,
-    ) {
+    fn did_output_sample_buffer(&self, sample_buffer: CMSampleBuffer, of_type: SCStreamOutputType) {
         if of_type != SCStreamOutputType::Screen {
             tracing::error!("non-screen received");
             return;
         }
+        self.capture_counter.borrow().touch();
 
-        let Ok(frame_info) = SCStreamFrameInfo::from_sample_buffer(&sample_buffer).map_err(|e| {
-            tracing::error!("Failed to get frame info from sample buffer: {e:?}");
-        }) else {
-            return;
-        };
-        if frame_info.status() != SCFrameStatus::Complete {
-            tracing::trace!("not completed");
-            return;
+        let mut input_buffer = self.sender.borrow_mut();
+        if self.freeze.is_frozen() {
+            if self.showing_snapshot.get() {
+                return;
+            }
+            let Some(snapshot) = self.freeze.snapshot(self.stream, || {
+                lock_published(&self.published).to_captured()
+            }) else {
+                return;
+            };
+            input_buffer.input_buffer_mut().clone_from(&*snapshot);
+            self.showing_snapshot.set(true);
+            self.sent_rects.borrow_mut().clear();
+        } else {
+            // Changes made while frozen were never sent, so resume with a
+            // whole frame.
+            let refresh = self.refresh.take(&mut self.refresh_seen.borrow_mut());
+            if refresh {
+                tracing::info!("Sending full frame for a refresh request");
+            }
+            // The first frame is whole too, so there is one to freeze on.
+            let forced = self.showing_snapshot.replace(false)
+                | refresh
+                | !lock_published(&self.published).is_whole();
+            if forced {
+                self.sent_rects.borrow_mut().clear();
+            }
+            let full_frame = forced | self.prefers_full_frame();
+            let frame = input_buffer.input_buffer_mut();
+            if !capture_sample(
+                &sample_buffer,
+                frame,
+                full_frame,
+                self.compositor.as_deref(),
+                &self.skipped_formats,
+            ) {
+                return;
+            }
+            self.color_depth.get().reduce(&mut frame.data);
+            lock_published(&self.published).draw(frame, full_frame);
         }
-        let Some(dirty_rects) = frame_info.dirty_rects() else {
-            tracing::error!("Failed to get dirty rects from frame info");
-            return;
-        };
+        self.publish(&mut input_buffer);
+    }
+}
 
-        if let Some(pixel_buffer) = sample_buffer.get_pixel_buffer().ok() {
-            let (mut x, mut y, max_x, max_y) =
-                dirty_rects
-                    .iter()
-                    .fold((0, 0, 0, 0), |(min_x, min_y, max_x, max_y), rect| {
-                        let x = rect.origin.x as usize;
-                        let y = rect.origin.y as usize;
-                        let width = rect.size.width as usize;
-                        let height = rect.size.height as usize;
-
-                        (
-                            min_x.min(x),
-                            min_y.min(y),
-                            max_x.max(x + width),
-                            max_y.max(y + height),
-                        )
-                    });
-            let mut width = max_x - x;
-            let mut height = max_y - y;
-            if width == 0 || height == 0 {
-                x = 0;
-                y = 0;
-                width = pixel_buffer.get_width() as usize;
-                height = pixel_buffer.get_height() as usize;
-            }
-            let mut input_buffer = self.sender.borrow_mut();
-            {
-                let input_buffer = input_buffer.input_buffer_mut();
-                if !convert_buffer(x, y, width, height, &pixel_buffer, input_buffer) {
-                    tracing::error!("Failed to convert buffer");
-                    return;
-                };
+/// Non-empty `rects`, or their bounding box when there are more than
+/// `MAX_DIRTY_RECTS`.
+fn merge_dirty_rects(rects: impl IntoIterator<Item = Rect>) -> Vec<Rect> {
+    let rects = rects
+        .into_iter()
+        .filter(|rect| !rect.is_empty())
+        .collect::<Vec<_>>();
+    if rects.len() <= MAX_DIRTY_RECTS {
+        return rects;
+    }
+    rects.into_iter().reduce(Rect::union).into_iter().collect()
+}
+
+/// Copies the changed regions of a complete screen sample into `output`, or
+/// the whole frame with `full_frame`. With a `compositor` the whole canvas is
+/// rendered instead.
+///
+/// Returns `false` when the sample carries no new frame.
+fn capture_sample(
+    sample_buffer: &CMSampleBuffer,
+    output: &mut CapturedData,
+    full_frame: bool,
+    compositor: Option<&Compositor>,
+    skipped_formats: &SkippedFormats,
+) -> bool {
+    let Ok(frame_info) = SCStreamFrameInfo::from_sample_buffer(sample_buffer).map_err(|e| {
+        tracing::error!("Failed to get frame info from sample buffer: {e:?}");
+    }) else {
+        return false;
+    };
+    if frame_info.status() != SCFrameStatus::Complete {
+        tracing::trace!("not completed");
+        return false;
+    }
+    let Some(dirty_rects) = frame_info.dirty_rects() else {
+        tracing::error!("Failed to get dirty rects from frame info");
+        return false;
+    };
+
+    let Ok(pixel_buffer) = sample_buffer.get_pixel_buffer() else {
+        return false;
+    };
+    let fourcc = pixel_buffer.get_pixel_format();
+    let Some(buffer_format) = BufferFormat::from_fourcc(fourcc) else {
+        if !skipped_formats.unsupported.replace(true) {
+            tracing::error!(
+                format = %format::fourcc_name(fourcc),
+                "Skipping frames in an unsupported pixel format"
+            );
+        }
+        return false;
+    };
+    if let Some(compositor) = compositor {
+        if matches!(buffer_format, BufferFormat::Yuv420 { .. }) {
+            if !skipped_formats.not_composited.replace(true) {
+                tracing::error!("Regions can't be composited from YCbCr frames, skipping them");
             }
-            input_buffer.publish();
-            self.update_notifier.notify_waiters();
-            self.capture_counter.borrow_mut().update();
+            return false;
+        }
+        if !compositor.compose(&pixel_buffer, output) {
+            tracing::error!("Failed to compose regions");
+            return false;
+        }
+        if buffer_format == BufferFormat::L10r {
+            format::unpack_l10r(&mut output.data);
         }
+        output.timestamp = super::presentation_millis(sample_buffer).unwrap_or(output.timestamp);
+        return true;
     }
+    let mut rects = if full_frame {
+        Vec::new()
+    } else {
+        merge_dirty_rects(dirty_rects.iter().map(|rect| Rect {
+            x: rect.origin.x as u16,
+            y: rect.origin.y as u16,
+            width: rect.size.width as u16,
+            height: rect.size.height as u16,
+        }))
+    };
+    if rects.is_empty() {
+        rects.push(Rect {
+            x: 0,
+            y: 0,
+            width: pixel_buffer.get_width() as u16,
+            height: pixel_buffer.get_height() as u16,
+        });
+    }
+    if !convert_buffer(&rects, &pixel_buffer, buffer_format, output) {
+        tracing::error!("Failed to convert buffer");
+        return false;
+    }
+    if buffer_format == BufferFormat::L10r {
+        format::unpack_l10r(&mut output.data);
+    }
+    output.timestamp = super::presentation_millis(sample_buffer).unwrap_or(output.timestamp);
+
+    true
 }
 
 impl super::ScreenCaptureContext {
@@ -258,24 +1100,13 @@ impl super::ScreenCaptureContext {
         match job {
             Job::GetSize(sender) => {
                 tracing::trace!("Requsted display size");
-                let screen_size = *self.display_size.borrow();
-                if let Err(e) = sender.send((screen_size.server.0, screen_size.server.1)) {
+                let capture_size = self.display_size.borrow().capture;
+                if let Err(e) = sender.send(capture_size) {
                     tracing::error!("Failed to send display size: {e:?}");
                 }
             }
             Job::SetSize(width, height) => {
-                // use objc2_core_graphics::{CGGetActiveDisplayList, CGDisplayCopyDisplayMode, CGDisplayMode, CGDirectDisplayID};
-                // let mut active_displays = std::mem::MaybeUninit::<[CGDirectDisplayID; 1]>::uninit();
-                // let mut display_count = std::mem::MaybeUninit::<u32>::uninit();
-                // unsafe { CGGetActiveDisplayList(1, &raw mut (&mut *active_displays.as_mut_ptr())[0], display_count.as_mut_ptr()) };
-                // let active_displays = unsafe { active_displays.assume_init() };
-                // let display_count = unsafe { display_count.assume_init() };
-                // if display_count == 0 {
-                //     panic!("No active displays found");
-                // }
-                // let display = active_displays[0];
-                // let display_mode = unsafe { CGDisplayCopyDisplayMode(display) }.unwrap();
-                self.display_size.send_if_modified(|screen_size| {
+                let changed = self.display_size.send_if_modified(|screen_size| {
                     if screen_size.client != (width, height) {
                         tracing::info!("Client display size changed: {} x {}", width, height);
                         screen_size.client = (width, height);
@@ -284,47 +1115,684 @@ impl super::ScreenCaptureContext {
                         false
                     }
                 });
+                if changed {
+                    self.match_client_resolution(width, height);
+                }
             }
             Job::CaptureStart(sender) => {
-                let screen_size = *self.display_size.borrow();
-                let (capture_sender, capture_receiver) =
-                    triple_buffer::triple_buffer(&CapturedData {
-                        data: Vec::with_capacity(
-                            (4 * screen_size.server.0 * screen_size.server.1) as usize,
-                        ),
-                        width: screen_size.server.0 as _,
-                        height: screen_size.server.1 as _,
-                        x: 0,
-                        y: 0,
-                    });
+                let capture_size = self.display_size.borrow().capture;
                 let update_notification = Arc::new(Notify::new());
-                let delegate = DisplayCaptureDelegate {
-                    sender: RefCell::new(capture_sender),
-                    update_notifier: update_notification.clone(),
-                    capture_counter: RefCell::new(self.capture_counter.clone()),
+                let mut published = Vec::new();
+                let (pending_delegates, capture_receivers) = self
+                    .stream_offsets
+                    .iter()
+                    .enumerate()
+                    .map(|(stream, &offset)| {
+                        let (capture_sender, capture_receiver) =
+                            triple_buffer::triple_buffer(&CapturedData {
+                                data: Vec::with_capacity(
+                                    4 * capture_size.0 as usize * capture_size.1 as usize,
+                                ),
+                                rects: Vec::new(),
+                                timestamp: 0,
+                            });
+                        let delegate = self.capture_delegate(
+                            stream,
+                            offset,
+                            capture_sender,
+                            update_notification.clone(),
+                        );
+                        published.push((offset, delegate.published.clone()));
+                        (delegate, capture_receiver)
+                    })
+                    .unzip();
+                let updates = DisplayUpdates {
+                    indices: Vec::new(),
+                    attached: false,
+                    display_requested: Some(watch_audio_only()),
+                    pending_delegates,
+                    attach_receivers: Vec::new(),
+                    display_sender: self.job_sender.clone(),
+                    update_notification,
+                    capture_receivers,
+                    display_size: self.display_size.subscribe(),
+                    desktop_size: capture_size,
+                    send_counter: self.send_counter.clone(),
+                    bitrate: self.bitrate.clone(),
+                    capture_failed: self.capture_failed.subscribe(),
+                    frame_skip: self.options.frame_skip,
+                    skipped_frames: self.options.skipped_frames.clone(),
+                    refresh: self.options.refresh.clone(),
+                    refresh_seen: 0,
+                    published,
+                    last_checksum: self.last_checksum.clone(),
+                    pending: VecDeque::new(),
+                    recording: stream_recording(self.options.recording.as_ref(), capture_size),
+                    format: self.options.pixel_format.update_format(),
+                    owner: Arc::default(),
                 };
-                let ret = self
-                    .stream
+                if sender.send(updates).is_err() {
+                    tracing::error!("Failed to send DisplayUpdates");
+                }
+            }
+            Job::CaptureAttach(delegate, owner, sender) => {
+                if owner.strong_count() == 0 {
+                    tracing::info!("Session ended before display capture started");
+                    return;
+                }
+                self.ensure_capture_running();
+                let stream = delegate.stream;
+                let ret = self.streams[stream]
                     .add_output_handler(delegate, SCStreamOutputType::Screen)
                     .context("Failed to start add stream output")
-                    .map(|index| DisplayUpdates {
-                        index: ScreenOutputIndex::new(index),
-                        display_sender: self.job_sender.clone(),
-                        update_notification,
-                        capture_receiver,
-                        display_size: self.display_size.subscribe(),
-                        send_counter: self.send_counter.clone(),
+                    .map(ScreenOutputIndex::new);
+                if let Ok(index) = ret {
+                    self.display_outputs.push(DisplayOutput {
+                        stream,
+                        index,
+                        owner,
                     });
-                tracing::info!("Display capture started");
+                    tracing::info!(
+                        outputs = self.display_outputs.len(),
+                        "Display output attached"
+                    );
+                }
                 if sender.send(ret).is_err() {
+                    tracing::error!("Failed to send display output index");
+                }
+            }
+            Job::CaptureStop(stream, index) => {
+                let Some(position) = self
+                    .display_outputs
+                    .iter()
+                    .position(|output| output.stream == stream && output.index == index)
+                else {
+                    tracing::debug!("Display output was already removed");
+                    return;
+                };
+                self.remove_display_output(position);
+                tracing::info!(
+                    outputs = self.display_outputs.len(),
+                    "Stopping display capture"
+                );
+            }
+        }
+    }
+}
+
+impl super::ScreenCaptureContext {
+    fn capture_delegate(
+        &self,
+        stream: usize,
+        offset: u16,
+        sender: triple_buffer::Input<CapturedData>,
+        update_notifier: Arc<Notify>,
+    ) -> DisplayCaptureDelegate {
+        DisplayCaptureDelegate {
+            stream,
+            offset,
+            sender: RefCell::new(sender),
+            update_notifier,
+            capture_counter: RefCell::new(self.capture_counter.clone()),
+            freeze: self.freeze.clone(),
+            compositor: self.compositor.clone(),
+            showing_snapshot: Cell::new(false),
+            input_activity: self.options.input_activity.clone(),
+            interactive: Cell::new(false),
+            color_depth: self.color_depth.clone(),
+            refresh: self.options.refresh.clone(),
+            refresh_seen: RefCell::default(),
+            sent_rects: Default::default(),
+            last_rects: Default::default(),
+            skipped_formats: SkippedFormats::default(),
+            published: SharedPublishedFrame::default(),
+            #[cfg(feature = "shm")]
+            shared_frame: None,
+        }
+    }
+
+    /// Keeps `frame` up to date with what clients are sent, through an
+    /// output handler of every stream which stays attached without clients.
+    #[cfg(feature = "shm")]
+    pub(super) fn attach_shared_frame(&mut self, frame: super::shm::SharedFrame) {
+        let frame = Arc::new(Mutex::new(frame));
+        for (stream, &offset) in self.stream_offsets.iter().enumerate() {
+            // Nobody reads the published frames but the segment
+            let (sender, _) = triple_buffer::triple_buffer(&CapturedData::default());
+            let mut delegate = self.capture_delegate(stream, offset, sender, Arc::default());
+            // Not a client, so it doesn't count towards the capture rate
+            delegate.capture_counter = RefCell::new(IntervalCounter::new());
+            delegate.shared_frame = Some(frame.clone());
+            if self.streams[stream]
+                .add_output_handler(delegate, SCStreamOutputType::Screen)
+                .is_none()
+            {
+                tracing::error!(stream, "Failed to attach shared memory output");
+            }
+        }
+    }
+
+    fn remove_display_output(&mut self, position: usize) {
+        let output = self.display_outputs.swap_remove(position);
+        self.streams[output.stream]
+            .remove_output_handler(output.index.to_raw(), SCStreamOutputType::Screen);
+        self.mark_idle_if_unused();
+    }
+
+    /// Adapts the color depth to the link with `depth_adaptation`.
+    pub(super) fn adapt_color_depth(&mut self) {
+        let Some(adaptation) = self.options.depth_adaptation else {
+            return;
+        };
+        if self.display_outputs.is_empty() || self.depth_changed.elapsed() < DEPTH_ADAPTATION_PERIOD
+        {
+            return;
+        }
+        let current = self.color_depth.get();
+        let depth = adaptation.adapt(
+            current,
+            self.options.color_depth,
+            self.capture_counter.interval().get(),
+            self.send_counter.interval().get(),
+        );
+        if depth == current {
+            return;
+        }
+        tracing::info!(?current, ?depth, "Adapting color depth to the link");
+        self.color_depth.set(depth);
+        self.depth_changed = Instant::now();
+        // Regions sent at the lower depth are otherwise only repaired once
+        // they change
+        if depth > current {
+            self.options.refresh.request();
+        }
+    }
+
+    /// Removes the output handlers of sessions which are gone without
+    /// stopping their capture, e.g. when the stop job didn't fit the queue
+    /// or the session ended while its handler was being attached.
+    pub(super) fn remove_orphaned_outputs(&mut self) {
+        while let Some(position) = self
+            .display_outputs
+            .iter()
+            .position(|output| output.owner.strong_count() == 0)
+        {
+            self.remove_display_output(position);
+            tracing::warn!(
+                outputs = self.display_outputs.len(),
+                "Removed orphaned display output"
+            );
+        }
+    }
+}
+
+#[cfg(feature = "mock-backend")]
+impl super::mock::MockContext {
+    pub(super) fn handle_display_job(&mut self, job: Job) {
+        match job {
+            Job::GetSize(sender) => {
+                let _ = sender.send(self.display_size.borrow().capture);
+            }
+            Job::SetSize(width, height) => {
+                self.display_size
+                    .send_modify(|screen_size| screen_size.client = (width, height));
+            }
+            Job::CaptureStart(sender) => {
+                let capture_size = self.display_size.borrow().capture;
+                let (capture_sender, capture_receiver) = triple_buffer::triple_buffer(&self.frame);
+                let update_notification = Arc::new(Notify::new());
+                let published = SharedPublishedFrame::default();
+                let delegate = DisplayCaptureDelegate {
+                    stream: 0,
+                    offset: 0,
+                    sender: RefCell::new(capture_sender),
+                    update_notifier: update_notification.clone(),
+                    capture_counter: RefCell::new(IntervalCounter::new()),
+                    freeze: FreezeSwitch::default(),
+                    compositor: None,
+                    showing_snapshot: Cell::new(false),
+                    input_activity: None,
+                    interactive: Cell::new(false),
+                    color_depth: SharedColorDepth::new(self.options.color_depth),
+                    refresh: self.options.refresh.clone(),
+                    refresh_seen: RefCell::default(),
+                    sent_rects: Default::default(),
+                    last_rects: Default::default(),
+                    skipped_formats: SkippedFormats::default(),
+                    published: published.clone(),
+                    #[cfg(feature = "shm")]
+                    shared_frame: None,
+                };
+                let updates = DisplayUpdates {
+                    indices: Vec::new(),
+                    attached: false,
+                    display_requested: Some(watch_audio_only()),
+                    pending_delegates: vec![delegate],
+                    attach_receivers: Vec::new(),
+                    display_sender: self.job_sender.clone(),
+                    update_notification,
+                    capture_receivers: vec![capture_receiver],
+                    display_size: self.display_size.subscribe(),
+                    desktop_size: capture_size,
+                    send_counter: self.send_counter.clone(),
+                    bitrate: self.bitrate.clone(),
+                    capture_failed: self.capture_failed.subscribe(),
+                    frame_skip: self.options.frame_skip,
+                    skipped_frames: self.options.skipped_frames.clone(),
+                    refresh: self.options.refresh.clone(),
+                    refresh_seen: 0,
+                    published: vec![(0, published)],
+                    last_checksum: None,
+                    pending: VecDeque::new(),
+                    recording: stream_recording(self.options.recording.as_ref(), capture_size),
+                    format: self.options.pixel_format.update_format(),
+                    owner: Arc::default(),
+                };
+                if sender.send(updates).is_err() {
                     tracing::error!("Failed to send DisplayUpdates");
                 }
             }
-            Job::CaptureStop(index) => {
-                tracing::info!("Stopping display capture");
-                self.stream
-                    .remove_output_handler(index.to_raw(), SCStreamOutputType::Screen);
+            Job::CaptureAttach(delegate, _, sender) => {
+                // Republish the test pattern until the session drops its
+                // `DisplayUpdates`, which holds the other notifier reference.
+                let frame = self.frame.clone();
+                tokio::task::spawn_local(async move {
+                    let mut interval = tokio::time::interval(super::mock::FRAME_INTERVAL);
+                    while Arc::strong_count(&delegate.update_notifier) > 1 {
+                        interval.tick().await;
+                        let mut input_buffer = delegate.sender.borrow_mut();
+                        lock_published(&delegate.published).draw(&frame, true);
+                        input_buffer.input_buffer_mut().clone_from(&frame);
+                        input_buffer.publish();
+                        delegate.update_notifier.notify_waiters();
+                    }
+                });
+                let _ = sender.send(Ok(ScreenOutputIndex::new(std::ptr::null_mut())));
             }
+            Job::CaptureStop(..) => tracing::info!("Stopping mock display capture"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solid(rects: &[Rect], value: u8) -> CapturedData {
+        let len = rects
+            .iter()
+            .map(|rect| rect.width as usize * rect.height as usize * 4)
+            .sum();
+        CapturedData {
+            timestamp: 0,
+            rects: rects.to_vec(),
+            data: vec![value; len],
+        }
+    }
+
+    #[test]
+    fn unchanged_rects_are_not_published_again() {
+        let left = Rect {
+            x: 0,
+            y: 0,
+            width: 2,
+            height: 2,
+        };
+        let right = Rect { x: 4, ..left };
+        let mut sent = SentRects::default();
+        assert!(sent.drop_unchanged(&mut solid(&[left, right], 1)));
+
+        let mut frame = solid(&[left, right], 1);
+        assert!(!sent.drop_unchanged(&mut frame));
+        assert!(frame.rects.is_empty() && frame.data.is_empty());
+
+        let mut frame = solid(&[left], 1);
+        frame.rects.push(right);
+        frame.data.extend([2; 16]);
+        assert!(sent.drop_unchanged(&mut frame));
+        assert_eq!(frame.rects, [right]);
+        assert_eq!(frame.data, [2; 16]);
+    }
+
+    #[test]
+    fn overlapping_rects_invalidate_sent_content() {
+        let whole = Rect {
+            x: 0,
+            y: 0,
+            width: 4,
+            height: 4,
+        };
+        let corner = Rect {
+            width: 2,
+            height: 2,
+            ..whole
+        };
+        let mut sent = SentRects::default();
+        assert!(sent.drop_unchanged(&mut solid(&[whole], 1)));
+        assert!(sent.drop_unchanged(&mut solid(&[corner], 2)));
+        // The corner changed the whole frame the client has
+        assert!(sent.drop_unchanged(&mut solid(&[whole], 1)));
+    }
+
+    #[test]
+    fn published_frame_keeps_drawn_rects() {
+        let whole = Rect {
+            x: 0,
+            y: 0,
+            width: 2,
+            height: 2,
+        };
+        let mut published = PublishedFrame::default();
+        published.draw(&solid(&[whole], 1), false);
+        assert!(published.to_captured().is_none());
+
+        published.draw(&solid(&[whole], 1), true);
+        let corner = Rect {
+            x: 1,
+            y: 1,
+            width: 1,
+            height: 1,
+        };
+        let outside = Rect { x: 2, ..corner };
+        published.draw(&solid(&[outside], 3), false);
+        let mut frame = solid(&[corner], 2);
+        frame.timestamp = 5;
+        published.draw(&frame, false);
+
+        let snapshot = published.to_captured().unwrap();
+        assert_eq!((snapshot.rects, snapshot.timestamp), (vec![whole], 5));
+        assert_eq!(snapshot.data, [[1; 12].as_slice(), &[2; 4]].concat());
+    }
+
+    #[test]
+    fn frame_checksum_is_fnv1a() {
+        assert_eq!(frame_checksum(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(frame_checksum(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(frame_checksum(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    /// BGRA frame whose pixels hold their own coordinates, with row padding.
+    fn frame(width: usize, height: usize, bytes_per_row: usize) -> Vec<u8> {
+        let mut frame = vec![0; bytes_per_row * height];
+        for y in 0..height {
+            for x in 0..width {
+                frame[y * bytes_per_row + x * 4..][..4]
+                    .copy_from_slice(&[x as u8, y as u8, 0, 0xFF]);
+            }
+        }
+        frame
+    }
+
+    #[test]
+    fn copy_rect_grows_output_beyond_initial_allocation() {
+        let input = frame(8, 6, 40);
+        let mut output = CapturedData {
+            data: Vec::with_capacity(2 * 2 * 4),
+            ..Default::default()
+        };
+
+        copy_rect(&input, 40, 0, 0, 8, 6, &mut output.data);
+        assert_eq!(output.data.len(), 8 * 6 * 4);
+        assert_eq!(output.data, frame(8, 6, 32));
+
+        output.data.clear();
+        copy_rect(&input, 40, 0, 0, 2, 1, &mut output.data);
+        assert_eq!(output.data, [0, 0, 0, 0xFF, 1, 0, 0, 0xFF]);
+    }
+
+    #[test]
+    fn copy_rect_packs_offset_rect() {
+        let input = frame(8, 6, 40);
+        let mut output = Vec::new();
+
+        copy_rect(&input, 40, 5, 4, 3, 2, &mut output);
+        let pixels = output
+            .chunks_exact(4)
+            .map(|p| (p[0], p[1]))
+            .collect::<Vec<_>>();
+        assert_eq!(pixels, [(5, 4), (6, 4), (7, 4), (5, 5), (6, 5), (7, 5)]);
+    }
+
+    #[test]
+    fn copy_rect_appends_rects_without_gaps() {
+        let input = frame(8, 6, 40);
+        let mut output = Vec::new();
+
+        copy_rect(&input, 40, 6, 0, 2, 2, &mut output);
+        copy_rect(&input, 40, 1, 5, 1, 1, &mut output);
+        assert_eq!(output.len(), (2 * 2 + 1) * 4);
+        let pixels = output
+            .chunks_exact(4)
+            .map(|p| (p[0], p[1], p[3]))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pixels,
+            [
+                (6, 0, 0xFF),
+                (7, 0, 0xFF),
+                (6, 1, 0xFF),
+                (7, 1, 0xFF),
+                (1, 5, 0xFF)
+            ]
+        );
+    }
+
+    fn rect(x: u16, y: u16, width: u16, height: u16) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn merge_dirty_rects_keeps_few_rects_apart() {
+        let rects = [rect(0, 0, 4, 4), rect(0, 0, 0, 4), rect(100, 50, 2, 2)];
+        assert_eq!(
+            merge_dirty_rects(rects),
+            [rect(0, 0, 4, 4), rect(100, 50, 2, 2)]
+        );
+    }
+
+    #[test]
+    fn merge_dirty_rects_bounds_many_rects() {
+        let rects = (0..=MAX_DIRTY_RECTS as u16).map(|i| rect(10 + i * 2, 20, 1, 1 + i));
+        assert_eq!(
+            merge_dirty_rects(rects),
+            [rect(
+                10,
+                20,
+                MAX_DIRTY_RECTS as u16 * 2 + 1,
+                MAX_DIRTY_RECTS as u16 + 1
+            )]
+        );
+    }
+
+    #[test]
+    fn rect_data_splits_packed_pixels() {
+        let frame = CapturedData {
+            rects: vec![rect(0, 0, 1, 1), rect(5, 5, 2, 1)],
+            data: (0..12).collect(),
+            ..Default::default()
+        };
+        let rects = frame.rect_data().collect::<Vec<_>>();
+        assert_eq!(
+            rects,
+            [
+                (rect(0, 0, 1, 1), &[0, 1, 2, 3][..]),
+                (rect(5, 5, 2, 1), &[4, 5, 6, 7, 8, 9, 10, 11][..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn client_size_rejects_zero_dimensions() {
+        assert_eq!(client_size(0, 1080), None);
+        assert_eq!(client_size(1920, 0), None);
+        assert_eq!(client_size(0, 0), None);
+    }
+
+    #[test]
+    fn client_size_rejects_oversized_dimensions() {
+        assert_eq!(client_size(8193, 1080), None);
+        assert_eq!(client_size(1920, u32::MAX), None);
+    }
+
+    #[test]
+    fn client_size_accepts_valid_dimensions() {
+        assert_eq!(client_size(1920, 1080), Some((1920, 1080)));
+        assert_eq!(client_size(8192, 8192), Some((8192, 8192)));
+    }
+
+    #[test]
+    fn depth_follows_the_send_lag() {
+        let adaptation = DepthAdaptation {
+            lag_ratio: 1.5,
+            recover_ratio: 1.1,
+        };
+        let capture = Duration::from_millis(33);
+        let adapt = |current, send| adaptation.adapt(current, ColorDepth::Bgra8888, capture, send);
+        assert_eq!(
+            adapt(ColorDepth::Bgra8888, Duration::from_millis(100)),
+            ColorDepth::Rgb565
+        );
+        assert_eq!(
+            adapt(ColorDepth::Rgb565, Duration::from_millis(40)),
+            ColorDepth::Rgb565
+        );
+        assert_eq!(
+            adapt(ColorDepth::Rgb565, Duration::from_millis(34)),
+            ColorDepth::Bgra8888
+        );
+        assert_eq!(
+            adapt(ColorDepth::Bgra8888, Duration::from_millis(34)),
+            ColorDepth::Bgra8888
+        );
+    }
+
+    /// Rect of the next bitmap update and its first byte.
+    #[cfg(feature = "mock-backend")]
+    async fn next_rect(updates: &mut DisplayUpdates) -> (Rect, u8) {
+        match updates.next_update().await {
+            Some(DisplayUpdate::Bitmap(update)) => (
+                Rect {
+                    x: update.x,
+                    y: update.y,
+                    width: update.width.get(),
+                    height: update.height.get(),
+                },
+                update.data[0],
+            ),
+            _ => panic!("expected a bitmap update"),
+        }
+    }
+
+    #[cfg(feature = "mock-backend")]
+    #[tokio::test]
+    async fn frames_the_session_missed_are_published_again() {
+        let local_set = tokio::task::LocalSet::new();
+        let (capture, _) = super::super::ScreenCapture::mock(&local_set, (8, 8));
+        local_set
+            .run_until(async move {
+                let (sender, receiver) = oneshot::channel();
+                capture
+                    .job_sender
+                    .send(ScreenJob::Display(Job::CaptureStart(sender)))
+                    .await
+                    .unwrap();
+                let mut updates = receiver.await.unwrap();
+                let delegate = updates.pending_delegates.pop().unwrap();
+                updates.attached = true;
+                let publish = |frame: CapturedData, whole: bool| {
+                    lock_published(&delegate.published).draw(&frame, whole);
+                    let mut input_buffer = delegate.sender.borrow_mut();
+                    *input_buffer.input_buffer_mut() = frame;
+                    delegate.publish(&mut input_buffer);
+                };
+                let whole = Rect {
+                    x: 0,
+                    y: 0,
+                    width: 8,
+                    height: 8,
+                };
+                publish(solid(&[whole], 0), true);
+                assert_eq!(next_rect(&mut updates).await, (whole, 0));
+
+                // Both published before the session asks for the next update
+                let top = Rect {
+                    width: 4,
+                    height: 2,
+                    ..whole
+                };
+                let bottom = Rect {
+                    x: 4,
+                    y: 4,
+                    width: 4,
+                    height: 4,
+                };
+                publish(solid(&[top], 1), false);
+                publish(solid(&[bottom], 2), false);
+                assert_eq!(next_rect(&mut updates).await, (top, 1));
+                assert_eq!(next_rect(&mut updates).await, (bottom, 2));
+                assert!(updates.pending.is_empty());
+            })
+            .await;
+    }
+
+    #[cfg(feature = "mock-backend")]
+    #[tokio::test]
+    async fn frames_captured_while_throttled_are_sent_together() {
+        let local_set = tokio::task::LocalSet::new();
+        let (capture, _) = super::super::ScreenCapture::mock(&local_set, (8, 8));
+        local_set
+            .run_until(async move {
+                let (sender, receiver) = oneshot::channel();
+                capture
+                    .job_sender
+                    .send(ScreenJob::Display(Job::CaptureStart(sender)))
+                    .await
+                    .unwrap();
+                let mut updates = receiver.await.unwrap();
+                let delegate = updates.pending_delegates.pop().unwrap();
+                updates.attached = true;
+                // The whole frame alone exceeds the limit for a second
+                updates.bitrate = BitrateCounter::new(Some(1));
+                let publish = |frame: CapturedData, whole: bool| {
+                    lock_published(&delegate.published).draw(&frame, whole);
+                    let mut input_buffer = delegate.sender.borrow_mut();
+                    *input_buffer.input_buffer_mut() = frame;
+                    delegate.publish(&mut input_buffer);
+                };
+                let whole = Rect {
+                    x: 0,
+                    y: 0,
+                    width: 8,
+                    height: 8,
+                };
+                publish(solid(&[whole], 0), true);
+                assert_eq!(next_rect(&mut updates).await, (whole, 0));
+
+                let top = Rect {
+                    width: 4,
+                    height: 2,
+                    ..whole
+                };
+                let bottom = Rect {
+                    x: 4,
+                    y: 4,
+                    width: 4,
+                    height: 4,
+                };
+                publish(solid(&[top], 1), false);
+                let (first, ()) = tokio::join!(next_rect(&mut updates), async {
+                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                    publish(solid(&[bottom], 2), false);
+                });
+                assert_eq!(first, (top, 1));
+                assert_eq!(next_rect(&mut updates).await, (bottom, 2));
+                assert!(updates.pending.is_empty());
+            })
+            .await;
+    }
+}
@@ -0,0 +1,97 @@
+//! Switches the captured display to the mode matching a client's size, so
+//! the client gets native pixels instead of a scaled frame.
+
+use objc2_core_foundation::{CFArray, CFRetained};
+use objc2_core_graphics::{
+    CGDirectDisplayID, CGDisplayCopyAllDisplayModes, CGDisplayCopyDisplayMode, CGDisplayMode,
+    CGDisplaySetDisplayMode,
+};
+
+/// Display whose mode follows the client, put back in its original mode
+/// when dropped.
+pub(super) struct DisplayModeSwitch {
+    display_id: CGDirectDisplayID,
+    original: CFRetained<CGDisplayMode>,
+    switched: bool,
+}
+
+/// Index of the mode whose pixel size is exactly `size`.
+fn matching_mode(modes: &[(usize, usize)], size: (usize, usize)) -> Option<usize> {
+    modes.iter().position(|mode| *mode == size)
+}
+
+fn set_mode(display_id: CGDirectDisplayID, mode: &CGDisplayMode) -> bool {
+    let err = unsafe { CGDisplaySetDisplayMode(display_id, Some(mode), None) };
+    if err.0 != 0 {
+        tracing::error!("[CGDisplaySetDisplayMode] error - {}", err.0);
+        return false;
+    }
+    true
+}
+
+impl DisplayModeSwitch {
+    pub(super) fn new(display_id: CGDirectDisplayID) -> Option<Self> {
+        let original = unsafe { CGDisplayCopyDisplayMode(display_id) }?;
+        Some(Self {
+            display_id,
+            original,
+            switched: false,
+        })
+    }
+
+    pub(super) fn display_id(&self) -> CGDirectDisplayID {
+        self.display_id
+    }
+
+    /// Switches to the mode of `width` x `height` pixels. Returns `false`
+    /// when the display has no such mode, leaving the frame to be scaled.
+    pub(super) fn switch_to(&mut self, width: u16, height: u16) -> bool {
+        let Some(modes) = (unsafe { CGDisplayCopyAllDisplayModes(self.display_id, None) }) else {
+            return false;
+        };
+        let modes = unsafe { CFRetained::cast_unchecked::<CFArray<CGDisplayMode>>(modes) };
+        let modes = modes
+            .iter()
+            .filter(|mode| unsafe { CGDisplayMode::is_usable_for_desktop_gui(Some(mode)) })
+            .collect::<Vec<_>>();
+        let sizes = modes
+            .iter()
+            .map(|mode| unsafe {
+                (
+                    CGDisplayMode::pixel_width(Some(mode)),
+                    CGDisplayMode::pixel_height(Some(mode)),
+                )
+            })
+            .collect::<Vec<_>>();
+        let Some(index) = matching_mode(&sizes, (width as usize, height as usize)) else {
+            tracing::info!(width, height, "No display mode matches the client, scaling");
+            return false;
+        };
+        if !set_mode(self.display_id, &modes[index]) {
+            return false;
+        }
+        tracing::info!(width, height, "Switched display mode to match the client");
+        self.switched = true;
+        true
+    }
+}
+
+impl Drop for DisplayModeSwitch {
+    fn drop(&mut self) {
+        if self.switched && set_mode(self.display_id, &self.original) {
+            tracing::info!("Restored the original display mode");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_exact_pixel_sizes_match() {
+        let modes = [(1440, 900), (2880, 1800), (1920, 1200)];
+        assert_eq!(matching_mode(&modes, (2880, 1800)), Some(1));
+        assert_eq!(matching_mode(&modes, (1920, 1080)), None);
+    }
+}
@@ -0,0 +1,238 @@
+//! Pixel formats frames are captured in. Whatever the capture format, the
+//! pixels sent to clients, recorded and published are 8-bit BGRA.
+
+use screencapturekit::stream::configuration::pixel_format::PixelFormat;
+use strum::EnumString;
+
+const BGRA: u32 = u32::from_be_bytes(*b"BGRA");
+const L10R: u32 = u32::from_be_bytes(*b"l10r");
+const YUV_420V: u32 = u32::from_be_bytes(*b"420v");
+const YUV_420F: u32 = u32::from_be_bytes(*b"420f");
+
+/// BT.709 YCbCr to RGB conversion in 16-bit fixed point, with luma and
+/// chroma scaled from their range to full 8 bits.
+struct YuvCoefficients {
+    luma_offset: i32,
+    luma: i32,
+    r_cr: i32,
+    g_cb: i32,
+    g_cr: i32,
+    b_cb: i32,
+}
+
+/// Luma in 16..=235 and chroma in 16..=240, as in `420v`.
+const VIDEO_RANGE: YuvCoefficients = YuvCoefficients {
+    luma_offset: 16,
+    luma: 76309,
+    r_cr: 117489,
+    g_cb: 13974,
+    g_cr: 34923,
+    b_cb: 138438,
+};
+
+/// Luma and chroma in 0..=255, as in `420f`.
+const FULL_RANGE: YuvCoefficients = YuvCoefficients {
+    luma_offset: 0,
+    luma: 65536,
+    r_cr: 103206,
+    g_cb: 12275,
+    g_cr: 30677,
+    b_cb: 121608,
+};
+
+/// Layout of the pixels of a captured `CVPixelBuffer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum BufferFormat {
+    /// 8-bit BGRA, copied as is.
+    Bgra,
+    /// Packed 10-bit ARGB, narrowed after copying.
+    L10r,
+    /// Bi-planar 4:2:0 YCbCr: a luma plane, then a plane of interleaved Cb
+    /// and Cr for every 2x2 pixels.
+    Yuv420 { full_range: bool },
+}
+
+impl BufferFormat {
+    /// Format of a `CVPixelBuffer` pixel format type, if frames can be
+    /// converted from it.
+    pub(super) fn from_fourcc(fourcc: u32) -> Option<Self> {
+        match fourcc {
+            BGRA => Some(Self::Bgra),
+            L10R => Some(Self::L10r),
+            YUV_420V => Some(Self::Yuv420 { full_range: false }),
+            YUV_420F => Some(Self::Yuv420 { full_range: true }),
+            _ => None,
+        }
+    }
+}
+
+/// Readable form of a pixel format type, e.g. `BGRA`.
+pub(super) fn fourcc_name(fourcc: u32) -> String {
+    String::from_utf8_lossy(&fourcc.to_be_bytes()).into_owned()
+}
+
+/// One plane of a locked pixel buffer.
+pub(super) struct Plane<'a> {
+    pub(super) data: &'a [u8],
+    pub(super) bytes_per_row: usize,
+}
+
+impl Plane<'_> {
+    /// Whether `height` rows of `width` bytes are within the plane.
+    pub(super) fn holds(&self, width: usize, height: usize) -> bool {
+        self.bytes_per_row >= width
+            && (height == 0 || self.data.len() >= self.bytes_per_row * (height - 1) + width)
+    }
+}
+
+/// Pixel format ScreenCaptureKit delivers frames in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, EnumString)]
+#[strum(serialize_all = "kebab-case", ascii_case_insensitive)]
+pub enum CaptureFormat {
+    /// 8-bit BGRA, sent with its alpha channel.
+    #[default]
+    Bgra,
+    /// 8-bit BGRA, sent with alpha marked unused. Areas the capture leaves
+    /// transparent, such as around composited regions, then show black
+    /// instead of depending on how the client treats alpha.
+    Bgrx,
+    /// 10-bit ARGB 2:10:10:10 for wide color displays, narrowed to 8 bits
+    /// per channel before sending.
+    L10r,
+}
+
+impl CaptureFormat {
+    pub(super) fn stream_format(self) -> PixelFormat {
+        match self {
+            Self::Bgra | Self::Bgrx => PixelFormat::BGRA,
+            Self::L10r => PixelFormat::l10r,
+        }
+    }
+
+    /// Format of the bitmap updates. 10-bit alpha has only 2 bits and is
+    /// dropped when narrowing.
+    pub(super) fn update_format(self) -> ironrdp::server::PixelFormat {
+        match self {
+            Self::Bgra => ironrdp::server::PixelFormat::BgrA32,
+            Self::Bgrx | Self::L10r => ironrdp::server::PixelFormat::BgrX32,
+        }
+    }
+}
+
+/// Narrows `l10r` pixels, little-endian words of 2 bits of alpha followed
+/// by 10 bits each of red, green and blue from the top, to opaque BGRA in
+/// place.
+pub(super) fn unpack_l10r(data: &mut [u8]) {
+    for pixel in data.chunks_exact_mut(4) {
+        let word = u32::from_le_bytes([pixel[0], pixel[1], pixel[2], pixel[3]]);
+        // The top 8 of a channel's 10 bits
+        let channel = |shift: u32| (word >> (shift + 2)) as u8;
+        pixel.copy_from_slice(&[channel(0), channel(10), channel(20), 0xFF]);
+    }
+}
+
+/// Appends the `width` x `height` rect at (`x`, `y`) of a 4:2:0 frame with
+/// the given planes as BGRA to `output`. The planes must hold the rect.
+pub(super) fn yuv420_to_bgra(
+    luma: &Plane,
+    chroma: &Plane,
+    full_range: bool,
+    (x, y, width, height): (usize, usize, usize, usize),
+    output: &mut Vec<u8>,
+) {
+    let coefficients = if full_range {
+        &FULL_RANGE
+    } else {
+        &VIDEO_RANGE
+    };
+    let clamp = |value: i32| ((value + (1 << 15)) >> 16).clamp(0, 255) as u8;
+    output.reserve(width * height * 4);
+    for row in y..y + height {
+        let luma_row = &luma.data[row * luma.bytes_per_row..];
+        let chroma_row = &chroma.data[row / 2 * chroma.bytes_per_row..];
+        for column in x..x + width {
+            let l = (luma_row[column] as i32 - coefficients.luma_offset) * coefficients.luma;
+            let cb = chroma_row[column / 2 * 2] as i32 - 128;
+            let cr = chroma_row[column / 2 * 2 + 1] as i32 - 128;
+            output.extend_from_slice(&[
+                clamp(l + coefficients.b_cb * cb),
+                clamp(l - coefficients.g_cb * cb - coefficients.g_cr * cr),
+                clamp(l + coefficients.r_cr * cr),
+                0xFF,
+            ]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_parse_by_name() {
+        assert_eq!("bgra".parse(), Ok(CaptureFormat::Bgra));
+        assert_eq!("BGRX".parse(), Ok(CaptureFormat::Bgrx));
+        assert_eq!("l10r".parse(), Ok(CaptureFormat::L10r));
+        assert!("yuv".parse::<CaptureFormat>().is_err());
+    }
+
+    #[test]
+    fn l10r_narrows_to_opaque_bgra() {
+        let pixel =
+            |a: u32, r: u32, g: u32, b: u32| (a << 30 | r << 20 | g << 10 | b).to_le_bytes();
+        let mut data = [pixel(0, 0x3FF, 0x200, 0x003), pixel(3, 0, 0x0FF, 0x3FC)].concat();
+        unpack_l10r(&mut data);
+        assert_eq!(data, [0x00, 0x80, 0xFF, 0xFF, 0xFF, 0x3F, 0x00, 0xFF]);
+    }
+
+    #[test]
+    fn buffer_formats_are_known_by_fourcc() {
+        let fourcc = |name: &[u8; 4]| u32::from_be_bytes(*name);
+        assert_eq!(
+            BufferFormat::from_fourcc(fourcc(b"BGRA")),
+            Some(BufferFormat::Bgra)
+        );
+        assert_eq!(
+            BufferFormat::from_fourcc(fourcc(b"420f")),
+            Some(BufferFormat::Yuv420 { full_range: true })
+        );
+        assert_eq!(BufferFormat::from_fourcc(fourcc(b"xf44")), None);
+        assert_eq!(fourcc_name(fourcc(b"xf44")), "xf44");
+    }
+
+    #[test]
+    fn yuv420_converts_with_shared_chroma() {
+        // 4x2 frame with row padding: black and white pixels sharing neutral
+        // chroma on the left, red on the right
+        let luma = [[16, 235, 63, 63, 0, 0], [235, 16, 63, 63, 0, 0]].concat();
+        let chroma = [128, 128, 102, 240, 0, 0];
+        let luma = Plane {
+            data: &luma,
+            bytes_per_row: 6,
+        };
+        let chroma = Plane {
+            data: &chroma,
+            bytes_per_row: 6,
+        };
+        assert!(luma.holds(4, 2) && chroma.holds(4, 1));
+
+        let mut output = Vec::new();
+        yuv420_to_bgra(&luma, &chroma, false, (0, 1, 3, 1), &mut output);
+        assert_eq!(
+            output,
+            [0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0xFF, 0, 1, 0xFF, 0xFF]
+        );
+    }
+
+    #[test]
+    fn planes_must_hold_the_rows() {
+        let data = [0; 12];
+        let plane = Plane {
+            data: &data,
+            bytes_per_row: 4,
+        };
+        assert!(plane.holds(4, 3));
+        assert!(!plane.holds(4, 4));
+        assert!(!plane.holds(5, 1));
+    }
+}
@@ -0,0 +1,120 @@
+//! Capture backend which serves a static test pattern instead of the screen,
+//! so the server runs without ScreenCaptureKit, e.g. in end-to-end tests.
+
+use std::{sync::Arc, time::Duration};
+
+use tokio::{
+    sync::{mpsc, watch},
+    task::{JoinHandle, LocalSet},
+};
+
+use crate::{
+    counter::{BitrateCounter, IntervalCounter},
+    error::Result,
+};
+
+use super::{
+    display::{CapturedData, Rect},
+    CaptureOptions, DisplayGeometry, ScreenCapture, ScreenJob, ScreenSize,
+};
+
+/// Vertical bars of the test pattern in BGRA: white, yellow, cyan and green.
+const BARS: [[u8; 4]; 4] = [
+    [0xFF, 0xFF, 0xFF, 0xFF],
+    [0x00, 0xFF, 0xFF, 0xFF],
+    [0xFF, 0xFF, 0x00, 0xFF],
+    [0x00, 0xFF, 0x00, 0xFF],
+];
+/// How often the test pattern is published again.
+pub(super) const FRAME_INTERVAL: Duration = Duration::from_millis(100);
+
+pub(super) struct MockContext {
+    pub(super) job_sender: mpsc::Sender<ScreenJob>,
+    pub(super) display_size: watch::Sender<ScreenSize>,
+    pub(super) capture_failed: watch::Sender<bool>,
+    pub(super) send_counter: IntervalCounter,
+    pub(super) bitrate: BitrateCounter,
+    pub(super) options: CaptureOptions,
+    /// Whole frame sent to every client.
+    pub(super) frame: CapturedData,
+}
+
+fn test_pattern(width: u16, height: u16) -> CapturedData {
+    let (width, height) = (width as usize, height as usize);
+    let mut data = Vec::with_capacity(width * height * 4);
+    for _ in 0..height {
+        for x in 0..width {
+            data.extend_from_slice(&BARS[x * BARS.len() / width]);
+        }
+    }
+    CapturedData {
+        timestamp: 0,
+        rects: vec![Rect {
+            x: 0,
+            y: 0,
+            width: width as _,
+            height: height as _,
+        }],
+        data,
+    }
+}
+
+impl ScreenCapture {
+    /// Capture which shows a `width` x `height` test pattern. Needs neither
+    /// a display nor the screen recording permission.
+    pub fn mock(
+        main_thread_local_set: &LocalSet,
+        (width, height): (u16, u16),
+    ) -> (Self, JoinHandle<Result<()>>) {
+        let (job_sender, mut job_receiver) = mpsc::channel::<ScreenJob>(10);
+        let (display_size, screen_size) = watch::channel(ScreenSize {
+            client: (width, height),
+            server: (width, height),
+            capture: (width, height),
+            layout: Arc::from([DisplayGeometry {
+                origin: (0.0, 0.0),
+                size: (width as f64, height as f64),
+                scale_factor: 1.0,
+            }]),
+        });
+        let (capture_failed, capture_failed_receiver) = watch::channel(false);
+        let mut context = MockContext {
+            job_sender: job_sender.clone(),
+            display_size,
+            capture_failed,
+            send_counter: IntervalCounter::new(),
+            bitrate: BitrateCounter::new(None),
+            options: CaptureOptions::default(),
+            frame: test_pattern(width, height),
+        };
+
+        let audio_channels = context.options.audio_channels;
+        let handle = main_thread_local_set.spawn_local(async move {
+            while let Some(job) = job_receiver.recv().await {
+                match job {
+                    ScreenJob::Display(job) => context.handle_display_job(job),
+                    ScreenJob::Sound(_) => tracing::debug!("Mock capture has no audio"),
+                    ScreenJob::Pause(paused) => {
+                        tracing::debug!(paused, "Mock capture never pauses")
+                    }
+                    ScreenJob::Shutdown(_) => break,
+                }
+            }
+            Ok(())
+        });
+
+        (
+            Self {
+                job_sender,
+                rdp_event_sender: Default::default(),
+                screen_size,
+                capture_failed: capture_failed_receiver,
+                last_checksum: None,
+                audio_channels,
+                logged_in: None,
+                observer: None,
+            },
+            handle,
+        )
+    }
+}
@@ -0,0 +1,174 @@
+//! Publishes captured frames into a POSIX shared memory segment so local
+//! processes can read them without going through RDP.
+//!
+//! The segment starts with a 64 byte header followed by the pixels, all
+//! fields in native endianness:
+//!
+//! | offset | type  | field                                      |
+//! |--------|-------|--------------------------------------------|
+//! | 0      | `u32` | magic, `ARSU`                              |
+//! | 4      | `u32` | layout version, currently 1                |
+//! | 8      | `u64` | sequence, odd while a frame is written     |
+//! | 16     | `u64` | frame counter                              |
+//! | 24     | `u32` | width                                      |
+//! | 28     | `u32` | height                                     |
+//! | 32     | `u32` | stride in bytes                            |
+//! | 36     | `u32` | pixel format, 1 = BGRA 8888                |
+//! | 64     | `u8`  | `stride * height` bytes of pixels          |
+//!
+//! The sequence works as a seqlock: readers load it, copy the header fields
+//! and pixels, then load it again and retry if it was odd or has changed.
+//! The segment is sized for the capture size at startup; updates outside of
+//! it are clipped.
+//!
+//! The pixels are what clients are sent, i.e. with the color depth reduced
+//! and held while capture is frozen.
+
+use std::{
+    ffi::CString,
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    sync::atomic::{fence, AtomicU64, Ordering},
+};
+
+use super::display::CapturedData;
+
+const MAGIC: u32 = u32::from_be_bytes(*b"ARSU");
+const VERSION: u32 = 1;
+const FORMAT_BGRA8888: u32 = 1;
+const DATA_OFFSET: usize = 64;
+
+#[repr(C)]
+struct Header {
+    magic: u32,
+    version: u32,
+    sequence: AtomicU64,
+    frame_counter: u64,
+    width: u32,
+    height: u32,
+    stride: u32,
+    format: u32,
+}
+
+const _: () = assert!(std::mem::size_of::<Header>() <= DATA_OFFSET);
+
+pub(super) struct SharedFrame {
+    name: CString,
+    base: *mut u8,
+    len: usize,
+    width: usize,
+    height: usize,
+}
+
+// The mapping is only written through `&mut self`.
+unsafe impl Send for SharedFrame {}
+
+impl SharedFrame {
+    pub(super) fn create(name: &str, width: u16, height: u16) -> io::Result<Self> {
+        let name = CString::new(name)?;
+        let (width, height) = (width as usize, height as usize);
+        let len = DATA_OFFSET + width * 4 * height;
+
+        // A segment left over by a previous run may have a different size,
+        // and macOS doesn't allow resizing it.
+        unsafe { libc::shm_unlink(name.as_ptr()) };
+        let fd = unsafe {
+            libc::shm_open(
+                name.as_ptr(),
+                libc::O_CREAT | libc::O_EXCL | libc::O_RDWR,
+                0o600 as libc::c_uint,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        if unsafe { libc::ftruncate(fd.as_raw_fd(), len as libc::off_t) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let base = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd.as_raw_fd(),
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        unsafe {
+            base.cast::<Header>().write(Header {
+                magic: MAGIC,
+                version: VERSION,
+                sequence: AtomicU64::new(0),
+                frame_counter: 0,
+                width: width as u32,
+                height: height as u32,
+                stride: (width * 4) as u32,
+                format: FORMAT_BGRA8888,
+            })
+        };
+
+        Ok(Self {
+            name,
+            base: base.cast(),
+            len,
+            width,
+            height,
+        })
+    }
+
+    fn header(&self) -> *mut Header {
+        self.base.cast()
+    }
+
+    /// Blits the published rects into the segment.
+    pub(super) fn write(&mut self, frame: &CapturedData) {
+        let header = self.header();
+        let sequence = unsafe { &(*header).sequence };
+        sequence.fetch_add(1, Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        for (rect, data) in frame.rect_data() {
+            let x = rect.x as usize;
+            let y = rect.y as usize;
+            if x >= self.width || y >= self.height {
+                continue;
+            }
+            let copy_width = (rect.width as usize).min(self.width - x);
+            let copy_height = (rect.height as usize).min(self.height - y);
+            let src_stride = rect.width as usize * 4;
+            let dst_stride = self.width * 4;
+
+            for row in 0..copy_height {
+                let src = &data[row * src_stride..][..copy_width * 4];
+                unsafe {
+                    std::ptr::copy_nonoverlapping(
+                        src.as_ptr(),
+                        self.base.add(DATA_OFFSET + (y + row) * dst_stride + x * 4),
+                        copy_width * 4,
+                    );
+                }
+            }
+        }
+        unsafe {
+            let frame_counter = std::ptr::addr_of_mut!((*header).frame_counter);
+            frame_counter.write_volatile(frame_counter.read_volatile() + 1);
+        }
+
+        sequence.fetch_add(1, Ordering::Release);
+    }
+}
+
+impl Drop for SharedFrame {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base.cast(), self.len);
+            libc::shm_unlink(self.name.as_ptr());
+        }
+    }
+}
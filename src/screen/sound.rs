@@ -12,15 +12,84 @@ use tokio::sync::mpsc;
 use tokio::sync::mpsc::UnboundedSender;
 
 use std::sync::{
-    atomic::{AtomicU32, Ordering},
+    atomic::{AtomicU32, AtomicU64, Ordering},
     Arc, RwLock,
 };
 
-use super::{ScreenCapture, ScreenJob};
+use super::{ScreenCapture, ScreenJob, ScreenOutputIndex};
+use crate::counter::BitrateCounter;
 
 pub const SAMPLE_RATE: u32 = 48000;
 pub const BITS_PER_SAMPLE: u16 = 32;
-pub const CHANNELS: u16 = 1;
+const SAMPLE_LEN: usize = BITS_PER_SAMPLE as usize / 8;
+
+/// Format audio is sent in with `channels` channels.
+fn audio_format(channels: u16) -> AudioFormat {
+    AudioFormat {
+        format: WaveFormat::PCM,
+        n_channels: channels,
+        n_samples_per_sec: SAMPLE_RATE,
+        n_avg_bytes_per_sec: SAMPLE_RATE * (channels * BITS_PER_SAMPLE) as u32 / 8,
+        n_block_align: channels * BITS_PER_SAMPLE / 8,
+        bits_per_sample: BITS_PER_SAMPLE,
+        data: None,
+    }
+}
+
+/// Sample frames in `len` bytes of captured PCM.
+fn buffer_frames(len: usize, channels: u16) -> u64 {
+    (len / (channels as usize * SAMPLE_LEN)) as u64
+}
+
+/// Timestamps of buffers without a presentation time, counted in sample
+/// frames from the last one that had it, so rounding to milliseconds doesn't
+/// add up.
+#[derive(Debug, Default)]
+struct AudioClock {
+    /// Presentation time of the last buffer which had it.
+    base: AtomicU32,
+    /// Sample frames since `base`.
+    frames: AtomicU64,
+}
+
+impl AudioClock {
+    /// Timestamp of a buffer with the given presentation time in
+    /// milliseconds, which restarts the count.
+    fn timestamp(&self, presentation: Option<u32>) -> u32 {
+        if let Some(ts) = presentation {
+            self.base.store(ts, Ordering::SeqCst);
+            self.frames.store(0, Ordering::SeqCst);
+            return ts;
+        }
+        let frames = self.frames.load(Ordering::SeqCst);
+        let elapsed = (frames * 1000 / SAMPLE_RATE as u64) as u32;
+        self.base.load(Ordering::SeqCst).wrapping_add(elapsed)
+    }
+
+    fn advance(&self, frames: u64) {
+        self.frames.fetch_add(frames, Ordering::SeqCst);
+    }
+}
+
+/// Interleaves the per-channel buffers ScreenCaptureKit delivers into the
+/// frames RDP expects. A single buffer is already interleaved.
+fn interleave(planes: &[&[u8]]) -> Vec<u8> {
+    if let [plane] = planes {
+        return plane.to_vec();
+    }
+    let frames = planes
+        .iter()
+        .map(|plane| plane.len() / SAMPLE_LEN)
+        .min()
+        .unwrap_or(0);
+    let mut data = Vec::with_capacity(frames * SAMPLE_LEN * planes.len());
+    for frame in 0..frames {
+        for plane in planes {
+            data.extend_from_slice(&plane[frame * SAMPLE_LEN..][..SAMPLE_LEN]);
+        }
+    }
+    data
+}
 
 pub(crate) enum Job {
     Start,
@@ -43,6 +112,7 @@ impl ServerEventSender for ScreenCapture {
 struct SoundServer {
     job_sender: mpsc::Sender<ScreenJob>,
     rdp_event_sender: Arc<RwLock<Option<mpsc::UnboundedSender<ServerEvent>>>>,
+    formats: Vec<AudioFormat>,
 }
 
 impl SoundServerFactory for ScreenCapture {
@@ -50,6 +120,7 @@ impl SoundServerFactory for ScreenCapture {
         Box::new(SoundServer {
             job_sender: self.job_sender.clone(),
             rdp_event_sender: self.rdp_event_sender.clone(),
+            formats: vec![audio_format(self.audio_channels)],
         })
     }
 }
@@ -70,20 +141,13 @@ impl RdpsndServerHandler for SoundServer {
     fn get_formats(&self) -> &[AudioFormat] {
         tracing::info!("get sound format");
 
-        &[AudioFormat {
-            format: WaveFormat::PCM,
-            n_channels: CHANNELS,
-            n_samples_per_sec: SAMPLE_RATE,
-            n_avg_bytes_per_sec: SAMPLE_RATE * (CHANNELS * BITS_PER_SAMPLE) as u32 / 8,
-            n_block_align: 16 * 8, // 16 bytes
-            bits_per_sample: BITS_PER_SAMPLE,
-            data: None,
-        }]
+        &self.formats
     }
 
     fn start(&mut self, client_format: &ClientAudioFormatPdu) -> Option<u16> {
         let Some(format_idx) = self.choose_format(&client_format.formats) else {
-            return Some(0);
+            tracing::warn!("Client supports no format audio is captured in, declining");
+            return None;
         };
         let _ = self.job_sender.try_send(ScreenJob::Sound(Job::Start));
         Some(format_idx)
@@ -96,7 +160,10 @@ impl RdpsndServerHandler for SoundServer {
 
 struct AudioCaptureDelegate {
     sender: Arc<RwLock<Option<UnboundedSender<ServerEvent>>>>,
-    ts: AtomicU32,
+    /// Fallback for samples without a presentation time.
+    clock: AudioClock,
+    bitrate: BitrateCounter,
+    channels: u16,
 }
 
 impl SCStreamOutputTrait for AudioCaptureDelegate {
@@ -115,19 +182,30 @@ impl SCStreamOutputTrait for AudioCaptureDelegate {
         else {
             return;
         };
-        let Some(buffer) = audio_buffer_list.get(0) else {
+        let buffers = (0..self.channels as usize)
+            .map_while(|index| audio_buffer_list.get(index))
+            .collect::<Vec<_>>();
+        if buffers.is_empty() {
             return;
-        };
-        let data = buffer.data();
+        }
+        let data = interleave(
+            &buffers
+                .iter()
+                .map(|buffer| buffer.data())
+                .collect::<Vec<_>>(),
+        );
+        let frames = buffer_frames(data.len(), self.channels);
+        let ts = self
+            .clock
+            .timestamp(super::presentation_millis(&sample_buffer).map(|millis| millis as u32));
 
         let sender = self.sender.write().unwrap();
         if let Some(sender) = sender.as_ref() {
-            let _ = sender.send(ServerEvent::Rdpsnd(RdpsndServerMessage::Wave(
-                data.to_vec(),
-                self.ts.load(Ordering::SeqCst),
-            )));
+            // Audio is never throttled, only counted, so video backs off first
+            self.bitrate.record(data.len());
+            let _ = sender.send(ServerEvent::Rdpsnd(RdpsndServerMessage::Wave(data, ts)));
         }
-        self.ts.fetch_add(100, Ordering::SeqCst);
+        self.clock.advance(frames);
     }
 }
 
@@ -135,18 +213,69 @@ impl super::ScreenCaptureContext {
     pub(crate) fn handle_sound_job(&mut self, job: Job) {
         match job {
             Job::Start => {
+                if self.sound_output.is_some() {
+                    tracing::debug!("Sound capture is already running");
+                    return;
+                }
                 let delegate = AudioCaptureDelegate {
                     sender: self.rdp_event_sender.clone(),
-                    ts: AtomicU32::new(0),
+                    clock: AudioClock::default(),
+                    bitrate: self.bitrate.clone(),
+                    channels: self.options.audio_channels,
                 };
                 tracing::info!("sound start");
-                self.stream
-                    .add_output_handler(delegate, SCStreamOutputType::Audio);
+                self.ensure_capture_running();
+                self.sound_output = self.streams[0]
+                    .add_output_handler(delegate, SCStreamOutputType::Audio)
+                    .map(ScreenOutputIndex::new);
+                if self.sound_output.is_none() {
+                    tracing::error!("Failed to add audio output");
+                }
             }
             Job::Stop => {
                 tracing::info!("sound stop");
-                // self.stream.remove_output_handler(index, SCStreamOutputType::Audio);
+                if let Some(index) = self.sound_output.take() {
+                    self.streams[0]
+                        .remove_output_handler(index.to_raw(), SCStreamOutputType::Audio);
+                    self.mark_idle_if_unused();
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffer_frames_count_every_channel() {
+        assert_eq!(buffer_frames(SAMPLE_RATE as usize * SAMPLE_LEN, 1), 48000);
+        assert_eq!(buffer_frames(SAMPLE_RATE as usize * SAMPLE_LEN, 2), 24000);
+        assert_eq!(buffer_frames(0, 2), 0);
+    }
+
+    #[test]
+    fn timestamps_follow_the_total_sample_count() {
+        let clock = AudioClock::default();
+        assert_eq!(clock.timestamp(Some(100)), 100);
+        // 21.33 ms each, which would lose a millisecond truncated one by one
+        for _ in 0..3 {
+            clock.advance(1024);
+        }
+        assert_eq!(clock.timestamp(None), 164);
+        assert_eq!(clock.timestamp(Some(500)), 500);
+        assert_eq!(clock.timestamp(None), 500);
+    }
+
+    #[test]
+    fn planes_are_interleaved_per_sample() {
+        let left = [1, 1, 1, 1, 3, 3, 3, 3];
+        let right = [2, 2, 2, 2, 4, 4, 4, 4];
+        assert_eq!(
+            interleave(&[&left, &right]),
+            [1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4]
+        );
+        assert_eq!(interleave(&[&left]), left);
+    }
+}
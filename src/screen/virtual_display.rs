@@ -0,0 +1,103 @@
+//! Virtual display backed by the private `CGVirtualDisplay` CoreGraphics
+//! classes, which is what AirPlay and Sidecar use for extra displays.
+//!
+//! The display exists as long as the `CGVirtualDisplay` object is alive, and
+//! the window server also removes it when the process exits. Its mode can be
+//! switched to any size up to the largest client monitor.
+
+use objc2::{
+    msg_send,
+    rc::Retained,
+    runtime::{AnyClass, AnyObject, Bool},
+};
+use objc2_core_foundation::CGSize;
+use objc2_core_graphics::CGDirectDisplayID;
+use objc2_foundation::{NSArray, NSString};
+
+use super::display::MAX_CLIENT_DIMENSION;
+use crate::error::{ArisuError, Result};
+
+const REFRESH_RATE: f64 = 60.0;
+/// Physical size reported for the display, roughly a 24" monitor.
+const SIZE_IN_MILLIMETERS: CGSize = CGSize {
+    width: 530.0,
+    height: 300.0,
+};
+
+pub(super) struct VirtualDisplay {
+    /// Releasing this removes the display from the system.
+    display: Retained<AnyObject>,
+    display_id: CGDirectDisplayID,
+}
+
+fn class(name: &str) -> Result<&'static AnyClass> {
+    AnyClass::get(&std::ffi::CString::new(name).unwrap())
+        .ok_or_else(|| ArisuError::VirtualDisplay(format!("{name} is not available")))
+}
+
+impl VirtualDisplay {
+    pub(super) fn create(width: u16, height: u16) -> Result<Self> {
+        let descriptor: Retained<AnyObject> =
+            unsafe { msg_send![class("CGVirtualDisplayDescriptor")?, new] };
+        unsafe {
+            let _: () = msg_send![&descriptor, setName: &*NSString::from_str("ARISU")];
+            let _: () = msg_send![&descriptor, setMaxPixelsWide: MAX_CLIENT_DIMENSION];
+            let _: () = msg_send![&descriptor, setMaxPixelsHigh: MAX_CLIENT_DIMENSION];
+            let _: () = msg_send![&descriptor, setSizeInMillimeters: SIZE_IN_MILLIMETERS];
+            let _: () = msg_send![&descriptor, setVendorID: 0x4152_u32];
+            let _: () = msg_send![&descriptor, setProductID: 0x5355_u32];
+            let _: () = msg_send![&descriptor, setSerialNum: 1_u32];
+        }
+
+        let display: Option<Retained<AnyObject>> = unsafe {
+            let display: *mut AnyObject = msg_send![class("CGVirtualDisplay")?, alloc];
+            msg_send![display, initWithDescriptor: &*descriptor]
+        };
+        let display = display
+            .ok_or_else(|| ArisuError::VirtualDisplay("failed to create display".to_owned()))?;
+        let display_id: CGDirectDisplayID = unsafe { msg_send![&display, displayID] };
+        let virtual_display = Self {
+            display,
+            display_id,
+        };
+        virtual_display.set_size(width, height)?;
+        tracing::info!(display_id, width, height, "Created virtual display");
+
+        Ok(virtual_display)
+    }
+
+    /// Switches the display to a single mode of `width` x `height` pixels.
+    pub(super) fn set_size(&self, width: u16, height: u16) -> Result<()> {
+        let (width, height) = (width as usize, height as usize);
+        let mode: Option<Retained<AnyObject>> = unsafe {
+            let mode: *mut AnyObject = msg_send![class("CGVirtualDisplayMode")?, alloc];
+            msg_send![mode, initWithWidth: width, height: height, refreshRate: REFRESH_RATE]
+        };
+        let mode =
+            mode.ok_or_else(|| ArisuError::VirtualDisplay("failed to create mode".to_owned()))?;
+        let settings: Retained<AnyObject> =
+            unsafe { msg_send![class("CGVirtualDisplaySettings")?, new] };
+        let modes = NSArray::from_retained_slice(&[mode]);
+        let applied: Bool = unsafe {
+            let _: () = msg_send![&settings, setHiDPI: 0_u32];
+            let _: () = msg_send![&settings, setModes: &*modes];
+            msg_send![&self.display, applySettings: &*settings]
+        };
+        if !applied.as_bool() {
+            return Err(ArisuError::VirtualDisplay(format!(
+                "failed to apply {width}x{height} mode"
+            )));
+        }
+        Ok(())
+    }
+
+    pub(super) fn display_id(&self) -> CGDirectDisplayID {
+        self.display_id
+    }
+}
+
+impl Drop for VirtualDisplay {
+    fn drop(&mut self) {
+        tracing::info!(display_id = self.display_id, "Removing virtual display");
+    }
+}
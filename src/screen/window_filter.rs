@@ -0,0 +1,165 @@
+//! Selects the windows and applications included in or excluded from the
+//! capture.
+
+use screencapturekit::shareable_content::{SCRunningApplication, SCWindow};
+
+/// Window levels from `CGWindowLevel.h`.
+const DOCK_WINDOW_LEVEL: i64 = 20;
+const MAIN_MENU_WINDOW_LEVEL: i64 = 24;
+const STATUS_WINDOW_LEVEL: i64 = 25;
+const DOCK_BUNDLE_ID: &str = "com.apple.dock";
+
+/// Window pattern, a window id or otherwise a substring of the title.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WindowMatch {
+    Id(u32),
+    Title(String),
+}
+
+impl std::str::FromStr for WindowMatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("empty window pattern".to_owned());
+        }
+        Ok(match s.parse() {
+            Ok(id) => Self::Id(id),
+            Err(_) => Self::Title(s.to_owned()),
+        })
+    }
+}
+
+impl WindowMatch {
+    fn matches(&self, id: u32, title: &str) -> bool {
+        match self {
+            Self::Id(expected) => *expected == id,
+            Self::Title(pattern) => title.contains(pattern.as_str()),
+        }
+    }
+}
+
+/// Windows matching any of `patterns`, warning about patterns matching none.
+pub(super) fn select<'a>(patterns: &[WindowMatch], windows: &'a [SCWindow]) -> Vec<&'a SCWindow> {
+    let windows = windows
+        .iter()
+        .map(|window| (window, window.window_id(), window.title()))
+        .collect::<Vec<_>>();
+    for pattern in patterns {
+        if !windows
+            .iter()
+            .any(|(_, id, title)| pattern.matches(*id, title))
+        {
+            tracing::warn!(?pattern, "Window pattern matches no window");
+        }
+    }
+    windows
+        .into_iter()
+        .filter(|(_, id, title)| patterns.iter().any(|pattern| pattern.matches(*id, title)))
+        .map(|(window, _, _)| window)
+        .collect()
+}
+
+/// Running applications with the given bundle identifiers, warning about
+/// ones which aren't running.
+pub(super) fn applications<'a>(
+    bundle_ids: &[String],
+    applications: &'a [SCRunningApplication],
+) -> Vec<&'a SCRunningApplication> {
+    let applications = applications
+        .iter()
+        .map(|application| (application, application.bundle_identifier()))
+        .collect::<Vec<_>>();
+    let mut selected = Vec::new();
+    for bundle_id in bundle_ids {
+        let found = applications
+            .iter()
+            .filter(|(_, id)| id == bundle_id)
+            .map(|(application, _)| *application)
+            .collect::<Vec<_>>();
+        if found.is_empty() {
+            tracing::warn!(bundle_id, "Application is not running, not excluding it");
+        }
+        selected.extend(found);
+    }
+    selected
+}
+
+/// This process as a running application, which owns the windows the server
+/// shows on the local display itself.
+pub(super) fn own_application(
+    applications: &[SCRunningApplication],
+) -> Option<&SCRunningApplication> {
+    let pid = std::process::id() as i32;
+    let found = applications
+        .iter()
+        .find(|application| application.process_id() == pid);
+    if found.is_none() {
+        tracing::warn!(
+            pid,
+            "Own application not found, its windows may be captured"
+        );
+    }
+    found
+}
+
+/// System UI which can be left out of the capture.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum SystemUi {
+    Dock,
+    /// The menu bar and the status items on it.
+    MenuBar,
+}
+
+/// Windows making up `ui`, found by owner and window level. The Dock app
+/// also draws the wallpaper, so only its window at the Dock level is used.
+pub(super) fn system_windows(ui: SystemUi, windows: &[SCWindow]) -> Vec<&SCWindow> {
+    let found = windows
+        .iter()
+        .filter(|window| {
+            let level = window.window_layer() as i64;
+            match ui {
+                SystemUi::Dock => {
+                    level == DOCK_WINDOW_LEVEL
+                        && window.owning_application().bundle_identifier() == DOCK_BUNDLE_ID
+                }
+                SystemUi::MenuBar => {
+                    level == MAIN_MENU_WINDOW_LEVEL || level == STATUS_WINDOW_LEVEL
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+    if found.is_empty() {
+        tracing::warn!(
+            ?ui,
+            "Could not identify system UI windows on this macOS version"
+        );
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_are_window_ids() {
+        assert_eq!("1234".parse(), Ok(WindowMatch::Id(1234)));
+        assert_eq!(
+            "Untitled 2".parse(),
+            Ok(WindowMatch::Title("Untitled 2".to_owned()))
+        );
+        assert!("".parse::<WindowMatch>().is_err());
+    }
+
+    #[test]
+    fn titles_match_by_substring() {
+        let pattern = WindowMatch::Title("Zoom".to_owned());
+        assert!(pattern.matches(1, "Zoom Meeting"));
+        assert!(!pattern.matches(1, "zoom meeting"));
+
+        let pattern = WindowMatch::Id(7);
+        assert!(pattern.matches(7, ""));
+        assert!(!pattern.matches(8, "7"));
+    }
+}
@@ -0,0 +1,582 @@
+use std::{
+    cell::{Cell, RefCell},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    rc::Rc,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use ironrdp::{
+    connector::{sspi, ConnectorError, ConnectorErrorKind},
+    pdu::rdp::capability_sets::{server_codecs_capabilities, BitmapCodecs},
+    server::{CliprdrServerFactory, Credentials, RdpServer, ServerEvent, TlsIdentityCtx},
+};
+use socket2::{Domain, Protocol, Socket, Type};
+use strum::EnumString;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{mpsc::UnboundedSender, oneshot},
+};
+
+use crate::{
+    audit::AuditLog,
+    clipboard::{ClipboardMode, PasteboardCliprdrFactory},
+    credential::{CredentialStore, LoginOutcome},
+    error::{ArisuError, Result},
+    input::InputOptions,
+    multi_client::{ControlLease, InputControl, MultiClientPolicy},
+    screen::ScreenCapture,
+    Security,
+};
+
+/// Request for the local user to approve an incoming connection.
+pub struct ConsentRequest {
+    pub peer: SocketAddr,
+    pub reply: oneshot::Sender<bool>,
+}
+
+/// Session lifecycle shown to the local user. Only clients which logged in
+/// are connected, and every `Connected` is followed by a `Disconnected`.
+#[derive(Debug, Clone, Copy)]
+pub enum SessionEvent {
+    Connected(SocketAddr),
+    Disconnected(SocketAddr),
+}
+
+/// Hooks for an app embedding the server, called on the server thread. Every
+/// method does nothing unless overridden.
+pub trait ServerObserver: Send + Sync {
+    /// A client logged in and its session started.
+    fn on_client_connected(&self, _peer: SocketAddr) {}
+
+    /// The session of a client which logged in ended.
+    fn on_client_disconnected(&self, _peer: SocketAddr) {}
+
+    /// A client was refused for a wrong user name or password. Connections
+    /// dropped before logging in for other reasons aren't reported.
+    ///
+    /// The user name the client tried isn't passed: IronRDP checks the
+    /// credentials itself and its error doesn't carry them.
+    fn on_auth_failed(&self, _peer: SocketAddr) {}
+
+    /// A client asked for its monitor to be `width` x `height`.
+    fn on_resolution_changed(&self, _width: u16, _height: u16) {}
+}
+
+pub struct ConsentPrompt {
+    sender: std::sync::mpsc::Sender<ConsentRequest>,
+    timeout: Duration,
+}
+
+impl ConsentPrompt {
+    pub fn new(sender: std::sync::mpsc::Sender<ConsentRequest>, timeout: Duration) -> Self {
+        Self { sender, timeout }
+    }
+
+    async fn ask(&self, peer: SocketAddr) -> bool {
+        let (reply, receiver) = oneshot::channel();
+        if self.sender.send(ConsentRequest { peer, reply }).is_err() {
+            tracing::error!("Consent prompt is not available");
+            return false;
+        }
+
+        match tokio::time::timeout(self.timeout, receiver).await {
+            Ok(Ok(approved)) => approved,
+            Ok(Err(_)) => {
+                tracing::warn!(%peer, "Consent prompt dropped without answer");
+                false
+            }
+            Err(_) => {
+                tracing::warn!(%peer, "Consent prompt timed out");
+                false
+            }
+        }
+    }
+}
+
+/// Codec display updates are sent in to clients which support it. Others
+/// get uncompressed bitmaps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString)]
+#[strum(serialize_all = "kebab-case", ascii_case_insensitive)]
+pub enum Codec {
+    /// Uncompressed bitmaps, least work for the CPU.
+    Bitmap,
+    /// RemoteFX, far less bandwidth for full-motion content.
+    #[strum(serialize = "remotefx", serialize = "rfx")]
+    RemoteFx,
+}
+
+impl Codec {
+    fn capabilities(self) -> BitmapCodecs {
+        let config: &[&str] = match self {
+            Self::Bitmap => &["remotefx:off"],
+            Self::RemoteFx => &["remotefx:on"],
+        };
+        server_codecs_capabilities(config).expect("codec configuration is valid")
+    }
+}
+
+pub struct ServerContext {
+    pub security: Security,
+    pub identity: Option<TlsIdentityCtx>,
+    pub credentials: CredentialStore,
+    pub screen: ScreenCapture,
+    pub input_options: InputOptions,
+    pub consent: Option<ConsentPrompt>,
+    /// Concurrent sessions allowed, one unless a multi-client policy is set.
+    pub max_sessions: Option<usize>,
+    /// Let a new session which logs in past the limit end the oldest ones.
+    pub takeover: bool,
+    /// Who gets input when several clients are connected, everyone if unset.
+    pub input_control: Option<InputControl>,
+    pub active_sessions: Cell<usize>,
+    /// Event senders of the running sessions, oldest first.
+    pub session_senders: RefCell<Vec<(u64, UnboundedSender<ServerEvent>)>>,
+    pub audit: Option<AuditLog>,
+    pub next_session_id: Cell<u64>,
+    pub tcp_nodelay: bool,
+    pub clipboard: ClipboardMode,
+    pub codec: Codec,
+    pub session_events: Option<std::sync::mpsc::Sender<SessionEvent>>,
+    pub observer: Option<Arc<dyn ServerObserver>>,
+}
+
+/// How often a session checks whether its client logged in.
+const LOGIN_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Keeps `ServerContext::active_sessions` counted while a session is alive.
+struct SessionGuard<'a>(&'a Cell<usize>);
+
+impl<'a> SessionGuard<'a> {
+    fn new(active_sessions: &'a Cell<usize>) -> Self {
+        active_sessions.set(active_sessions.get() + 1);
+        Self(active_sessions)
+    }
+}
+
+impl Drop for SessionGuard<'_> {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() - 1);
+    }
+}
+
+impl ServerContext {
+    fn max_sessions(&self) -> Option<usize> {
+        match self.input_control.as_ref().map(InputControl::policy) {
+            Some(MultiClientPolicy::Exclusive) => {
+                Some(self.max_sessions.map_or(1, |max| max.min(1)))
+            }
+            Some(_) => self.max_sessions,
+            None => Some(self.max_sessions.unwrap_or(1)),
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.max_sessions()
+            .is_some_and(|max_sessions| self.active_sessions.get() >= max_sessions)
+    }
+
+    /// Disconnects the oldest sessions other than `session_id` which are
+    /// past the limit.
+    fn take_over(&self, session_id: u64) {
+        let Some(max_sessions) = self.max_sessions() else {
+            return;
+        };
+        let senders = self.session_senders.borrow();
+        let excess = senders.len().saturating_sub(max_sessions);
+        for (id, sender) in senders
+            .iter()
+            .filter(|(id, _)| *id != session_id)
+            .take(excess)
+        {
+            tracing::info!(session_id = id, by = session_id, "Session taken over");
+            let _ = sender.send(ServerEvent::Quit("another client took over".to_owned()));
+        }
+    }
+
+    fn build_server(
+        &self,
+        addr: SocketAddr,
+        session_id: u64,
+        credentials: Credentials,
+        screen: ScreenCapture,
+        control: Option<ControlLease>,
+    ) -> Result<RdpServer> {
+        let server_builder = RdpServer::builder().with_addr(addr);
+        let server_builder = if let Some(identity) = &self.identity {
+            let acceptor = identity.make_acceptor().map_err(ArisuError::Tls)?;
+
+            if self.security == Security::Hybrid {
+                server_builder.with_hybrid(acceptor, identity.pub_key.clone())
+            } else {
+                server_builder.with_tls(acceptor)
+            }
+        } else {
+            server_builder.with_no_security()
+        };
+
+        let mut server = server_builder
+            .with_input_handler(
+                self.screen
+                    .input_handler(
+                        self.input_options.clone(),
+                        self.audit.as_ref().map(|audit| audit.session(session_id)),
+                    )
+                    .with_control(control),
+            )
+            .with_display_handler(screen)
+            .with_bitmap_codecs(self.codec.capabilities())
+            .with_cliprdr_factory(
+                PasteboardCliprdrFactory::new(self.clipboard)
+                    .map(|factory| Box::new(factory) as Box<dyn CliprdrServerFactory>),
+            )
+            // .with_sound_factory(Some(Box::new(screen_handler)))
+            .build();
+        server.set_credentials(Some(credentials));
+
+        Ok(server)
+    }
+
+    async fn run_session(&self, stream: TcpStream, peer: SocketAddr) -> anyhow::Result<()> {
+        let local_addr = stream.local_addr().context("failed to get local address")?;
+        if let Err(e) = stream.set_nodelay(self.tcp_nodelay) {
+            tracing::warn!(?e, %peer, "Failed to set TCP_NODELAY");
+        }
+        let session_id = self.next_session_id.get();
+        self.next_session_id.set(session_id + 1);
+
+        let taking_over = self.takeover && self.is_full();
+        if self.is_full() && !taking_over {
+            tracing::warn!(
+                %peer,
+                active_sessions = self.active_sessions.get(),
+                "Rejecting connection - server is full"
+            );
+            // The quit event is picked up once the connection is established,
+            // so the client gets a server-initiated disconnect instead of a
+            // dropped socket.
+            let mut server = self.build_server(
+                local_addr,
+                session_id,
+                self.credentials.peek(),
+                self.screen.clone(),
+                None,
+            )?;
+            let _ = server
+                .event_sender()
+                .send(ServerEvent::Quit("server is full".to_owned()));
+            return server.run_connection(stream).await;
+        }
+        // Taken before waiting for consent, so connections arriving
+        // meanwhile see the slot as used. Returning releases it.
+        let _session = SessionGuard::new(&self.active_sessions);
+
+        if let Some(consent) = &self.consent {
+            tracing::info!(%peer, "Waiting for local user consent");
+            if !consent.ask(peer).await {
+                tracing::warn!(%peer, "Connection rejected - local user did not approve");
+                return Ok(());
+            }
+        }
+
+        let Some(credentials) = self.credentials.issue(peer.ip()) else {
+            tracing::warn!(%peer, "Connection refused - no password available");
+            return Ok(());
+        };
+        tracing::info!(%peer, session_id, "Session started");
+        let (screen, logged_in) = self.screen.for_session();
+        let control = self
+            .input_control
+            .as_ref()
+            .map(|control| control.join(session_id));
+        // Whether the client logged in and was reported as connected
+        let mut connected = false;
+        let result = match self.build_server(local_addr, session_id, credentials, screen, control) {
+            Ok(mut server) => {
+                self.session_senders
+                    .borrow_mut()
+                    .push((session_id, server.event_sender().clone()));
+                let connection = server.run_connection(stream);
+                tokio::pin!(connection);
+                let logged_in_check = async {
+                    while !logged_in.load(Ordering::Acquire) {
+                        tokio::time::sleep(LOGIN_POLL_INTERVAL).await;
+                    }
+                };
+                let result = tokio::select! {
+                    result = &mut connection => result,
+                    () = logged_in_check => {
+                        connected = true;
+                        if let Some(session_events) = &self.session_events {
+                            let _ = session_events.send(SessionEvent::Connected(peer));
+                        }
+                        if let Some(observer) = &self.observer {
+                            observer.on_client_connected(peer);
+                        }
+                        // Only a client which logged in gets to end the others
+                        if taking_over {
+                            self.take_over(session_id);
+                        }
+                        connection.await
+                    }
+                };
+                self.session_senders
+                    .borrow_mut()
+                    .retain(|(id, _)| *id != session_id);
+                result
+            }
+            Err(e) => Err(e.into()),
+        };
+        let outcome = if logged_in.load(Ordering::Acquire) {
+            LoginOutcome::LoggedIn
+        } else if result.as_ref().is_err_and(is_credential_rejection) {
+            LoginOutcome::Rejected
+        } else {
+            LoginOutcome::Abandoned
+        };
+        self.credentials.report(peer.ip(), outcome);
+        if let Some(session_events) = self.session_events.as_ref().filter(|_| connected) {
+            let _ = session_events.send(SessionEvent::Disconnected(peer));
+        }
+        if let Some(observer) = &self.observer {
+            if connected {
+                observer.on_client_disconnected(peer);
+            } else if outcome == LoginOutcome::Rejected {
+                observer.on_auth_failed(peer);
+            }
+        }
+        result
+    }
+}
+
+/// Whether a connection failed because the client's credentials were
+/// rejected, rather than e.g. being dropped or failing TLS. ironrdp checks
+/// the password itself, so this is told from the error it ended with.
+fn is_credential_rejection(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        let rejected =
+            cause
+                .downcast_ref::<ConnectorError>()
+                .is_some_and(|error| match error.kind() {
+                    // A password mismatch without CredSSP
+                    ConnectorErrorKind::General => error.context == "invalid credentials",
+                    ConnectorErrorKind::AccessDenied => true,
+                    _ => false,
+                });
+        // NTLM under CredSSP fails the message integrity check for a wrong
+        // password and denies an unknown user
+        rejected
+            || cause.downcast_ref::<sspi::Error>().is_some_and(|error| {
+                matches!(
+                    error.error_type,
+                    sspi::ErrorKind::LogonDenied | sspi::ErrorKind::MessageAltered
+                )
+            })
+    })
+}
+
+/// Addresses to listen on. With `dual_stack`, listening on either `0.0.0.0`
+/// or `::` listens on the other too.
+pub fn listen_addrs(hosts: &[IpAddr], port: u16, dual_stack: bool) -> Vec<SocketAddr> {
+    let mut hosts = hosts.to_vec();
+    let wildcards = [Ipv4Addr::UNSPECIFIED.into(), Ipv6Addr::UNSPECIFIED.into()];
+    if dual_stack && wildcards.iter().any(|wildcard| hosts.contains(wildcard)) {
+        hosts.extend(wildcards);
+    }
+    let mut addrs = Vec::with_capacity(hosts.len());
+    for host in hosts {
+        let addr = SocketAddr::new(host, port);
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+    addrs
+}
+
+/// Binds a listening socket on `addr`.
+///
+/// `only_v6` keeps an IPv6 socket from also claiming the IPv4 port, which is
+/// required when a separate IPv4 listener shares the same port.
+pub fn bind(addr: SocketAddr, only_v6: bool) -> Result<TcpListener> {
+    let bind = || -> std::io::Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        if addr.is_ipv6() {
+            socket.set_only_v6(only_v6)?;
+        }
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(128)?;
+
+        TcpListener::from_std(socket.into())
+    };
+
+    bind().map_err(|source| ArisuError::Bind { addr, source })
+}
+
+/// Retry schedule for auxiliary listeners, which must not take the RDP
+/// server down when their port is taken.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub attempts: u32,
+}
+
+impl Backoff {
+    /// Delay after the failed `attempt`, counted from zero.
+    fn delay(&self, attempt: u32) -> Duration {
+        self.initial
+            .saturating_mul(1 << attempt.min(31))
+            .min(self.max)
+    }
+
+    /// Calls `bind` until it succeeds, sleeping with exponential backoff in
+    /// between. Gives up with `None` after `attempts` failures.
+    pub async fn retry<T, E: std::fmt::Debug>(
+        &self,
+        mut bind: impl FnMut() -> std::result::Result<T, E>,
+    ) -> Option<T> {
+        for attempt in 0..self.attempts {
+            match bind() {
+                Ok(listener) => return Some(listener),
+                Err(e) if attempt + 1 < self.attempts => {
+                    let delay = self.delay(attempt);
+                    tracing::warn!(?e, ?delay, "Failed to bind, retrying");
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => tracing::error!(?e, attempts = self.attempts, "Failed to bind"),
+            }
+        }
+        None
+    }
+}
+
+/// Accepts connections on `listener` and serves each on its own task.
+pub(crate) async fn serve(listener: TcpListener, context: Rc<ServerContext>) -> anyhow::Result<()> {
+    tracing::info!("Listening for connections on {}", listener.local_addr()?);
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::error!(?e, "Failed to accept connection");
+                continue;
+            }
+        };
+        tracing::info!(%peer, "Received connection");
+
+        let context = Rc::clone(&context);
+        tokio::task::spawn_local(async move {
+            if let Err(e) = context.run_session(stream, peer).await {
+                tracing::error!(?e, %peer, "Connection error");
+            }
+            tracing::info!(%peer, "Connection closed");
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_rejected_credentials_are_auth_failures() {
+        let mismatch = anyhow::Error::new(ConnectorError::new(
+            "invalid credentials",
+            ConnectorErrorKind::General,
+        ))
+        .context("failed to accept client during finalize");
+        assert!(is_credential_rejection(&mismatch));
+
+        let ntlm = ConnectorError::new("Credssp server error", ConnectorErrorKind::Custom)
+            .with_source(sspi::Error::new(sspi::ErrorKind::MessageAltered, "MIC"));
+        assert!(is_credential_rejection(&ntlm.into()));
+
+        let dropped = anyhow::Error::new(ConnectorError::new(
+            "read frame by hint",
+            ConnectorErrorKind::General,
+        ));
+        assert!(!is_credential_rejection(&dropped));
+        assert!(!is_credential_rejection(&anyhow::anyhow!(
+            "connection reset"
+        )));
+    }
+
+    const BACKOFF: Backoff = Backoff {
+        initial: Duration::from_millis(1),
+        max: Duration::from_millis(4),
+        attempts: 4,
+    };
+
+    #[test]
+    fn codecs_parse_by_name() {
+        assert_eq!("bitmap".parse(), Ok(Codec::Bitmap));
+        assert_eq!("RemoteFX".parse(), Ok(Codec::RemoteFx));
+        assert_eq!("rfx".parse(), Ok(Codec::RemoteFx));
+    }
+
+    #[test]
+    fn dual_stack_pairs_the_wildcards() {
+        let any = IpAddr::from(Ipv4Addr::UNSPECIFIED);
+        let lan = IpAddr::from([192, 168, 0, 2]);
+        assert_eq!(
+            listen_addrs(&[lan, any], 3389, true),
+            [
+                SocketAddr::new(lan, 3389),
+                SocketAddr::new(any, 3389),
+                SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 3389),
+            ]
+        );
+        assert_eq!(listen_addrs(&[lan], 3389, true).len(), 1);
+        assert_eq!(
+            listen_addrs(&["::".parse().unwrap()], 3389, true),
+            [
+                SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 3389),
+                SocketAddr::new(any, 3389),
+            ]
+        );
+        assert_eq!(listen_addrs(&["::".parse().unwrap()], 3389, false).len(), 1);
+        assert_eq!(listen_addrs(&[lan, lan], 3389, false).len(), 1);
+    }
+
+    #[test]
+    fn delay_doubles_up_to_max() {
+        let delays = (0..5).map(|attempt| BACKOFF.delay(attempt));
+        assert!(delays.eq([1, 2, 4, 4, 4].map(Duration::from_millis)));
+        assert_eq!(BACKOFF.delay(u32::MAX), BACKOFF.max);
+    }
+
+    #[tokio::test]
+    async fn retry_returns_first_success() {
+        let mut calls = 0;
+        let bound = BACKOFF
+            .retry(|| {
+                calls += 1;
+                if calls < 3 {
+                    Err("address in use")
+                } else {
+                    Ok(calls)
+                }
+            })
+            .await;
+
+        assert_eq!(bound, Some(3));
+        assert_eq!(calls, 3);
+    }
+
+    #[tokio::test]
+    async fn retry_gives_up_after_attempts() {
+        let mut calls = 0;
+        let bound = BACKOFF
+            .retry(|| {
+                calls += 1;
+                Err::<(), _>("address in use")
+            })
+            .await;
+
+        assert_eq!(bound, None);
+        assert_eq!(calls, BACKOFF.attempts);
+    }
+}
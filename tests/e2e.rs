@@ -0,0 +1,374 @@
+//! Starts the server with the mock capture backend and connects to it.
+//!
+//! Run with `cargo test --features mock-backend`.
+#![cfg(feature = "mock-backend")]
+
+use std::{
+    io::{Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpListener, TcpStream},
+    path::PathBuf,
+    process::{Child, Command},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use ironrdp_connector::{ClientConnector, Credentials, DesktopSize};
+use ironrdp_core::{decode_cursor, ReadCursor};
+use ironrdp_pdu::{
+    fast_path::{FastPathHeader, FastPathUpdate, FastPathUpdatePdu, Fragmentation, UpdateCode},
+    gcc::KeyboardType,
+    geometry::InclusiveRectangle,
+    rdp::{
+        capability_sets::MajorPlatformType,
+        client_info::{PerformanceFlags, TimezoneInfo},
+    },
+    Action,
+};
+use ironrdp_tokio::TokioFramed;
+use tokio_rustls::rustls::{
+    self,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    DigitallySignedStruct, SignatureScheme,
+};
+
+/// Size of the test pattern the server is started with.
+const MOCK_SIZE: (u16, u16) = (64, 48);
+const USERNAME: &str = "user";
+const PASSWORD: &str = "secret";
+
+/// Kills the server when the test ends, whether it passed or not.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn free_port(host: IpAddr) -> u16 {
+    TcpListener::bind((host, 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn start_server(host: IpAddr, port: u16, args: &[&str]) -> Server {
+    let mock_size = format!("{}x{}", MOCK_SIZE.0, MOCK_SIZE.1);
+    Server(
+        Command::new(env!("CARGO_BIN_EXE_arisu"))
+            .args(["--host", &host.to_string(), "--dual-stack", "false"])
+            .args(["--port", &port.to_string()])
+            .args(["--mock-capture", &mock_size])
+            .args(args)
+            .spawn()
+            .expect("failed to start the server"),
+    )
+}
+
+/// Self-signed certificate for `localhost` and its key, as PEM files which
+/// are removed when the test ends.
+struct Identity {
+    certificate: PathBuf,
+    key: PathBuf,
+}
+
+impl Identity {
+    fn generate(name: &str) -> Self {
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let directory = std::env::temp_dir();
+        let id = std::process::id();
+        let identity = Self {
+            certificate: directory.join(format!("arisu-{name}-{id}.crt")),
+            key: directory.join(format!("arisu-{name}-{id}.key")),
+        };
+        std::fs::write(&identity.certificate, cert.pem()).unwrap();
+        std::fs::write(&identity.key, key_pair.serialize_pem()).unwrap();
+        identity
+    }
+}
+
+impl Drop for Identity {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.certificate);
+        let _ = std::fs::remove_file(&self.key);
+    }
+}
+
+/// The test server's certificate is self-signed.
+#[derive(Debug)]
+struct AcceptAnyCertificate;
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        rustls::crypto::ring::default_provider()
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+fn tls_connector() -> tokio_rustls::TlsConnector {
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .dangerous()
+    .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate))
+    .with_no_client_auth();
+    tokio_rustls::TlsConnector::from(Arc::new(config))
+}
+
+fn client_config() -> ironrdp_connector::Config {
+    ironrdp_connector::Config {
+        credentials: Credentials::UsernamePassword {
+            username: USERNAME.to_owned(),
+            password: PASSWORD.to_owned(),
+        },
+        domain: None,
+        enable_tls: true,
+        enable_credssp: false,
+        keyboard_type: KeyboardType::IbmEnhanced,
+        keyboard_subtype: 0,
+        keyboard_layout: 0,
+        keyboard_functional_keys_count: 12,
+        ime_file_name: String::new(),
+        dig_product_id: String::new(),
+        desktop_size: DesktopSize {
+            width: MOCK_SIZE.0,
+            height: MOCK_SIZE.1,
+        },
+        desktop_scale_factor: 0,
+        bitmap: None,
+        client_build: 0,
+        client_name: "arisu-e2e".to_owned(),
+        client_dir: "C:\\Windows\\System32\\mstscax.dll".to_owned(),
+        platform: MajorPlatformType::UNIX,
+        hardware_id: None,
+        request_data: None,
+        autologon: false,
+        enable_audio_playback: false,
+        performance_flags: PerformanceFlags::default(),
+        license_cache: None,
+        timezone_info: TimezoneInfo::default(),
+        enable_server_pointer: false,
+        pointer_software_rendering: true,
+    }
+}
+
+/// Rects of the bitmap updates in a fast-path output frame. Updates split
+/// over several frames are collected in `fragments`.
+fn bitmap_rects(frame: &[u8], fragments: &mut Vec<u8>) -> Vec<InclusiveRectangle> {
+    let mut cursor = ReadCursor::new(frame);
+    decode_cursor::<FastPathHeader>(&mut cursor).expect("invalid fast-path header");
+    let mut rects = Vec::new();
+    while !cursor.is_empty() {
+        let update =
+            decode_cursor::<FastPathUpdatePdu<'_>>(&mut cursor).expect("invalid fast-path update");
+        let data = match update.fragmentation {
+            Fragmentation::Single => update.data.to_vec(),
+            Fragmentation::First => {
+                *fragments = update.data.to_vec();
+                continue;
+            }
+            Fragmentation::Next => {
+                fragments.extend_from_slice(update.data);
+                continue;
+            }
+            Fragmentation::Last => {
+                fragments.extend_from_slice(update.data);
+                std::mem::take(fragments)
+            }
+        };
+        if update.update_code != UpdateCode::Bitmap {
+            continue;
+        }
+        match FastPathUpdate::decode_with_code(&data, update.update_code) {
+            Ok(FastPathUpdate::Bitmap(bitmap)) => {
+                rects.extend(bitmap.rectangles.into_iter().map(|data| data.rectangle))
+            }
+            other => panic!("invalid bitmap update: {other:?}"),
+        }
+    }
+    rects
+}
+
+/// Logs in over TLS and returns the area covered by the bitmap updates once
+/// they add up to a whole frame.
+async fn receive_frame(host: IpAddr, port: u16) -> (u16, u16) {
+    let stream = connect(host, port);
+    stream.set_nonblocking(true).unwrap();
+    let client_addr = stream.local_addr().unwrap();
+    let stream = tokio::net::TcpStream::from_std(stream).unwrap();
+
+    let mut framed = TokioFramed::new(stream);
+    let mut connector = ClientConnector::new(client_config(), client_addr);
+    let should_upgrade = ironrdp_async::connect_begin(&mut framed, &mut connector)
+        .await
+        .expect("connection initiation failed");
+    let stream = tls_connector()
+        .connect(
+            ServerName::try_from("localhost").unwrap(),
+            framed.into_inner_no_leftover(),
+        )
+        .await
+        .expect("TLS upgrade failed");
+    let upgraded = ironrdp_async::mark_as_upgraded(should_upgrade, &mut connector);
+    let mut framed = TokioFramed::new(stream);
+    ironrdp_async::connect_finalize(
+        upgraded,
+        &mut framed,
+        connector,
+        "localhost".into(),
+        Vec::new(),
+        None,
+        None,
+    )
+    .await
+    .expect("login failed");
+
+    let (mut right, mut bottom) = (0, 0);
+    let mut fragments = Vec::new();
+    while (right, bottom) != (MOCK_SIZE.0 - 1, MOCK_SIZE.1 - 1) {
+        let (action, frame) = framed.read_pdu().await.expect("connection lost");
+        if action != Action::FastPath {
+            continue;
+        }
+        for rect in bitmap_rects(&frame, &mut fragments) {
+            assert!(
+                rect.right < MOCK_SIZE.0 && rect.bottom < MOCK_SIZE.1,
+                "bitmap update {rect:?} outside of the desktop"
+            );
+            right = right.max(rect.right);
+            bottom = bottom.max(rect.bottom);
+        }
+    }
+    (right + 1, bottom + 1)
+}
+
+fn connect(host: IpAddr, port: u16) -> TcpStream {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        match TcpStream::connect((host, port)) {
+            Ok(stream) => return stream,
+            Err(_) if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(100)),
+            Err(e) => panic!("server is not listening: {e}"),
+        }
+    }
+}
+
+/// Reads one TPKT packet, header included.
+fn read_tpkt(stream: &mut TcpStream) -> Vec<u8> {
+    let mut packet = vec![0; 4];
+    stream.read_exact(&mut packet).unwrap();
+    assert_eq!(packet[0], 0x03, "not a TPKT packet");
+    let length = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+    packet.resize(length, 0);
+    stream.read_exact(&mut packet[4..]).unwrap();
+    packet
+}
+
+/// Connects to a server listening on `host` and checks it negotiates
+/// standard RDP security.
+fn negotiate(host: IpAddr) {
+    let port = free_port(host);
+    let _server = start_server(host, port, &["--security", "none"]);
+    let mut stream = connect(host, port);
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+
+    // X.224 Connection Request carrying an RDP Negotiation Request for
+    // standard RDP security, which `--security none` accepts.
+    #[rustfmt::skip]
+    let request = [
+        0x03, 0x00, 0x00, 0x13, // TPKT
+        0x0E, 0xE0, 0x00, 0x00, 0x00, 0x00, 0x00, // X.224 CR
+        0x01, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, // RDP_NEG_REQ
+    ];
+    stream.write_all(&request).unwrap();
+
+    let response = read_tpkt(&mut stream);
+    assert_eq!(response[5] & 0xF0, 0xD0, "not an X.224 Connection Confirm");
+    assert_eq!(response[11], 0x02, "not an RDP Negotiation Response");
+    assert_eq!(
+        u32::from_le_bytes(response[15..19].try_into().unwrap()),
+        0,
+        "standard RDP security not selected"
+    );
+}
+
+#[test]
+fn negotiates_connection_with_mock_capture() {
+    negotiate(Ipv4Addr::LOCALHOST.into());
+}
+
+#[test]
+fn negotiates_connection_over_ipv6() {
+    if TcpListener::bind((Ipv6Addr::LOCALHOST, 0)).is_err() {
+        eprintln!("IPv6 loopback is unavailable, skipping");
+        return;
+    }
+    negotiate(Ipv6Addr::LOCALHOST.into());
+}
+
+/// Logs in with the default user name and receives the test pattern. TLS
+/// security is used as the IronRDP client doesn't support standard RDP
+/// security, which `--security none` negotiates.
+#[tokio::test]
+async fn receives_bitmap_updates_of_the_mock_size() {
+    let host = Ipv4Addr::LOCALHOST.into();
+    let port = free_port(host);
+    let identity = Identity::generate("e2e");
+    let _server = start_server(
+        host,
+        port,
+        &[
+            "--security",
+            "tls",
+            "--certificate",
+            identity.certificate.to_str().unwrap(),
+            "--key",
+            identity.key.to_str().unwrap(),
+            "--password",
+            PASSWORD,
+        ],
+    );
+
+    let size = tokio::time::timeout(Duration::from_secs(30), receive_frame(host, port))
+        .await
+        .expect("no whole frame received");
+    assert_eq!(size, MOCK_SIZE);
+}